@@ -1,53 +1,862 @@
+use lettre::Address;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor,
+};
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    marker::PhantomData,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigContainer {
     pub destinations: HashMap<String, DestinationConfig>,
     pub sources: HashMap<String, SourceConfig>,
     pub retryagent: Option<RetryAgentConfig>,
     pub mappings: HashMap<String, Vec<String>>,
+    pub journal: Option<JournalConfig>,
+    pub dedup: Option<DedupConfig>,
+    pub spill: Option<SpillConfig>,
+    pub supervision: Option<SupervisionConfig>,
 }
 impl ConfigContainer {
+    /// Load the configuration file, in JSON, TOML or YAML format depending on its extension,
+    /// together with the files it includes. Loading stops at the first file that can not be
+    /// read or parsed, otherwise all errors are reported at once, one per line.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ConfigContainer, String> {
-        let config_file =
-            File::open(path).map_err(|e| format!("Failed to open config file: {}", e))?;
-        let reader = BufReader::new(config_file);
-        let config: ConfigContainer = serde_json::from_reader(reader)
-            .map_err(|e| format!("Failed to parse config file: {}", e))?;
-        config.validate()?;
-        Ok(config)
-    }
-    fn validate(&self) -> Result<(), String> {
+        let mut loader = ConfigLoader::default();
+        loader.load(path.as_ref())?;
+        loader.resolve()?;
+        let errors = loader.config.errors();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(loader.config)
+    }
+
+    /// Problems that prevent idlemail from running
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
         for (srcname, dsts) in &self.mappings {
             if !self.sources.contains_key(srcname) {
-                return Err(format!("Unknown source: {} specified in mappings", srcname));
+                errors.push(format!("Unknown source: {} specified in mappings", srcname));
             }
             for dstname in dsts {
                 if !self.destinations.contains_key(dstname) {
-                    return Err(format!(
+                    errors.push(format!(
                         "Unknown destination: {} specified in mappings",
                         dstname
                     ));
                 }
             }
         }
-        for srcname in self.sources.keys() {
+        for (srcname, srccfg) in &self.sources {
             if !self.mappings.contains_key(srcname) {
-                return Err(format!("Source: {} has no mapping", srcname));
+                errors.push(format!("Source: {} has no mapping", srcname));
+            }
+            let (port, interval) = match srccfg {
+                SourceConfig::Test(config) => (None, config.interval),
+                SourceConfig::ImapPoll(config) => (Some(config.port), config.interval),
+                SourceConfig::ImapIdle(config) => (Some(config.port), config.renewinterval),
+            };
+            if port == Some(0) {
+                errors.push(format!("Source: {} has a port of 0", srcname));
+            }
+            if interval == 0 {
+                errors.push(format!("Source: {} has an interval of 0", srcname));
             }
         }
         if let Some(RetryAgentConfig::Filesystem(config)) = &self.retryagent {
             if !Path::new(&config.path).exists() {
-                return Err("FilesystemRetryAgent: Path does not exist".to_string());
+                errors.push("FilesystemRetryAgent: Path does not exist".to_string());
+            }
+        }
+        for (dstname, dstcfg) in &self.destinations {
+            if let Some(retry) = dstcfg.retry() {
+                if retry
+                    .backoff
+                    .is_some_and(|backoff| !backoff.is_finite() || backoff < 1.0)
+                {
+                    errors.push(format!(
+                        "Destination: {} has a retry backoff that is not a number of at least 1",
+                        dstname
+                    ));
+                }
+                if retry.max_attempts == Some(0) {
+                    errors.push(format!(
+                        "Destination: {} has a retry max_attempts of 0",
+                        dstname
+                    ));
+                }
+            }
+            if dstcfg.queue_depth() == Some(0) {
+                errors.push(format!("Destination: {} has a queue_depth of 0", dstname));
+            }
+            if let Some(PermanentFailureConfig::Bounce {
+                postmaster, from, ..
+            }) = dstcfg.on_permanent_failure()
+            {
+                for address in std::iter::once(postmaster).chain(from) {
+                    if let Err(e) = address.parse::<Address>() {
+                        errors.push(format!(
+                            "Destination: {} has an invalid bounce address: {}: {}",
+                            dstname, address, e
+                        ));
+                    }
+                }
+            }
+            match dstcfg {
+                DestinationConfig::Smtp(config) => {
+                    if config.port == 0 {
+                        errors.push(format!("Destination: {} has a port of 0", dstname));
+                    }
+                    if let Err(e) = config.recipient.parse::<Address>() {
+                        errors.push(format!(
+                            "Destination: {} has an invalid recipient: {}: {}",
+                            dstname, config.recipient, e
+                        ));
+                    }
+                }
+                DestinationConfig::Exec(config) => {
+                    // relative paths are resolved in the child's workdir
+                    let executable = Path::new(&config.executable);
+                    let in_workdir = config.workdir.is_some()
+                        && executable.is_relative()
+                        && config.executable.contains('/');
+                    if !in_workdir {
+                        if let Err(e) = check_executable(&config.executable) {
+                            errors.push(format!("Destination: {}: {}", dstname, e));
+                        }
+                    }
+                    if let Some(workdir) = &config.workdir {
+                        if !Path::new(workdir).is_dir() {
+                            errors.push(format!(
+                                "Destination: {} has a workdir that does not exist: {}",
+                                dstname, workdir
+                            ));
+                        }
+                    }
+                    if config.workers == Some(0) {
+                        errors.push(format!("Destination: {} has 0 workers", dstname));
+                    }
+                }
+                DestinationConfig::Webhook(WebhookDestinationConfig { url, .. })
+                | DestinationConfig::Notify(NotifyDestinationConfig {
+                    target: NotifyTarget::Http { url, .. },
+                    ..
+                }) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                    errors.push(format!(
+                        "Destination: {} has an url that is not http(s): {}",
+                        dstname, url
+                    ));
+                }
+                _ => {}
+            }
+        }
+        for (dstname, dstcfg) in &self.destinations {
+            for (setting, next) in dstcfg.handoffs() {
+                if !self.destinations.contains_key(next) {
+                    errors.push(format!(
+                        "Unknown destination: {} specified in {} of {}",
+                        next, setting, dstname
+                    ));
+                }
+            }
+            if self.handoff_loop(dstname) {
+                errors.push(format!(
+                    "Destination: {} has a loop in its on_permanent_failure and dead_letter chain",
+                    dstname
+                ));
+            }
+        }
+        if let Some(config) = &self.journal {
+            if !Path::new(&config.path).exists() {
+                errors.push("Journal: Path does not exist".to_string());
+            }
+        }
+        if let Some(RetryAgentConfig::Sqlite(config)) = &self.retryagent {
+            if !parent_exists(&config.path) {
+                errors.push("SqliteRetryAgent: Folder of the database does not exist".to_string());
+            }
+        }
+        if let Some(path) = self.dedup.as_ref().and_then(|c| c.path.as_ref()) {
+            if !parent_exists(path) {
+                errors.push("Dedup: Folder of the database does not exist".to_string());
+            }
+        }
+        if let Some(config) = &self.spill {
+            if !Path::new(&config.path).exists() {
+                errors.push("Spill: Path does not exist".to_string());
             }
         }
+        if let Some(config) = &self.supervision {
+            if let (Some(min_delay), Some(max_delay)) = (config.min_delay, config.max_delay) {
+                if min_delay > max_delay {
+                    errors.push("Supervision: min_delay is larger than max_delay".to_string());
+                }
+            }
+            if let Some(path) = &config.status_file {
+                if !parent_exists(path) {
+                    errors
+                        .push("Supervision: Folder of the status_file does not exist".to_string());
+                }
+            }
+        }
+        errors
+    }
+
+    /// Whether mails the destination hands off (as fallback or dead letter) can get back to it,
+    /// where they would loop forever
+    fn handoff_loop(&self, dstname: &str) -> bool {
+        let mut pending = vec![dstname];
+        let mut visited = HashSet::new();
+        while let Some(current) = pending.pop() {
+            let Some(dstcfg) = self.destinations.get(current) else {
+                continue;
+            };
+            for (_, next) in dstcfg.handoffs() {
+                if next == dstname {
+                    return true;
+                }
+                if visited.insert(next) {
+                    pending.push(next);
+                }
+            }
+        }
+        false
+    }
+
+    /// Settings that are valid, but most likely not intended
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (srcname, srccfg) in &self.sources {
+            if self
+                .mappings
+                .get(srcname)
+                .is_some_and(|dsts| dsts.is_empty())
+            {
+                warnings.push(format!(
+                    "Source: {} is not mapped to any destination",
+                    srcname
+                ));
+            }
+            if let SourceConfig::ImapIdle(config) = srccfg {
+                if config.renewinterval > MAX_IDLE_RENEWINTERVAL {
+                    warnings.push(format!(
+                        "Source: {} has a renewinterval above 29 minutes, servers may drop the connection before it is renewed",
+                        srcname
+                    ));
+                }
+            }
+        }
+        for (dstname, dstcfg) in &self.destinations {
+            let used = self.mappings.values().flatten().any(|d| d == dstname)
+                || self
+                    .destinations
+                    .values()
+                    .any(|other| other.handoffs().any(|(_, d)| d == dstname));
+            if !used {
+                warnings.push(format!(
+                    "Destination: {} is not used by any mapping",
+                    dstname
+                ));
+            }
+            if dstcfg.retry().is_some() && self.retryagent.is_none() {
+                warnings.push(format!(
+                    "Destination: {} has a retry policy, but no retryagent is configured",
+                    dstname
+                ));
+            }
+            if let DestinationConfig::Smtp(SmtpDestinationConfig {
+                encryption: Encryption::None,
+                auth: Some(AuthMethod::Plain { .. } | AuthMethod::Login { .. }),
+                ..
+            }) = dstcfg
+            {
+                warnings.push(format!(
+                    "Destination: {} sends its credentials without encryption",
+                    dstname
+                ));
+            }
+        }
+        if self.retryagent.as_ref().is_some_and(|c| c.delay() == 0) {
+            warnings.push("RetryAgent: A delay of 0 retries failed mails immediately".to_string());
+        }
+        if self.dedup.as_ref().is_some_and(|c| c.window == 0) {
+            warnings.push("Dedup: A window of 0 does not detect any duplicates".to_string());
+        }
+        warnings
+    }
+}
+
+/// Longest renewinterval in seconds, as servers may drop IDLE connections after 30 minutes (RFC 2177)
+const MAX_IDLE_RENEWINTERVAL: u64 = 29 * 60;
+/// Delivery attempts of notify destinations without `max_attempts`, late notifications are
+/// pointless
+const NOTIFY_MAX_ATTEMPTS: u32 = 5;
+
+/// Contents of a single configuration file, before it is merged with the files it includes
+#[derive(Deserialize, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(
+    title = "Idlemail configuration",
+    description = "Configuration file of idlemail, or a file included by it"
+)]
+struct ConfigFile {
+    /// Schema of the file for editors, ignored by idlemail
+    #[serde(default, rename = "$schema")]
+    _schema: Option<String>,
+    /// Paths or glob patterns of further configuration files, relative to this file
+    #[serde(default)]
+    include: Vec<String>,
+    /// Fields that sources and destinations can inherit
+    #[serde(default)]
+    templates: HashMap<String, Map<String, Value>>,
+    // sources and destinations are only deserialized, once their templates are applied
+    /// Destinations by name
+    #[serde(default)]
+    #[schemars(schema_with = "entries_schema::<DestinationConfig>")]
+    destinations: HashMap<String, Value>,
+    /// Sources by name
+    #[serde(default)]
+    #[schemars(schema_with = "entries_schema::<SourceConfig>")]
+    sources: HashMap<String, Value>,
+    /// Names of the destinations, that the mails of each source are forwarded to
+    #[serde(default)]
+    mappings: HashMap<String, Vec<String>>,
+    retryagent: Option<RetryAgentConfig>,
+    journal: Option<JournalConfig>,
+    dedup: Option<DedupConfig>,
+    spill: Option<SpillConfig>,
+    supervision: Option<SupervisionConfig>,
+}
+impl ConfigFile {
+    fn parse(path: &Path, text: &str) -> Result<Self, String> {
+        deserialize_file(path, text, PhantomData).map_err(|(message, location)| match location {
+            Some((line, column)) => format!("{}:{}:{}: {}", path.display(), line, column, message),
+            None => format!("{}: {}", path.display(), message),
+        })
+    }
+}
+
+/// Message of an error in a file, with its one-based line and column if known
+type FileError = (String, Option<(usize, usize)>);
+
+/// Deserialize the file in JSON, TOML or YAML format depending on its extension
+fn deserialize_file<'de, S: DeserializeSeed<'de>>(
+    path: &Path,
+    text: &'de str,
+    seed: S,
+) -> Result<S::Value, FileError> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension {
+        "toml" => seed
+            .deserialize(toml::Deserializer::new(text))
+            .map_err(|e| {
+                (
+                    e.message().to_owned(),
+                    e.span().map(|span| line_and_column(text, span.start)),
+                )
+            }),
+        "yaml" | "yml" => seed
+            .deserialize(serde_yaml::Deserializer::from_str(text))
+            .map_err(|e| {
+                (
+                    strip_location(&e.to_string()),
+                    e.location().map(|l| (l.line(), l.column())),
+                )
+            }),
+        _ => {
+            let mut deserializer = serde_json::Deserializer::from_str(text);
+            seed.deserialize(&mut deserializer)
+                .and_then(|value| deserializer.end().map(|_| value))
+                .map_err(|e| (strip_location(&e.to_string()), Some((e.line(), e.column()))))
+        }
+    }
+}
+
+/// Message of the error, that stops `Locate` at the searched value
+const LOCATED: &str = "located";
+
+/// Walks the keys of a configuration file, and fails at the value of the given key path.
+/// The error carries the location, that the format's deserializer assigns to the value.
+struct Locate<'a> {
+    keys: &'a [String],
+}
+impl Locate<'_> {
+    fn located<E: de::Error>(&self) -> Result<(), E> {
+        match self.keys.is_empty() {
+            true => Err(E::custom(LOCATED)),
+            false => Ok(()),
+        }
+    }
+}
+impl<'de> DeserializeSeed<'de> for Locate<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+impl<'de> Visitor<'de> for Locate<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a configuration file")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        self.located()
+    }
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        self.located()
+    }
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        self.located()
+    }
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        self.located()
+    }
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        self.located()
+    }
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.located()
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        self.located()?;
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        self.located()?;
+        let (key, keys) = self.keys.split_first().expect("checked by located");
+        while let Some(candidate) = map.next_key::<String>()? {
+            if &candidate == key {
+                return map.next_value_seed(Locate { keys });
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// One-based line and column of the key path in the file, e.g. `["sources", "src", "port"]`.
+/// Keys that do not exist are skipped, the location of the last existing one is returned.
+fn locate(path: &Path, text: &str, keys: &[String]) -> Option<(usize, usize)> {
+    (1..=keys.len()).rev().find_map(|depth| {
+        match deserialize_file(
+            path,
+            text,
+            Locate {
+                keys: &keys[..depth],
+            },
+        ) {
+            // serde_yaml prefixes the message with the path
+            Err((message, location)) if message.ends_with(LOCATED) => location,
+            _ => None,
+        }
+    })
+}
+
+/// JSON schema of the configuration files
+pub fn schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(ConfigFile))
+        .expect("Failed to serialize the schema")
+}
+
+/// Sources or destinations by name, which are either complete, or inherit from a template
+fn entries_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let schema = serde_json::json!({
+        "type": "object",
+        "additionalProperties": {
+            "anyOf": [
+                gen.subschema_for::<T>(),
+                {
+                    "type": "object",
+                    "required": ["template"],
+                    "properties": { "template": { "type": "string" } }
+                }
+            ]
+        }
+    });
+    serde_json::from_value(schema).expect("Invalid schema")
+}
+
+/// One-based line and column of the byte offset in the text
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Remove the location serde_json and serde_yaml append to their messages
+fn strip_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_owned(),
+        None => message.to_owned(),
+    }
+}
+
+/// Merges a configuration file with the files it includes, rejecting everything that is
+/// defined more than once.
+#[derive(Default)]
+struct ConfigLoader {
+    config: ConfigContainer,
+    /// File each template, source, destination, mapping and section was defined in
+    origins: HashMap<String, PathBuf>,
+    /// Content of each loaded file, to locate errors found after parsing it
+    texts: HashMap<PathBuf, String>,
+    loaded: HashSet<PathBuf>,
+    templates: HashMap<String, Map<String, Value>>,
+    sources: HashMap<String, Value>,
+    destinations: HashMap<String, Value>,
+}
+impl ConfigLoader {
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| format!("{}: Failed to open config file: {}", path.display(), e))?;
+        if !self.loaded.insert(canonical) {
+            return Err(format!("{}: Included more than once", path.display()));
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("{}: Failed to read config file: {}", path.display(), e))?;
+        let file = ConfigFile::parse(path, &text)?;
+        self.texts.insert(path.to_path_buf(), text);
+
+        let origins = &mut self.origins;
+        merge_map(
+            origins,
+            path,
+            "Template",
+            &mut self.templates,
+            file.templates,
+        )?;
+        merge_map(
+            origins,
+            path,
+            "Destination",
+            &mut self.destinations,
+            file.destinations,
+        )?;
+        merge_map(origins, path, "Source", &mut self.sources, file.sources)?;
+        merge_map(
+            origins,
+            path,
+            "Mapping of source",
+            &mut self.config.mappings,
+            file.mappings,
+        )?;
+        merge_option(
+            origins,
+            path,
+            "retryagent",
+            &mut self.config.retryagent,
+            file.retryagent,
+        )?;
+        merge_option(
+            origins,
+            path,
+            "journal",
+            &mut self.config.journal,
+            file.journal,
+        )?;
+        merge_option(origins, path, "dedup", &mut self.config.dedup, file.dedup)?;
+        merge_option(origins, path, "spill", &mut self.config.spill, file.spill)?;
+        merge_option(
+            origins,
+            path,
+            "supervision",
+            &mut self.config.supervision,
+            file.supervision,
+        )?;
+
+        let folder = path.parent().unwrap_or_else(|| Path::new(""));
+        for pattern in &file.include {
+            for included in expand_include(path, &folder.join(pattern))? {
+                self.load(&included)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the templates to the sources and destinations of all files, and deserialize them
+    fn resolve(&mut self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (name, entry) in std::mem::take(&mut self.sources) {
+            match self.resolve_entry("Source", "sources", &name, entry) {
+                Ok(source) => {
+                    self.config.sources.insert(name, source);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        for (name, entry) in std::mem::take(&mut self.destinations) {
+            match self.resolve_entry("Destination", "destinations", &name, entry) {
+                Ok(destination) => {
+                    self.config.destinations.insert(name, destination);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort();
+        Err(errors.join("\n"))
+    }
+
+    fn resolve_entry<T: DeserializeOwned>(
+        &self,
+        kind: &str,
+        section: &str,
+        name: &str,
+        entry: Value,
+    ) -> Result<T, String> {
+        let key = format!("{} {}", kind, name);
+        let origin = &self.origins[&key];
+        // located at the given field of the entry, or the entry itself if the field is not
+        // in the file (e.g. it was inherited from a template)
+        let error_at = |fields: &[String], message: String| {
+            let mut keys = vec![section.to_owned(), name.to_owned()];
+            keys.extend_from_slice(fields);
+            let text = self.texts.get(origin).map_or("", String::as_str);
+            match locate(origin, text, &keys) {
+                Some((line, column)) => {
+                    format!(
+                        "{}:{}:{}: {}: {}",
+                        origin.display(),
+                        line,
+                        column,
+                        key,
+                        message
+                    )
+                }
+                None => format!("{}: {}: {}", origin.display(), key, message),
+            }
+        };
+        let error = |message: String| error_at(&[], message);
+        let mut fields = match entry {
+            Value::Object(fields) => fields,
+            _ => return Err(error("Expected a map".to_owned())),
+        };
+        if let Some(template) = fields.remove("template") {
+            let template_error = |message| error_at(&["template".to_owned()], message);
+            let template = template
+                .as_str()
+                .ok_or_else(|| template_error("template has to be a string".to_owned()))?;
+            let defaults = self
+                .templates
+                .get(template)
+                .ok_or_else(|| template_error(format!("Unknown template: {}", template)))?;
+            fields = merge_fields(defaults.clone(), fields);
+        }
+        let mut entry = Value::Object(fields);
+        interpolate(&mut entry, name).map_err(error)?;
+        serde_path_to_error::deserialize(entry).map_err(|e| {
+            let message = e.inner().to_string();
+            let mut fields: Vec<_> = e
+                .path()
+                .iter()
+                .map_while(|segment| match segment {
+                    serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+                    _ => None,
+                })
+                .collect();
+            // the unknown field is not part of the path
+            if let Some(field) = message
+                .strip_prefix("unknown field `")
+                .and_then(|rest| rest.split('`').next())
+            {
+                fields.push(field.to_owned());
+            }
+            match e.path().to_string().as_str() {
+                "." => error_at(&fields, message),
+                path => error_at(&fields, format!("{}: {}", path, message)),
+            }
+        })
+    }
+}
+
+/// Merge the fields into the defaults. Maps are merged recursively, other values of the
+/// fields replace the defaults.
+fn merge_fields(
+    mut defaults: Map<String, Value>,
+    fields: Map<String, Value>,
+) -> Map<String, Value> {
+    for (key, value) in fields {
+        let merged = match (defaults.remove(&key), value) {
+            (Some(Value::Object(default)), Value::Object(value)) => {
+                Value::Object(merge_fields(default, value))
+            }
+            (_, value) => value,
+        };
+        defaults.insert(key, merged);
+    }
+    defaults
+}
+
+/// Replace `${name}` with the name of the source or destination, and `${VAR}` with the
+/// environment variable `VAR` in all strings of the value. `$${` is kept as `${`.
+fn interpolate(value: &mut Value, name: &str) -> Result<(), String> {
+    match value {
+        Value::String(text) => *text = interpolate_str(text, name)?,
+        Value::Array(values) => {
+            for value in values {
+                interpolate(value, name)?;
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values_mut() {
+                interpolate(value, name)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(text: &str, name: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated variable in: {}", text))?;
+        match &rest[start + 2..start + end] {
+            "name" => result.push_str(name),
+            variable => result.push_str(
+                &env::var(variable)
+                    .map_err(|_| format!("Environment variable {} is not set", variable))?,
+            ),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn merge_map<T>(
+    origins: &mut HashMap<String, PathBuf>,
+    path: &Path,
+    kind: &str,
+    target: &mut HashMap<String, T>,
+    entries: HashMap<String, T>,
+) -> Result<(), String> {
+    for (name, entry) in entries {
+        let key = format!("{} {}", kind, name);
+        if let Some(origin) = origins.get(&key) {
+            return Err(format!(
+                "{}: {} is already defined in {}",
+                path.display(),
+                key,
+                origin.display()
+            ));
+        }
+        origins.insert(key, path.to_owned());
+        target.insert(name, entry);
+    }
+    Ok(())
+}
+
+fn merge_option<T>(
+    origins: &mut HashMap<String, PathBuf>,
+    path: &Path,
+    section: &str,
+    target: &mut Option<T>,
+    value: Option<T>,
+) -> Result<(), String> {
+    if value.is_some() {
+        if let Some(origin) = origins.get(section) {
+            return Err(format!(
+                "{}: Section {} is already defined in {}",
+                path.display(),
+                section,
+                origin.display()
+            ));
+        }
+        origins.insert(section.to_owned(), path.to_owned());
+        *target = value;
+    }
+    Ok(())
+}
+
+/// Files matching the include pattern in alphabetical order. A pattern without wildcards has
+/// to match an existing file.
+fn expand_include(path: &Path, pattern: &Path) -> Result<Vec<PathBuf>, String> {
+    let pattern = pattern.to_string_lossy();
+    let paths = glob::glob(&pattern)
+        .map_err(|e| {
+            format!(
+                "{}: Invalid include pattern {}: {}",
+                path.display(),
+                pattern,
+                e
+            )
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: Failed to include {}", path.display(), e))?;
+    if paths.is_empty() && !pattern.contains(['*', '?', '[']) {
+        return Err(format!(
+            "{}: Included file {} does not exist",
+            path.display(),
+            pattern
+        ));
+    }
+    Ok(paths)
+}
+
+/// Check that the executable exists and may be executed, searching PATH for bare names
+fn check_executable(executable: &str) -> Result<(), String> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+    if executable.contains('/') {
+        if !Path::new(executable).exists() {
+            return Err(format!("Executable {} does not exist", executable));
+        }
+        if !is_executable(Path::new(executable)) {
+            return Err(format!("Executable {} is not executable", executable));
+        }
+        return Ok(());
+    }
+    let found = env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|folder| is_executable(&folder.join(executable)))
+    });
+    match found {
+        true => Ok(()),
+        false => Err(format!("Executable {} was not found in PATH", executable)),
+    }
+}
+
+/// Whether the folder containing the given file exists
+fn parent_exists(path: &str) -> bool {
+    Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .exists()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum AuthMethod {
@@ -59,7 +868,7 @@ pub enum AuthMethod {
     Login { user: String, password: String },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum Encryption {
@@ -75,7 +884,7 @@ pub enum Encryption {
 // # Sources
 // #############
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ImapPollSourceConfig {
     pub server: String,
@@ -85,7 +894,7 @@ pub struct ImapPollSourceConfig {
     pub auth: AuthMethod,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ImapIdleSourceConfig {
     pub server: String,
@@ -96,14 +905,14 @@ pub struct ImapIdleSourceConfig {
     pub auth: AuthMethod,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct TestSourceConfig {
     pub delay: u64,
     pub interval: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum SourceConfig {
@@ -119,7 +928,56 @@ pub enum SourceConfig {
 // # Destinations
 // #############
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Retry behaviour of a single destination, overriding the RetryAgent's settings
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicyConfig {
+    /// Seconds before the first retry, defaults to the RetryAgent's delay
+    pub delay: Option<u64>,
+    /// Factor the delay is multiplied with after every further failed attempt
+    pub backoff: Option<f64>,
+    /// Upper bound of the delay in seconds
+    pub max_delay: Option<u64>,
+    /// Maximum amount of delivery attempts, including the first one
+    pub max_attempts: Option<u32>,
+    /// Destination that mails are handed to, after the last attempt failed
+    pub dead_letter: Option<String>,
+}
+
+/// What happens to mails that a destination permanently rejected
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum PermanentFailureConfig {
+    /// Log the failure and drop the mail
+    #[serde(rename = "drop")]
+    Drop,
+    /// Store the mail into a Maildir
+    #[serde(rename = "quarantine")]
+    Quarantine { path: String },
+    /// Hand the mail to another destination
+    #[serde(rename = "fallback")]
+    Fallback { destination: String },
+    /// Send a delivery status notification with the original mail through another destination
+    #[serde(rename = "bounce")]
+    Bounce {
+        destination: String,
+        postmaster: String,
+        from: Option<String>,
+    },
+}
+impl PermanentFailureConfig {
+    /// Destination that mails are handed to by this policy
+    pub fn destination(&self) -> Option<&str> {
+        match self {
+            PermanentFailureConfig::Fallback { destination }
+            | PermanentFailureConfig::Bounce { destination, .. } => Some(destination),
+            PermanentFailureConfig::Drop | PermanentFailureConfig::Quarantine { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct SmtpDestinationConfig {
     pub server: String,
@@ -127,23 +985,148 @@ pub struct SmtpDestinationConfig {
     pub encryption: Encryption,
     pub auth: Option<AuthMethod>,
     pub recipient: String,
+    pub retry: Option<RetryPolicyConfig>,
+    pub on_permanent_failure: Option<PermanentFailureConfig>,
+    pub queue_depth: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct TestDestinationConfig {
     pub fail_n_first: u16,
+    /// Amount of mails the destination crashes on, before it handles them
+    pub panic_n_first: Option<u16>,
+    pub retry: Option<RetryPolicyConfig>,
+    pub on_permanent_failure: Option<PermanentFailureConfig>,
+    pub queue_depth: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ExecResourceLimits {
+    /// Maximum cpu time in seconds (RLIMIT_CPU)
+    pub cpu: Option<u64>,
+    /// Maximum size of the virtual address space in bytes (RLIMIT_AS)
+    pub memory: Option<u64>,
+    /// Maximum size of files created by the process in bytes (RLIMIT_FSIZE)
+    pub filesize: Option<u64>,
+    /// Maximum amount of open file descriptors (RLIMIT_NOFILE)
+    pub files: Option<u64>,
+    /// Maximum amount of processes for the user (RLIMIT_NPROC)
+    pub processes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecInputFormat {
+    /// The raw RFC822 message
+    #[default]
+    #[serde(rename = "rfc822")]
+    Rfc822,
+    /// A JSON envelope with headers, decoded text parts and the list of attachments
+    #[serde(rename = "json")]
+    Json,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct ExecDestinationConfig {
     pub executable: String,
     pub arguments: Option<Vec<String>>,
     pub environment: Option<HashMap<String, String>>,
+    pub input: Option<ExecInputFormat>,
+    /// Keep long-running worker processes, instead of spawning one process per mail
+    pub persistent: Option<bool>,
+    /// Amount of worker processes in persistent mode
+    pub workers: Option<usize>,
+    /// Seconds after which a still running child is terminated
+    pub timeout: Option<u64>,
+    /// Seconds between SIGTERM and SIGKILL when terminating a child
+    pub kill_timeout: Option<u64>,
+    /// Maximum amount of bytes captured from stdout and stderr each
+    pub output_limit: Option<usize>,
+    pub workdir: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub limits: Option<ExecResourceLimits>,
+    pub retry: Option<RetryPolicyConfig>,
+    pub on_permanent_failure: Option<PermanentFailureConfig>,
+    pub queue_depth: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// The raw message, sent as message/rfc822
+    #[default]
+    #[serde(rename = "rfc822")]
+    Rfc822,
+    /// A JSON envelope with headers, decoded text parts and base64 encoded attachments
+    #[serde(rename = "json")]
+    Json,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum WebhookAuth {
+    #[serde(rename = "bearer")]
+    Bearer { token: String },
+    #[serde(rename = "basic")]
+    Basic { user: String, password: String },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookDestinationConfig {
+    pub url: String,
+    pub format: Option<WebhookFormat>,
+    pub headers: Option<HashMap<String, String>>,
+    pub auth: Option<WebhookAuth>,
+    /// Secret used to sign the request body with HMAC-SHA256
+    pub hmac_secret: Option<String>,
+    /// Request timeout in seconds
+    pub timeout: Option<u64>,
+    pub retry: Option<RetryPolicyConfig>,
+    pub on_permanent_failure: Option<PermanentFailureConfig>,
+    pub queue_depth: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum NotifyTarget {
+    /// POST a JSON notification (compatible with ntfy, Gotify and Apprise)
+    #[serde(rename = "http")]
+    Http {
+        url: String,
+        topic: Option<String>,
+        priority: Option<u8>,
+        headers: Option<HashMap<String, String>>,
+        auth: Option<WebhookAuth>,
+        timeout: Option<u64>,
+    },
+    /// Append one line per notification to a file
+    #[serde(rename = "file")]
+    File { path: String },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyDestinationConfig {
+    pub target: NotifyTarget,
+    pub title: Option<String>,
+    pub message: Option<String>,
+    /// Maximum length of the text body snippet in characters
+    pub snippet_length: Option<usize>,
+    /// Seconds during which further mails are aggregated into one notification
+    pub aggregate: Option<u64>,
+    /// Minimum amount of seconds between two notifications
+    pub min_interval: Option<u64>,
+    pub retry: Option<RetryPolicyConfig>,
+    pub on_permanent_failure: Option<PermanentFailureConfig>,
+    pub queue_depth: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum DestinationConfig {
@@ -153,26 +1136,137 @@ pub enum DestinationConfig {
     Smtp(SmtpDestinationConfig),
     #[serde(rename = "exec")]
     Exec(ExecDestinationConfig),
+    #[serde(rename = "webhook")]
+    Webhook(WebhookDestinationConfig),
+    #[serde(rename = "notify")]
+    Notify(NotifyDestinationConfig),
+}
+impl DestinationConfig {
+    pub fn retry(&self) -> Option<&RetryPolicyConfig> {
+        match self {
+            DestinationConfig::Test(config) => config.retry.as_ref(),
+            DestinationConfig::Smtp(config) => config.retry.as_ref(),
+            DestinationConfig::Exec(config) => config.retry.as_ref(),
+            DestinationConfig::Webhook(config) => config.retry.as_ref(),
+            DestinationConfig::Notify(config) => config.retry.as_ref(),
+        }
+    }
+    /// Destinations that undeliverable mails are handed to, with the setting naming them
+    pub fn handoffs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let fallback = self.on_permanent_failure().and_then(|p| p.destination());
+        let dead_letter = self.retry().and_then(|r| r.dead_letter.as_deref());
+        fallback
+            .map(|d| ("on_permanent_failure", d))
+            .into_iter()
+            .chain(dead_letter.map(|d| ("dead_letter", d)))
+    }
+    /// Maximum amount of delivery attempts, if the retry policy sets none
+    pub fn default_max_attempts(&self) -> Option<u32> {
+        match self {
+            DestinationConfig::Notify(_) => Some(NOTIFY_MAX_ATTEMPTS),
+            _ => None,
+        }
+    }
+    /// Maximum amount of mails queued for the destination. Sources delivering to a saturated
+    /// destination are paused, other mails wait in the hub until it takes mails again.
+    pub fn queue_depth(&self) -> Option<usize> {
+        match self {
+            DestinationConfig::Test(config) => config.queue_depth,
+            DestinationConfig::Smtp(config) => config.queue_depth,
+            DestinationConfig::Exec(config) => config.queue_depth,
+            DestinationConfig::Webhook(config) => config.queue_depth,
+            DestinationConfig::Notify(config) => config.queue_depth,
+        }
+    }
+    pub fn on_permanent_failure(&self) -> Option<&PermanentFailureConfig> {
+        match self {
+            DestinationConfig::Test(config) => config.on_permanent_failure.as_ref(),
+            DestinationConfig::Smtp(config) => config.on_permanent_failure.as_ref(),
+            DestinationConfig::Exec(config) => config.on_permanent_failure.as_ref(),
+            DestinationConfig::Webhook(config) => config.on_permanent_failure.as_ref(),
+            DestinationConfig::Notify(config) => config.on_permanent_failure.as_ref(),
+        }
+    }
 }
 
 // #############
 // # RetryAgent
 // #############
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MemoryRetryAgentConfig {
     pub delay: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct FilesystemRetryAgentConfig {
     pub delay: u64,
     pub path: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupScope {
+    /// A mail is forwarded to every destination at most once, regardless of its source
+    #[default]
+    #[serde(rename = "global")]
+    Global,
+    /// A mail is forwarded at most once per mapping from a source to a destination
+    #[serde(rename = "mapping")]
+    Mapping,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DedupConfig {
+    /// Seconds for which forwarded mails are remembered
+    pub window: u64,
+    /// Database in which forwarded mails are remembered across restarts
+    pub path: Option<String>,
+    pub scope: Option<DedupScope>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SpillConfig {
+    /// Size in bytes above which mails are moved out of memory
+    pub threshold: usize,
+    /// Folder the mails are stored in
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SupervisionConfig {
+    /// Seconds before a crashed agent is restarted, doubled with every further crash
+    pub min_delay: Option<u64>,
+    /// Maximum seconds before a crashed agent is restarted
+    pub max_delay: Option<u64>,
+    /// Maximum amount of restarts within the restart_window, before an agent is given up on
+    pub max_restarts: Option<usize>,
+    /// Seconds in which the restarts of an agent are counted
+    pub restart_window: Option<u64>,
+    /// File that the states of the agents are written to as JSON
+    pub status_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct JournalConfig {
+    /// Folder in which mails are persisted while they are in flight
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteRetryAgentConfig {
+    pub delay: u64,
+    /// Path of the database file, which is created if it does not exist
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum RetryAgentConfig {
@@ -180,4 +1274,326 @@ pub enum RetryAgentConfig {
     Memory(MemoryRetryAgentConfig),
     #[serde(rename = "filesystem")]
     Filesystem(FilesystemRetryAgentConfig),
+    #[serde(rename = "sqlite")]
+    Sqlite(SqliteRetryAgentConfig),
+}
+impl RetryAgentConfig {
+    /// Seconds before a failed mail is retried
+    pub fn delay(&self) -> u64 {
+        match self {
+            RetryAgentConfig::Memory(config) => config.delay,
+            RetryAgentConfig::Filesystem(config) => config.delay,
+            RetryAgentConfig::Sqlite(config) => config.delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(
+            dir.path().join("main.toml"),
+            "include = [\"conf.d/*.toml\", \"destinations.yaml\"]\n\
+             [retryagent]\ntype = \"memory\"\ndelay = 60\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("conf.d/src.toml"),
+            "[sources.src]\ntype = \"test\"\ndelay = 0\ninterval = 1\n\
+             [mappings]\nsrc = [\"dst\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("destinations.yaml"),
+            "destinations:\n  dst:\n    type: test\n    fail_n_first: 0\n",
+        )
+        .unwrap();
+        let config = ConfigContainer::from_file(dir.path().join("main.toml")).unwrap();
+        assert!(config.sources.contains_key("src"));
+        assert!(config.destinations.contains_key("dst"));
+        assert_eq!(config.retryagent.unwrap().delay(), 60);
+
+        // everything can only be defined once
+        fs::write(
+            dir.path().join("conf.d/dst.toml"),
+            "[destinations.dst]\ntype = \"test\"\nfail_n_first = 1\n",
+        )
+        .unwrap();
+        let err = ConfigContainer::from_file(dir.path().join("main.toml")).unwrap_err();
+        assert!(
+            err.contains("Destination dst is already defined in"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            r#"
+templates:
+  provider:
+    type: imap_idle
+    server: imap.example.org
+    port: 993
+    path: INBOX
+    renewinterval: 300
+    keep: true
+    auth:
+      type: login
+      user: "${name}@example.org"
+      password: "${IDLEMAIL_TEST_PASSWORD}"
+sources:
+  alice:
+    template: provider
+    keep: false
+    auth:
+      password: "$${literal}"
+  bob:
+    template: provider
+mappings:
+  alice: []
+  bob: []
+"#,
+        )
+        .unwrap();
+        env::set_var("IDLEMAIL_TEST_PASSWORD", "secret");
+        let config = ConfigContainer::from_file(&path).unwrap();
+        let (alice, bob) = match (&config.sources["alice"], &config.sources["bob"]) {
+            (SourceConfig::ImapIdle(alice), SourceConfig::ImapIdle(bob)) => (alice, bob),
+            _ => panic!("the type was not inherited"),
+        };
+        assert!(!alice.keep);
+        assert!(bob.keep);
+        match (&alice.auth, &bob.auth) {
+            (
+                AuthMethod::Login { user, password },
+                AuthMethod::Login {
+                    user: bob_user,
+                    password: bob_password,
+                },
+            ) => {
+                assert_eq!(user, "alice@example.org");
+                assert_eq!(password, "${literal}");
+                assert_eq!(bob_user, "bob@example.org");
+                assert_eq!(bob_password, "secret");
+            }
+            _ => panic!("the auth was not inherited"),
+        }
+    }
+
+    #[test]
+    fn test_entry_errors_are_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "[sources.a]\ntype = \"test\"\nbogus = 1\n\n[destinations.d]\ntype = \"test\"\nfail_n_first = \"x\"\n",
+        )
+        .unwrap();
+        let err = ConfigContainer::from_file(&path).unwrap_err();
+        let errors: Vec<_> = err.lines().collect();
+        assert_eq!(errors.len(), 2, "{}", err);
+        assert!(
+            errors[0].contains(":3:9: Source a: unknown field `bogus`"),
+            "{}",
+            err
+        );
+        assert!(
+            errors[1].contains(":5:1: Destination d: invalid type"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            r#"
+sources:
+  poll:
+    type: imap_poll
+    server: imap.example.org
+    port: 993
+    interval: 0
+    keep: true
+    auth:
+      type: none
+destinations:
+  smtp:
+    type: smtp
+    server: smtp.example.org
+    port: 25
+    encryption:
+      type: none
+    recipient: not an address
+  exec:
+    type: exec
+    executable: /nonexistent/deliver
+    workdir: /tmp
+mappings:
+  poll: [smtp, exec]
+"#,
+        )
+        .unwrap();
+        // all errors are reported at once
+        let err = ConfigContainer::from_file(&path).unwrap_err();
+        let errors: Vec<_> = err.lines().collect();
+        assert_eq!(errors.len(), 3, "{}", err);
+        assert!(err.contains("Source: poll has an interval of 0"), "{}", err);
+        assert!(
+            err.contains("Destination: smtp has an invalid recipient"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("Destination: exec: Executable /nonexistent/deliver does not exist"),
+            "{}",
+            err
+        );
+
+        fs::write(
+            &path,
+            r#"
+sources:
+  idle:
+    type: imap_idle
+    server: imap.example.org
+    port: 993
+    path: INBOX
+    renewinterval: 3600
+    keep: true
+    auth:
+      type: none
+destinations:
+  dst:
+    type: test
+    fail_n_first: 0
+  unused:
+    type: test
+    fail_n_first: 0
+mappings:
+  idle: [dst]
+"#,
+        )
+        .unwrap();
+        let mut warnings = ConfigContainer::from_file(&path).unwrap().warnings();
+        warnings.sort();
+        assert_eq!(
+            warnings,
+            vec![
+                "Destination: unused is not used by any mapping",
+                "Source: idle has a renewinterval above 29 minutes, servers may drop the connection before it is renewed",
+            ]
+        );
+
+        // mails handed off as dead letter must not loop, even through a fallback
+        fs::write(
+            &path,
+            r#"
+sources:
+  src:
+    type: test
+    delay: 0
+    interval: 1
+destinations:
+  a:
+    type: test
+    fail_n_first: 0
+    retry:
+      dead_letter: b
+  b:
+    type: test
+    fail_n_first: 0
+    on_permanent_failure:
+      type: fallback
+      destination: a
+mappings:
+  src: [a]
+"#,
+        )
+        .unwrap();
+        let err = ConfigContainer::from_file(&path).unwrap_err();
+        let mut errors: Vec<_> = err.lines().collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            vec![
+                "Destination: a has a loop in its on_permanent_failure and dead_letter chain",
+                "Destination: b has a loop in its on_permanent_failure and dead_letter chain",
+            ]
+        );
+
+        let mut config: ConfigContainer = serde_json::from_str(
+            r#"{
+                "destinations": { "dst": { "type": "test", "fail_n_first": 0 } },
+                "sources": {},
+                "mappings": {}
+            }"#,
+        )
+        .unwrap();
+        if let Some(DestinationConfig::Test(dstcfg)) = config.destinations.get_mut("dst") {
+            dstcfg.retry = Some(RetryPolicyConfig {
+                backoff: Some(f64::INFINITY),
+                ..Default::default()
+            });
+        }
+        assert_eq!(
+            config.errors(),
+            vec!["Destination: dst has a retry backoff that is not a number of at least 1"]
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema: Value = serde_json::from_str(&schema()).unwrap();
+        for section in [
+            "include",
+            "templates",
+            "sources",
+            "destinations",
+            "mappings",
+        ] {
+            assert!(schema["properties"][section].is_object(), "{}", section);
+        }
+        assert!(schema["definitions"]["DestinationConfig"]["oneOf"].is_array());
+    }
+
+    #[test_case("${name}" => Ok("alice".to_owned()))]
+    #[test_case("a$${name}b" => Ok("a${name}b".to_owned()))]
+    #[test_case("${IDLEMAIL_TEST_UNSET}" => Err("Environment variable IDLEMAIL_TEST_UNSET is not set".to_owned()))]
+    #[test_case("${name" => Err("Unterminated variable in: ${name".to_owned()))]
+    fn test_interpolate(text: &str) -> Result<String, String> {
+        interpolate_str(text, "alice")
+    }
+
+    #[test_case("config.json", "{\n  \"sources\": {},\n  \"mappings\": []\n}", ":3:14: "; "json")]
+    #[test_case("config.toml", "# comment\n\nmappings = 1\n", ":3:12: "; "toml")]
+    #[test_case("config.yaml", "sources: {}\nmapping: {}\n", ":2:1: "; "yaml")]
+    #[test_case("config.toml", "[sources.a]\ntype = \"test\"\ndelay = 0\ninterval = 1\nbogus = 1\n", ":5:9: Source a: unknown field"; "unknown field in a toml source")]
+    #[test_case("config.yaml", "destinations:\n  d:\n    type: test\n    fail_n_first: x\n", ":3:5: Destination d: invalid type"; "invalid value in a yaml destination")]
+    #[test_case("config.json", "{\n  \"sources\": {\n    \"a\": { \"template\": \"t\" }\n  }\n}", ":3:26: Source a: Unknown template"; "unknown template in a json source")]
+    fn test_error_location(filename: &str, content: &str, location: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(filename);
+        fs::write(&path, content).unwrap();
+        let err = ConfigContainer::from_file(&path).unwrap_err();
+        assert!(
+            err.starts_with(&format!("{}{}", path.display(), location)),
+            "{}",
+            err
+        );
+    }
 }
@@ -0,0 +1,219 @@
+use crate::{
+    config::{DedupConfig, DedupScope},
+    hub::Mail,
+};
+use anyhow::{Context, Result};
+use log::debug;
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Interval in which expired entries are removed from the database
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS seen (
+        key TEXT PRIMARY KEY NOT NULL,
+        seen_time INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS seen_seen_time ON seen (seen_time);
+";
+
+/// Identity of a mail: its Message-ID, or a hash of its content if it has none
+fn mail_identity(mail: &Mail) -> String {
+    match &mail.message_id {
+        Some(message_id) => format!("message-id:{}", message_id),
+        None => format!("sha256:{}", mail.hash),
+    }
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Remembers which mails were forwarded to which destinations, to drop duplicates.
+///
+/// Mails are only remembered persistently once they were delivered. A mail that is re-fetched
+/// after a crash before its delivery is thus no duplicate.
+pub struct Deduplicator {
+    connection: Mutex<Connection>,
+    window: i64,
+    scope: DedupScope,
+    last_purge: Mutex<Instant>,
+    /// Keys of the mails distributed to destinations that did not deliver them yet, with the
+    /// time they were distributed
+    in_flight: Mutex<HashMap<String, Instant>>,
+}
+impl Deduplicator {
+    pub fn new(config: &DedupConfig) -> Result<Self> {
+        let connection = match &config.path {
+            Some(path) => Connection::open(path).context("Failed to open database")?,
+            None => Connection::open_in_memory().context("Failed to open database")?,
+        };
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to create database schema")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            window: config.window as i64,
+            scope: config.scope.unwrap_or_default(),
+            last_purge: Mutex::new(Instant::now()),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn key(&self, srcname: &str, identity: &str, dstname: &str) -> String {
+        match self.scope {
+            DedupScope::Global => format!("{}\n{}", dstname, identity),
+            DedupScope::Mapping => format!("{}\n{}\n{}", srcname, dstname, identity),
+        }
+    }
+
+    /// Filter the given destinations down to those that did not receive the mail yet.
+    pub fn unseen(
+        &self,
+        srcname: &str,
+        mail: &Mail,
+        destinations: &[String],
+    ) -> Result<Vec<String>> {
+        let identity = mail_identity(mail);
+        let window = Duration::from_secs(self.window as u64);
+        let in_flight = self.in_flight.lock().unwrap();
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached("SELECT COUNT(*) FROM seen WHERE key = ?1 AND seen_time >= ?2")?;
+        let mut unseen = Vec::new();
+        for dstname in destinations {
+            let key = self.key(srcname, &identity, dstname);
+            if in_flight
+                .get(&key)
+                .is_some_and(|distributed| distributed.elapsed() < window)
+            {
+                continue;
+            }
+            let count: i64 =
+                statement.query_row(params![key, unix_time() - self.window], |row| row.get(0))?;
+            if count == 0 {
+                unseen.push(dstname.clone());
+            }
+        }
+        Ok(unseen)
+    }
+
+    /// Remember that the mail is distributed to the given destinations, until it is delivered.
+    pub fn reserve(&self, srcname: &str, mail: &Mail, destinations: &[String]) {
+        let identity = mail_identity(mail);
+        let window = Duration::from_secs(self.window as u64);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.retain(|_, distributed| distributed.elapsed() < window);
+        for dstname in destinations {
+            in_flight.insert(self.key(srcname, &identity, dstname), Instant::now());
+        }
+    }
+
+    /// Remember that the mail was delivered to the given destinations.
+    pub fn record(&self, srcname: &str, mail: &Mail, destinations: &[String]) -> Result<()> {
+        let identity = mail_identity(mail);
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction
+                .prepare_cached("INSERT OR REPLACE INTO seen (key, seen_time) VALUES (?1, ?2)")?;
+            for dstname in destinations {
+                statement.execute(params![self.key(srcname, &identity, dstname), unix_time()])?;
+            }
+        }
+        transaction.commit()?;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for dstname in destinations {
+            in_flight.remove(&self.key(srcname, &identity, dstname));
+        }
+
+        let mut last_purge = self.last_purge.lock().unwrap();
+        if last_purge.elapsed() >= PURGE_INTERVAL {
+            let purged = connection.execute(
+                "DELETE FROM seen WHERE seen_time < ?1",
+                params![unix_time() - self.window],
+            )?;
+            debug!(target: "Dedup", "Purged {} expired entries", purged);
+            *last_purge = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destinations() -> Vec<String> {
+        vec!["dst0".to_owned(), "dst1".to_owned()]
+    }
+
+    #[test]
+    fn test_global_scope() {
+        let dedup = Deduplicator::new(&DedupConfig {
+            window: 3600,
+            path: None,
+            scope: None,
+        })
+        .unwrap();
+        let mail = Mail::from_rfc822(
+            "src0".to_owned(),
+            b"Message-ID: <a@example.org>\r\n\r\nBody".to_vec(),
+        );
+        assert_eq!(
+            dedup.unseen("src0", &mail, &destinations()).unwrap(),
+            destinations()
+        );
+        // mails in flight are duplicates as well
+        dedup.reserve("src0", &mail, &destinations()[..1]);
+        assert_eq!(
+            dedup.unseen("src0", &mail, &destinations()).unwrap(),
+            vec!["dst1"]
+        );
+        dedup.record("src0", &mail, &destinations()[..1]).unwrap();
+
+        // the same mail through another source, with different content
+        let copy = Mail::from_rfc822(
+            "src1".to_owned(),
+            b"Message-ID: <a@example.org>\r\nX-List: yes\r\n\r\nBody".to_vec(),
+        );
+        assert_eq!(
+            dedup.unseen("src1", &copy, &destinations()).unwrap(),
+            vec!["dst1"]
+        );
+    }
+
+    #[test]
+    fn test_mapping_scope_persistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DedupConfig {
+            window: 3600,
+            path: Some(dir.path().join("dedup.db").to_string_lossy().to_string()),
+            scope: Some(DedupScope::Mapping),
+        };
+        // without Message-ID, the content is used
+        let mail = Mail::from_rfc822("src0".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec());
+        Deduplicator::new(&config)
+            .unwrap()
+            .record("src0", &mail, &destinations())
+            .unwrap();
+
+        let dedup = Deduplicator::new(&config).unwrap();
+        assert!(dedup
+            .unseen("src0", &mail, &destinations())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            dedup.unseen("src1", &mail, &destinations()).unwrap(),
+            destinations()
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use super::process::{self, DEFAULT_KILL_TIMEOUT};
+use crate::config::ExecDestinationConfig;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::{
+    io::{BufRead, BufReader},
+    os::unix::io::AsRawFd,
+    process::{Child, ChildStdin},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Response of a persistent worker process for a single mail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    TempFail(String),
+    PermFail(String),
+}
+impl Response {
+    fn parse(line: &str) -> Result<Self> {
+        let line = line.trim_end();
+        let (status, reason) = match line.split_once(' ') {
+            Some((status, reason)) => (status, reason.to_owned()),
+            None => (line, String::new()),
+        };
+        match status {
+            "OK" => Ok(Response::Ok),
+            "TEMPFAIL" => Ok(Response::TempFail(reason)),
+            "PERMFAIL" => Ok(Response::PermFail(reason)),
+            _ => Err(anyhow!("Invalid response from worker: {}", line)),
+        }
+    }
+}
+
+/// A long-running worker process, that is fed mails over its stdin.
+///
+/// Each mail is sent as a frame consisting of the payload's length in bytes as decimal
+/// number, followed by a newline and the payload itself.
+/// The worker answers every frame with a single line on its stdout:
+/// `OK`, `TEMPFAIL <reason>` or `PERMFAIL <reason>`.
+pub struct CoProcess {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    responses: mpsc::Receiver<String>,
+    kill_timeout: Duration,
+}
+impl CoProcess {
+    pub fn spawn(config: &ExecDestinationConfig, dstname: &str, worker_id: usize) -> Result<Self> {
+        let mut command = process::build_command(config);
+        command.env("IDLEMAIL_DESTINATION", dstname);
+        command.env("IDLEMAIL_WORKER", worker_id.to_string());
+        let mut child = command.spawn().context("Failed to spawn worker process")?;
+
+        // stdin is written with the same timeout as the response is awaited with
+        let stdin = child.stdin.take();
+        if let Some(stdin) = &stdin {
+            process::set_nonblocking(stdin.as_raw_fd())
+                .context("Failed to configure stdin of worker process")?;
+        }
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("Failed to open stdout of worker process"))?;
+        let stderr = child.stderr.take();
+
+        // Responses are read by a separate thread, so we can wait for them with a timeout.
+        // If the worker exits, the channel disconnects.
+        let (response_sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if response_sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        // stderr of persistent workers is logged continuously
+        if let Some(stderr) = stderr {
+            let log_target = format!("ExecDst[{}][Worker{}]", dstname, worker_id);
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    warn!(target: &log_target, "{}", line);
+                }
+            });
+        }
+
+        Ok(Self {
+            child,
+            stdin,
+            responses,
+            kill_timeout: Duration::from_secs(config.kill_timeout.unwrap_or(DEFAULT_KILL_TIMEOUT)),
+        })
+    }
+
+    /// Send one mail to the worker and wait for its response, both within the timeout.
+    /// An error means, that the worker is unusable and has to be restarted.
+    pub fn deliver(&mut self, payload: &[u8], timeout: Option<Duration>) -> Result<Response> {
+        let started = Instant::now();
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("Failed to open stdin of worker process"))?;
+        let expired = || timeout.is_some_and(|timeout| started.elapsed() >= timeout);
+        process::write_all(stdin, format!("{}\n", payload.len()).as_bytes(), expired)
+            .and_then(|_| process::write_all(stdin, payload, expired))
+            .context("Failed to send mail to worker process")?;
+
+        let response = match timeout {
+            Some(timeout) => self
+                .responses
+                .recv_timeout(timeout.saturating_sub(started.elapsed()))
+                .map_err(|e| match e {
+                    mpsc::RecvTimeoutError::Timeout => {
+                        anyhow!("Worker did not respond within {}s", timeout.as_secs())
+                    }
+                    mpsc::RecvTimeoutError::Disconnected => anyhow!("Worker process exited"),
+                })?,
+            None => self
+                .responses
+                .recv()
+                .map_err(|_| anyhow!("Worker process exited"))?,
+        };
+        Response::parse(&response)
+    }
+
+    /// Close the worker's stdin and wait for it to exit.
+    /// Workers that do not exit on their own are terminated (SIGTERM, then SIGKILL).
+    pub fn terminate(mut self, log_target: &str) {
+        drop(self.stdin.take());
+        match process::wait_timeout(&mut self.child, Some(self.kill_timeout), self.kill_timeout) {
+            Ok(process::ChildOutcome::Exited(status)) => {
+                info!(target: log_target, "Worker exited with: {}", status)
+            }
+            Ok(process::ChildOutcome::TimedOut) => {
+                warn!(target: log_target, "Worker did not exit and was terminated")
+            }
+            Err(e) => warn!(target: log_target, "Failed to wait for worker: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("OK" => Response::Ok)]
+    #[test_case("OK\r" => Response::Ok)]
+    #[test_case("TEMPFAIL database locked" => Response::TempFail("database locked".to_owned()))]
+    #[test_case("PERMFAIL" => Response::PermFail("".to_owned()))]
+    fn test_parse_response(line: &str) -> Response {
+        Response::parse(line).unwrap()
+    }
+
+    #[test]
+    fn test_parse_invalid_response() {
+        assert!(Response::parse("Hello World").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::hub::Mail;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use mail_parser::{Address, Message, MessageParser, MimeHeaders};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+/// Metadata parsed from the headers of a mail.
+#[derive(Serialize, Debug, Default)]
+pub struct MailMetadata {
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub subject: Option<String>,
+    pub message_id: Option<String>,
+    pub date: Option<String>,
+}
+impl MailMetadata {
+    fn from_message(message: &Message) -> Self {
+        Self {
+            from: message.from().and_then(format_first_address),
+            to: message.to().map(format_addresses).unwrap_or_default(),
+            subject: message.subject().map(|s| s.to_owned()),
+            message_id: message.message_id().map(|s| s.to_owned()),
+            date: message.date().map(|d| d.to_rfc3339()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct MailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MailAttachment {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: usize,
+    /// base64 encoded contents, only included when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+/// JSON representation of a mail, for destinations that hand the mail over in
+/// a pre-parsed form instead of the raw RFC822 message.
+#[derive(Serialize, Debug)]
+pub struct MailEnvelope {
+    pub source: String,
+    pub destination: String,
+    pub id: String,
+    pub hash: String,
+    pub mailbox: Option<String>,
+    pub attempt: u32,
+    pub size: usize,
+    /// Metadata provided by the source
+    pub metadata: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub parsed: MailMetadata,
+    pub headers: Vec<MailHeader>,
+    pub text: Vec<String>,
+    pub html: Vec<String>,
+    pub attachments: Vec<MailAttachment>,
+}
+
+fn format_address(name: Option<&str>, address: Option<&str>) -> Option<String> {
+    match (name, address) {
+        (Some(name), Some(address)) => Some(format!("{} <{}>", name, address)),
+        (None, Some(address)) => Some(address.to_owned()),
+        (Some(name), None) => Some(name.to_owned()),
+        (None, None) => None,
+    }
+}
+fn format_first_address(address: &Address) -> Option<String> {
+    address
+        .first()
+        .and_then(|a| format_address(a.name.as_deref(), a.address.as_deref()))
+}
+fn format_addresses(address: &Address) -> Vec<String> {
+    address
+        .iter()
+        .filter_map(|a| format_address(a.name.as_deref(), a.address.as_deref()))
+        .collect()
+}
+
+/// Parse the metadata of the given raw mail. Returns empty metadata if the mail could not be parsed.
+pub fn parse_metadata(data: &[u8]) -> MailMetadata {
+    MessageParser::default()
+        .parse(data)
+        .map(|message| MailMetadata::from_message(&message))
+        .unwrap_or_default()
+}
+
+/// Parse the given mail (with its raw content `data`) into a MailEnvelope. Attachment contents
+/// are only included (base64 encoded) if `attachment_data` is set, otherwise only their metadata
+/// is listed.
+pub fn parse_envelope(
+    mail: &Mail,
+    data: &[u8],
+    dstname: &str,
+    attachment_data: bool,
+) -> MailEnvelope {
+    let mut envelope = MailEnvelope {
+        source: mail.from_src.clone(),
+        destination: dstname.to_owned(),
+        id: mail.id.clone(),
+        hash: mail.hash.clone(),
+        mailbox: mail.mailbox().map(|m| m.to_owned()),
+        attempt: mail.attempt,
+        size: mail.data.len(),
+        metadata: mail.metadata.clone(),
+        parsed: MailMetadata::default(),
+        headers: Vec::new(),
+        text: Vec::new(),
+        html: Vec::new(),
+        attachments: Vec::new(),
+    };
+    let message = match MessageParser::default().parse(data) {
+        Some(message) => message,
+        None => return envelope,
+    };
+
+    envelope.parsed = MailMetadata::from_message(&message);
+    envelope.headers = message
+        .headers_raw()
+        .map(|(name, value)| MailHeader {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+        })
+        .collect();
+    envelope.text = message
+        .text_bodies()
+        .filter_map(|part| part.text_contents().map(|s| s.to_owned()))
+        .collect();
+    envelope.html = message
+        .html_bodies()
+        .filter_map(|part| part.text_contents().map(|s| s.to_owned()))
+        .collect();
+    envelope.attachments = message
+        .attachments()
+        .map(|part| MailAttachment {
+            filename: part.attachment_name().map(|s| s.to_owned()),
+            content_type: part.content_type().map(|ct| match ct.subtype() {
+                Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                None => ct.ctype().to_owned(),
+            }),
+            size: part.len(),
+            data: attachment_data.then(|| BASE64_STANDARD.encode(part.contents())),
+        })
+        .collect();
+    envelope
+}
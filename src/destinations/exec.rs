@@ -1,21 +1,32 @@
 use crate::{
-    config::ExecDestinationConfig,
-    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    config::{ExecDestinationConfig, ExecInputFormat},
+    hub::{metadata, DestinationMessage, HubDestinationChannel, Mail, MailAgent},
+    logging::mail_log,
 };
-use log::{debug, error, info, log_enabled, trace, Level as log_level};
+use async_std::task;
+use futures::future;
+use log::{debug, error, info, log_enabled, trace, warn, Level as log_level};
 use std::{
-    io::{Read, Write},
-    process::{Command, Stdio},
-    thread,
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use super::MailDestination;
+use super::{
+    coprocess::{CoProcess, Response},
+    envelope,
+    process::{self, ChildOutcome},
+    MailDestination,
+};
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
 
 pub struct ExecDestination {
     name: String,
     log_target: String,
     config: ExecDestinationConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl ExecDestination {
     pub fn new(name: String, config: &ExecDestinationConfig) -> Self {
@@ -27,13 +38,48 @@ impl ExecDestination {
         }
     }
 }
+
+/// Environment variables describing the mail, for the spawned process
+fn mail_environment(dstname: &str, mail: &Mail, data: &[u8]) -> Vec<(&'static str, String)> {
+    let parsed = envelope::parse_metadata(data);
+    let source_metadata = |key: &str| mail.metadata.get(key).cloned();
+    let mut environment = vec![
+        ("IDLEMAIL_DESTINATION", dstname.to_owned()),
+        ("IDLEMAIL_SOURCE", mail.from_src.clone()),
+        ("IDLEMAIL_ID", mail.id.clone()),
+        ("IDLEMAIL_HASH", mail.hash.clone()),
+        ("IDLEMAIL_SIZE", mail.data.len().to_string()),
+        ("IDLEMAIL_ATTEMPT", mail.attempt.to_string()),
+    ];
+    let optional = [
+        ("IDLEMAIL_MAILBOX", source_metadata(metadata::MAILBOX)),
+        ("IDLEMAIL_UID", source_metadata(metadata::UID)),
+        (
+            "IDLEMAIL_UIDVALIDITY",
+            source_metadata(metadata::UIDVALIDITY),
+        ),
+        ("IDLEMAIL_FLAGS", source_metadata(metadata::FLAGS)),
+        (
+            "IDLEMAIL_INTERNAL_DATE",
+            source_metadata(metadata::INTERNAL_DATE),
+        ),
+        ("IDLEMAIL_FETCHED", source_metadata(metadata::FETCHED)),
+        ("IDLEMAIL_FROM", parsed.from),
+        ("IDLEMAIL_SUBJECT", parsed.subject),
+        ("IDLEMAIL_MESSAGE_ID", parsed.message_id),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            // environment variables can not contain NUL bytes
+            environment.push((key, value.replace('\0', "")));
+        }
+    }
+    environment
+}
+
 impl MailAgent for ExecDestination {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailDestination for ExecDestination {
@@ -43,111 +89,314 @@ impl MailDestination for ExecDestination {
 
         let name = self.name.clone();
         let log_target = self.log_target.clone();
-        let config = self.config.clone();
-        self.worker = Some(thread::spawn(move || {
-            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
-                // spawn the process with the apropriate configuration (args, env, ..)
-                let mut exec_config = Command::new(&config.executable);
-                exec_config.stdin(Stdio::piped()).stdout(Stdio::piped());
-                if let Some(arguments) = config.arguments.as_ref() {
-                    exec_config.args(arguments);
-                }
-                if let Some(environment) = config.environment.as_ref() {
-                    exec_config.envs(environment);
-                }
-                exec_config.env("IDLEMAIL_DESTINATION", &name);
-                exec_config.env("IDLEMAIL_SOURCE", &mail.from_src);
-
-                match exec_config.spawn() {
-                    Ok(ref mut child) => {
-                        match child.stdin.as_mut().map(|stdin| stdin.write(&mail.data)) {
-                            Some(Ok(_)) => {
-                                // we successfully opened stdin, and piped the mail to the child
-                                // wait for child to exit
-                                let child_result = child.wait();
-                                if log_enabled!(log_level::Debug) {
-                                    // if debug log is enabled, print child output
-                                    // we do this manually to ensure, that child-output is one block in the log
-                                    // child messages randomly mixed in would be ugly
-                                    if let Some(stdout) = child.stdout.as_mut() {
-                                        let mut child_log = String::new();
-                                        if stdout.read_to_string(&mut child_log).is_ok() {
-                                            debug!(
-                                                target: &format!("{}[Child]", log_target),
-                                                "{}", child_log
-                                            );
-                                        }
-                                    }
-                                }
-                                // handle child exit status
-                                match child_result {
-                                    Ok(res) => {
-                                        debug!(
-                                            target: &log_target,
-                                            "Successfully sent mail to child"
-                                        );
-                                        if res.success() {
-                                            info!(
-                                                target: &log_target,
-                                                "Child exited with: {}",
-                                                res.code().unwrap_or(0)
-                                            );
-                                            continue;
-                                        } else {
-                                            error!(
-                                                target: &log_target,
-                                                "Child exited with: {}",
-                                                res.code().unwrap_or(-1)
-                                            );
-                                        }
-                                    }
-                                    Err(err) => error!(
-                                        target: &log_target,
-                                        "Child exited with error: {}", err
-                                    ),
-                                }
-                            }
-                            Some(Err(err)) => {
-                                // piping stdin to the child went wrong
-                                error!(
-                                    target: &log_target,
-                                    "Error while piping mail to spawned process: {}", err
-                                )
-                            }
-                            None => {
-                                error!(target: &log_target, "Failed to open stdin of child process")
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        error!(
-                            target: &log_target,
-                            "Error while spawning configured executable: {}", err
-                        );
-                    }
-                }
-                // if we made it here (we continue on success), something went wrong
-                channel.notify_failed_send(mail);
+        let config = Arc::new(self.config.clone());
+        self.worker = Some(task::spawn(async move {
+            if config.persistent.unwrap_or(false) {
+                run_persistent(&name, &log_target, &config, channel).await;
+            } else {
+                run_spawning(&name, &log_target, &config, channel).await;
             }
             info!(target: &log_target, "Stopping");
         }));
     }
 }
 
+/// Data piped into the executable for the given mail, depending on the configured input format
+fn mail_input<'a>(
+    config: &ExecDestinationConfig,
+    dstname: &str,
+    mail: &Mail,
+    data: &'a [u8],
+) -> serde_json::Result<Cow<'a, [u8]>> {
+    match config.input.unwrap_or_default() {
+        ExecInputFormat::Rfc822 => Ok(Cow::Borrowed(data)),
+        ExecInputFormat::Json => {
+            serde_json::to_vec(&envelope::parse_envelope(mail, data, dstname, false))
+                .map(Cow::Owned)
+        }
+    }
+}
+
+/// Result of handing a mail to the executable
+enum Outcome {
+    Delivered,
+    /// The executable rejected the mail permanently
+    Rejected(String),
+    Failed(String),
+}
+
+/// Spawn one instance of the configured executable per mail
+async fn run_spawning(
+    name: &str,
+    log_target: &str,
+    config: &Arc<ExecDestinationConfig>,
+    channel: HubDestinationChannel,
+) {
+    while let Ok(DestinationMessage::Mail { mail }) = channel.next().await {
+        // running the process blocks, keep it off the executor
+        let (mail, result) = {
+            let (name, log_target, config) =
+                (name.to_owned(), log_target.to_owned(), config.clone());
+            task::spawn_blocking(move || {
+                let result = execute(&name, &log_target, &config, &mail);
+                (mail, result)
+            })
+            .await
+        };
+        match result {
+            Ok(()) => channel.notify_successful_send(mail),
+            Err(reason) => {
+                mail_log!(
+                    Error,
+                    log_target,
+                    mail.log_fields().destination(name),
+                    "{}",
+                    reason
+                );
+                channel.notify_failed_send(mail, reason);
+            }
+        }
+    }
+}
+
+/// Run the configured executable for a single mail
+fn execute(
+    name: &str,
+    log_target: &str,
+    config: &ExecDestinationConfig,
+    mail: &Mail,
+) -> Result<(), String> {
+    let data = mail
+        .data
+        .bytes()
+        .map_err(|err| format!("Failed to read mail: {}", err))?;
+    // spawn the process with the apropriate configuration (args, env, sandboxing, ..)
+    let mut command = process::build_command(config);
+    command.envs(mail_environment(name, mail, &data));
+    let input = mail_input(config, name, mail, &data)
+        .map_err(|err| format!("Failed to serialize mail envelope: {}", err))?;
+
+    let result = process::run(&mut command, config, &input)
+        .map_err(|err| format!("Error while running configured executable: {}", err))?;
+    if log_enabled!(log_level::Debug) {
+        // if debug log is enabled, print child output
+        // we do this manually to ensure, that child-output is one block in the log
+        // child messages randomly mixed in would be ugly
+        debug!(
+            target: &format!("{}[Child]", log_target),
+            "{}",
+            result.stdout.as_string()
+        );
+    }
+    if !result.stderr.data.is_empty() {
+        warn!(
+            target: &format!("{}[Child]", log_target),
+            "{}",
+            result.stderr.as_string()
+        );
+    }
+    if let Some(err) = result.input_error {
+        debug!(
+            target: log_target,
+            "Error while piping mail to spawned process: {}", err
+        );
+    }
+    // handle child exit status
+    match result.outcome {
+        ChildOutcome::Exited(res) if res.success() => {
+            mail_log!(
+                Info,
+                log_target,
+                mail.log_fields().destination(name),
+                "Child exited with: {}",
+                res.code().unwrap_or(0)
+            );
+            Ok(())
+        }
+        ChildOutcome::Exited(res) => {
+            Err(format!("Child exited with: {}", res.code().unwrap_or(-1)))
+        }
+        ChildOutcome::TimedOut => Err(format!(
+            "Child did not exit within {}s and was terminated",
+            config.timeout.unwrap_or(0)
+        )),
+    }
+}
+
+/// Stream mails to the configured amount of long-running worker processes
+async fn run_persistent(
+    name: &str,
+    log_target: &str,
+    config: &Arc<ExecDestinationConfig>,
+    channel: HubDestinationChannel,
+) {
+    // idle workers wait on the same channel, each mail is taken by one of them
+    let channel = Arc::new(channel);
+    let workers = (0..config.workers.unwrap_or(1).max(1)).map(|worker_id| {
+        task::spawn(run_persistent_worker(
+            name.to_owned(),
+            format!("{}[Worker{}]", log_target, worker_id),
+            PersistentWorker::new(worker_id),
+            config.clone(),
+            channel.clone(),
+        ))
+    });
+    future::join_all(workers).await;
+}
+
+async fn run_persistent_worker(
+    name: String,
+    log_target: String,
+    mut worker: PersistentWorker,
+    config: Arc<ExecDestinationConfig>,
+    channel: Arc<HubDestinationChannel>,
+) {
+    while let Ok(DestinationMessage::Mail { mail }) = channel.next().await {
+        // (re)start the worker process if necessary, with an exponential backoff
+        if worker.coprocess.is_none() {
+            task::sleep(worker.next_start.saturating_duration_since(Instant::now())).await;
+        }
+        // talking to the worker process blocks, keep it off the executor
+        let (returned, mail, outcome) = {
+            let (name, log_target, config) = (name.clone(), log_target.clone(), config.clone());
+            task::spawn_blocking(move || {
+                let outcome = worker.deliver(&name, &log_target, &config, &mail);
+                (worker, mail, outcome)
+            })
+            .await
+        };
+        worker = returned;
+        match outcome {
+            Outcome::Delivered => channel.notify_successful_send(mail),
+            Outcome::Rejected(reason) => channel.notify_rejected_send(mail, reason),
+            Outcome::Failed(reason) => channel.notify_failed_send(mail, reason),
+        }
+    }
+
+    if let Some(coprocess) = worker.coprocess {
+        task::spawn_blocking(move || coprocess.terminate(&log_target)).await;
+    }
+}
+
+/// A long-running worker process, which is restarted with an exponential backoff if it fails
+struct PersistentWorker {
+    id: usize,
+    coprocess: Option<CoProcess>,
+    restart_delay: Duration,
+    next_start: Instant,
+}
+impl PersistentWorker {
+    fn new(id: usize) -> Self {
+        Self {
+            id,
+            coprocess: None,
+            restart_delay: MIN_RESTART_DELAY,
+            next_start: Instant::now(),
+        }
+    }
+
+    fn backoff(&mut self) {
+        self.next_start = Instant::now() + self.restart_delay;
+        self.restart_delay = (self.restart_delay * 2).min(MAX_RESTART_DELAY);
+    }
+
+    fn deliver(
+        &mut self,
+        name: &str,
+        log_target: &str,
+        config: &ExecDestinationConfig,
+        mail: &Mail,
+    ) -> Outcome {
+        let data = match mail.data.bytes() {
+            Ok(data) => data,
+            Err(err) => {
+                let reason = format!("Failed to read mail: {}", err);
+                error!(target: log_target, "{}", reason);
+                return Outcome::Failed(reason);
+            }
+        };
+        let input = match mail_input(config, name, mail, &data) {
+            Ok(input) => input,
+            Err(err) => {
+                let reason = format!("Failed to serialize mail envelope: {}", err);
+                error!(target: log_target, "{}", reason);
+                return Outcome::Failed(reason);
+            }
+        };
+
+        if self.coprocess.is_none() {
+            match CoProcess::spawn(config, name, self.id) {
+                Ok(started) => {
+                    info!(target: log_target, "Worker process started");
+                    self.coprocess = Some(started);
+                }
+                Err(err) => {
+                    let reason = format!("{:#}", err);
+                    error!(target: log_target, "{}", reason);
+                    self.backoff();
+                    return Outcome::Failed(reason);
+                }
+            }
+        }
+
+        let coprocess = self.coprocess.as_mut().unwrap();
+        match coprocess.deliver(&input, config.timeout.map(Duration::from_secs)) {
+            Ok(Response::Ok) => {
+                mail_log!(
+                    Info,
+                    log_target,
+                    mail.log_fields().destination(name),
+                    "Worker accepted mail"
+                );
+                self.restart_delay = MIN_RESTART_DELAY;
+                Outcome::Delivered
+            }
+            Ok(Response::TempFail(reason)) => {
+                mail_log!(
+                    Error,
+                    log_target,
+                    mail.log_fields().destination(name),
+                    "Worker reported temporary failure: {}",
+                    reason
+                );
+                Outcome::Failed(reason)
+            }
+            Ok(Response::PermFail(reason)) => {
+                mail_log!(
+                    Warn,
+                    log_target,
+                    mail.log_fields().destination(name),
+                    "Worker rejected mail, will not try again: {}",
+                    reason
+                );
+                Outcome::Rejected(reason)
+            }
+            Err(err) => {
+                error!(target: log_target, "Worker failed, restarting: {:#}", err);
+                self.coprocess.take().unwrap().terminate(log_target);
+                self.backoff();
+                Outcome::Failed(format!("{:#}", err))
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::hub::Mail;
+    use crate::hub::HubMessage;
+    use async_std::channel as async_mpsc;
     use lettre::{
         message::{header, Mailbox, MultiPart, SinglePart},
         Message,
     };
     use std::{
-        collections::HashMap,
-        fs::{File, Permissions},
+        collections::{BTreeMap, HashMap},
+        fs::{self, File, Permissions},
+        io::Write,
         os::unix::prelude::PermissionsExt,
         path::PathBuf,
-        sync::mpsc,
+        thread,
     };
     use tempfile::TempDir;
     use test_case::test_case;
@@ -179,6 +428,7 @@ mod tests {
             .from(Mailbox::new(None, "sender@example.org".parse().unwrap()))
             .to(Mailbox::new(None, "receiver@example.or".parse().unwrap()))
             .subject("Test Email")
+            .message_id(Some("<unit-test@example.org>".to_owned()))
             .date_now()
             .multipart(body)
             .unwrap();
@@ -211,7 +461,7 @@ mod tests {
     #[test_case(vec!["ARG0"], HashMap::new() => false)]
     fn test_successfull_execution(cliargs: Vec<&str>, env: HashMap<String, String>) -> bool {
         let mail = create_testmail("unit-test source 0".to_owned());
-        let mailmd5 = format!("{:x}", md5::compute(&mail.data));
+        let mailmd5 = format!("{:x}", md5::compute(mail.data.bytes().unwrap()));
         let (_dir, executable_path) = prepare_validation_script(&format!(
             r#"#!/bin/bash
             cd $(dirname "$0")
@@ -226,27 +476,226 @@ mod tests {
             mailmd5
         ));
 
-        let mut execdst = ExecDestination::new(
-            "unit-test exec dst".to_owned(),
-            &ExecDestinationConfig {
+        run_execdst(
+            ExecDestinationConfig {
                 executable: executable_path.to_string_lossy().to_string(),
                 arguments: Some(cliargs.into_iter().map(|s| s.to_owned()).collect()),
                 environment: Some(env),
+                ..Default::default()
+            },
+            mail,
+        )
+    }
+
+    #[test_case(Some(1), "sleep 30" => false)]
+    #[test_case(Some(5), "sleep 0.1" => true)]
+    #[test_case(Some(5), "head -c 4194304 /dev/zero; head -c 4194304 /dev/zero >&2" => true)]
+    #[test_case(None, "exit 3" => false)]
+    fn test_timeout_and_output(timeout: Option<u64>, script: &str) -> bool {
+        let mail = create_testmail("unit-test source 0".to_owned());
+        let (_dir, executable_path) =
+            prepare_validation_script(&format!("#!/bin/bash\n{}\n", script));
+        run_execdst(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                timeout,
+                kill_timeout: Some(1),
+                output_limit: Some(1024),
+                ..Default::default()
+            },
+            mail,
+        )
+    }
+
+    #[test_case("sleep 30 & echo $! > pid", true ; "background")]
+    #[test_case("setsid sleep 5 & echo $! > pid", true ; "escaped process group")]
+    #[test_case("sleep 30 & echo $! > pid; sleep 30", false ; "timed out")]
+    fn test_grandchild_holding_pipes(script: &str, delivered: bool) {
+        let mail = create_testmail("unit-test source 0".to_owned());
+        let (dir, executable_path) =
+            prepare_validation_script(&format!("#!/bin/bash\ncd $(dirname \"$0\")\n{}\n", script));
+        let started = Instant::now();
+        let result = run_execdst(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                timeout: Some(2),
+                kill_timeout: Some(1),
+                ..Default::default()
+            },
+            mail,
+        );
+        assert_eq!(result, delivered);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // grandchildren within the process group are killed
+        let pid = fs::read_to_string(dir.path().join("pid")).unwrap();
+        let running = || {
+            fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+                .is_ok_and(|stat| !stat.contains(") Z "))
+        };
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while running() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(running(), script.starts_with("setsid"));
+    }
+
+    #[test]
+    fn test_workdir() {
+        let mail = create_testmail("unit-test source 0".to_owned());
+        let workdir = tempfile::tempdir().unwrap();
+        let (_dir, executable_path) = prepare_validation_script(&format!(
+            "#!/bin/bash\nif [ \"$(pwd)\" != \"{}\" ]; then exit 1; fi\n",
+            workdir.path().to_string_lossy()
+        ));
+        assert!(run_execdst(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                workdir: Some(workdir.path().to_string_lossy().to_string()),
+                ..Default::default()
+            },
+            mail,
+        ));
+    }
+
+    #[test]
+    fn test_mail_metadata() {
+        let mail = create_testmail("unit-test source 0".to_owned())
+            .with_mailbox("INBOX".to_owned())
+            .with_metadata(BTreeMap::from([(
+                metadata::UID.to_owned(),
+                "42".to_owned(),
+            )]));
+        let (_dir, executable_path) = prepare_validation_script(
+            r#"#!/bin/bash
+            if [ "$IDLEMAIL_FROM" != "sender@example.org" ]; then echo "From incorrect"; exit 1; fi
+            if [ "$IDLEMAIL_SUBJECT" != "Test Email" ]; then echo "Subject incorrect"; exit 1; fi
+            if [ "$IDLEMAIL_MAILBOX" != "INBOX" ]; then echo "Mailbox incorrect"; exit 1; fi
+            if [ "$IDLEMAIL_ATTEMPT" != "1" ]; then echo "Attempt incorrect"; exit 1; fi
+            if [ "$IDLEMAIL_UID" != "42" ]; then echo "UID incorrect"; exit 1; fi
+            if [ -n "$IDLEMAIL_FLAGS" ]; then echo "Unexpected flags"; exit 1; fi
+            if [ "$IDLEMAIL_MESSAGE_ID" != "unit-test@example.org" ]; then echo "Message-ID incorrect"; exit 1; fi
+            if [ -z "$IDLEMAIL_HASH" ]; then echo "Hash missing"; exit 1; fi
+            INPUT=$(cat)
+            if ! grep -q '"subject":"Test Email"' <<< "$INPUT"; then echo "Subject missing in JSON"; exit 1; fi
+            if ! grep -q '"metadata":{"mailbox":"INBOX","uid":"42"}' <<< "$INPUT"; then echo "Metadata missing in JSON"; exit 1; fi
+            if ! grep -q '"text":\["text/plain"\]' <<< "$INPUT"; then echo "Text body missing in JSON"; exit 1; fi
+        "#,
+        );
+        assert!(run_execdst(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                input: Some(ExecInputFormat::Json),
+                ..Default::default()
+            },
+            mail,
+        ));
+    }
+
+    #[test_case("OK", "TEMPFAIL busy", "PERMFAIL spam" => 1)]
+    #[test_case("OK", "OK", "OK" => 0)]
+    #[test_case("OK", "INVALID", "OK" => 1)]
+    fn test_persistent(response0: &str, response1: &str, response2: &str) -> usize {
+        let mails = (0..3)
+            .map(|_| create_testmail("unit-test source 0".to_owned()))
+            .collect();
+        let (_dir, executable_path) = prepare_validation_script(&format!(
+            r#"#!/bin/bash
+            export LC_ALL=C
+            RESPONSES=("{}" "{}" "{}")
+            i=0
+            while read -r len; do
+                read -r -d '' -N "$len" mail
+                if ! grep -q "Subject: Test Email" <<< "$mail"; then echo "TEMPFAIL broken frame"; continue; fi
+                echo "${{RESPONSES[$i]}}"
+                i=$((i+1))
+            done
+        "#,
+            response0, response1, response2
+        ));
+        run_execdst_mails(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                persistent: Some(true),
+                timeout: Some(5),
+                ..Default::default()
+            },
+            mails,
+        )
+    }
+
+    #[test]
+    fn test_persistent_crash() {
+        let mails = (0..2)
+            .map(|_| create_testmail("unit-test source 0".to_owned()))
+            .collect();
+        let (_dir, executable_path) = prepare_validation_script(
+            r#"#!/bin/bash
+            read -r len
+            exit 1
+        "#,
+        );
+        let failed = run_execdst_mails(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                persistent: Some(true),
+                ..Default::default()
             },
+            mails,
         );
-        let (ra_send, ra_recv) = mpsc::channel();
+        assert_eq!(failed, 2);
+    }
+
+    #[test]
+    fn test_persistent_not_reading() {
+        // larger than the pipe's buffer, so writing it blocks
+        let mut data = b"Subject: Test Email\r\n\r\n".to_vec();
+        data.resize(data.len() + 1024 * 1024, b'x');
+        let mail = Mail::from_rfc822("unit-test source 0".to_owned(), data);
+        let (_dir, executable_path) = prepare_validation_script("#!/bin/bash\nsleep 30\n");
+        let started = Instant::now();
+        let failed = run_execdst_mails(
+            ExecDestinationConfig {
+                executable: executable_path.to_string_lossy().to_string(),
+                persistent: Some(true),
+                timeout: Some(1),
+                kill_timeout: Some(1),
+                ..Default::default()
+            },
+            vec![mail],
+        );
+        assert_eq!(failed, 1);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    /// Send the given mail through an ExecDestination, returns whether sending was successful
+    fn run_execdst(config: ExecDestinationConfig, mail: Mail) -> bool {
+        run_execdst_mails(config, vec![mail]) == 0
+    }
+
+    /// Send the given mails through an ExecDestination, returns the amount of failed sends
+    fn run_execdst_mails(config: ExecDestinationConfig, mails: Vec<Mail>) -> usize {
+        let mut execdst = ExecDestination::new("unit-test exec dst".to_owned(), &config);
+        let (ra_send, ra_recv) = async_mpsc::unbounded();
         {
-            let (dst_send, dst_recv) = mpsc::channel();
+            let (dst_send, dst_recv) = async_mpsc::unbounded();
             let dstchan = HubDestinationChannel {
                 name: "unit-test exec dst".to_owned(),
                 sender: ra_send,
                 recv: dst_recv,
+                queues: Default::default(),
+                in_flight: Default::default(),
             };
             execdst.start(dstchan);
-            dst_send.send(DestinationMessage::Mail { mail }).unwrap();
+            for mail in mails {
+                dst_send
+                    .try_send(DestinationMessage::Mail { mail })
+                    .unwrap();
+            }
         } // drop dst_send here, this signals the destination to exit
-        execdst.join();
-        let res = ra_recv.try_recv();
-        res.is_err()
+        task::block_on(execdst.join().unwrap());
+        std::iter::from_fn(|| ra_recv.try_recv().ok())
+            .filter(|msg| matches!(msg, HubMessage::SendingMailFailed { .. }))
+            .count()
     }
 }
@@ -0,0 +1,72 @@
+use crate::config::WebhookAuth;
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::{sync::Arc, time::Duration};
+
+/// Build an HTTP agent, that uses the system's TLS implementation
+pub fn build_agent(timeout: Duration) -> Result<ureq::Agent> {
+    let tls = native_tls::TlsConnector::new().context("Failed to initialize TLS")?;
+    Ok(ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(tls))
+        .timeout(timeout)
+        .build())
+}
+
+/// Value of the Authorization header for the given authentication
+pub fn authorization(auth: &WebhookAuth) -> String {
+    match auth {
+        WebhookAuth::Bearer { token } => format!("Bearer {}", token),
+        WebhookAuth::Basic { user, password } => {
+            format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("{}:{}", user, password))
+            )
+        }
+    }
+}
+
+/// Result of a single HTTP delivery attempt
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Delivered,
+    /// The receiver rejected the request permanently (4xx)
+    Rejected(String),
+    /// Delivery failed temporarily (5xx, timeouts, connection problems)
+    Failed(String),
+}
+impl From<Result<ureq::Response, ureq::Error>> for Outcome {
+    fn from(result: Result<ureq::Response, ureq::Error>) -> Self {
+        match result {
+            Ok(response) => classify_status(response.status(), response.status_text()),
+            Err(ureq::Error::Status(status, response)) => {
+                classify_status(status, response.status_text())
+            }
+            Err(ureq::Error::Transport(err)) => Outcome::Failed(err.to_string()),
+        }
+    }
+}
+
+fn classify_status(status: u16, status_text: &str) -> Outcome {
+    match status {
+        200..=299 => Outcome::Delivered,
+        // request timeout and rate limiting are worth another attempt
+        408 | 429 => Outcome::Failed(format!("{} {}", status, status_text)),
+        400..=499 => Outcome::Rejected(format!("{} {}", status, status_text)),
+        _ => Outcome::Failed(format!("{} {}", status, status_text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(200 => Outcome::Delivered)]
+    #[test_case(204 => Outcome::Delivered)]
+    #[test_case(400 => Outcome::Rejected("400 Status".to_owned()))]
+    #[test_case(429 => Outcome::Failed("429 Status".to_owned()))]
+    #[test_case(503 => Outcome::Failed("503 Status".to_owned()))]
+    fn test_classify_status(status: u16) -> Outcome {
+        classify_status(status, "Status")
+    }
+}
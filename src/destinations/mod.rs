@@ -1,9 +1,95 @@
-use crate::hub::{HubDestinationChannel, MailAgent};
+use crate::{
+    config::DestinationConfig,
+    hub::{HubChannel, HubDestinationChannel, HubMessage, Mail, MailAgent, QueueMonitor},
+};
+use async_std::future::timeout as await_timeout;
+use std::{collections::HashMap, time::Duration};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use ulid::Ulid;
 
+mod coprocess;
+mod envelope;
 pub mod exec;
+mod http;
+pub mod notify;
+mod process;
 pub mod smtp;
 pub mod testdst;
+pub mod webhook;
 
 pub trait MailDestination: MailAgent {
     fn start(&mut self, channel: HubDestinationChannel);
 }
+
+pub fn create(dstname: &str, config: &DestinationConfig) -> Box<dyn MailDestination> {
+    let dstname = dstname.to_owned();
+    match config {
+        DestinationConfig::Test(config) => Box::new(testdst::TestDestination::new(dstname, config)),
+        DestinationConfig::Smtp(config) => Box::new(smtp::SmtpDestination::new(dstname, config)),
+        DestinationConfig::Exec(config) => Box::new(exec::ExecDestination::new(dstname, config)),
+        DestinationConfig::Webhook(config) => {
+            Box::new(webhook::WebhookDestination::new(dstname, config))
+        }
+        DestinationConfig::Notify(config) => {
+            Box::new(notify::NotifyDestination::new(dstname, config))
+        }
+    }
+}
+
+/// Deliver a probe mail through a new instance of the destination, without retrying it.
+/// Returns why the delivery failed.
+pub async fn probe(
+    dstname: &str,
+    config: &DestinationConfig,
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut hubchannel = HubChannel::new(QueueMonitor::new(&[dstname.to_owned()], HashMap::new()));
+    let mut destination = create(dstname, config);
+    destination.start(hubchannel.get_destination_channel(dstname.to_owned()));
+    if hubchannel
+        .queue_mail_for_sending(dstname, probe_mail(dstname))
+        .is_err()
+    {
+        return Err("Destination does not take mails".to_owned());
+    }
+
+    let outcome = await_timeout(timeout, async {
+        loop {
+            match hubchannel.next().await {
+                HubMessage::SendingMailSucceeded { .. } => return Ok(()),
+                HubMessage::SendingMailFailed { reason, .. } => return Err(reason),
+                HubMessage::SendingMailRejected { reason, .. } => {
+                    return Err(format!("Mail was rejected: {}", reason))
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err(format!("No outcome within {}s", timeout.as_secs())));
+
+    hubchannel.shutdown_destinations();
+    if let Some(worker) = destination.join() {
+        worker.await;
+    }
+    outcome
+}
+
+fn probe_mail(dstname: &str) -> Mail {
+    let date = OffsetDateTime::now_utc()
+        .format(&Rfc2822)
+        .unwrap_or_default();
+    let data = format!(
+        "From: idlemail <idlemail@localhost>\r\n\
+         To: {dstname} <idlemail@localhost>\r\n\
+         Subject: Idlemail probe for destination {dstname}\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@idlemail>\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         This mail was sent by `idlemail test-destination` to check the destination {dstname}.\r\n",
+        id = Ulid::new(),
+    );
+    Mail::from_rfc822("probe".to_owned(), data.into_bytes())
+}
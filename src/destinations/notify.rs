@@ -0,0 +1,444 @@
+use crate::{
+    config::{NotifyDestinationConfig, NotifyTarget},
+    hub::{DestinationMessage, HubDestinationChannel, Mail, MailAgent},
+    logging::mail_log,
+};
+use anyhow::Context;
+use async_std::task;
+use log::{error, info, trace};
+use serde_json::json;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use super::{
+    envelope,
+    http::{self, Outcome},
+    MailDestination,
+};
+
+const DEFAULT_TITLE: &str = "New mail from {from}";
+const DEFAULT_MESSAGE: &str = "{subject}\n{snippet}";
+const AGGREGATED_TITLE: &str = "{count} new mails";
+const DEFAULT_SNIPPET_LENGTH: usize = 200;
+const DEFAULT_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Notification {
+    title: String,
+    message: String,
+}
+
+/// Replace all `{key}` placeholders in the template with their values.
+/// Unknown placeholders are kept as they are.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(key, _)| *key == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Whitespace-normalized beginning of the given text, with at most `max_len` characters
+fn snippet(text: &str, max_len: usize) -> String {
+    let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if normalized.chars().count() <= max_len {
+        normalized
+    } else {
+        let mut snippet: String = normalized.chars().take(max_len).collect();
+        snippet.push('…');
+        snippet
+    }
+}
+
+pub struct NotifyDestination {
+    name: String,
+    log_target: String,
+    config: NotifyDestinationConfig,
+    worker: Option<task::JoinHandle<()>>,
+}
+impl NotifyDestination {
+    pub fn new(name: String, config: &NotifyDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Notify[{}]", name),
+            name,
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    fn summarize(
+        config: &NotifyDestinationConfig,
+        dstname: &str,
+        mail: &Mail,
+    ) -> std::io::Result<Notification> {
+        let data = mail.data.bytes()?;
+        let envelope = envelope::parse_envelope(mail, &data, dstname, false);
+        let snippet = snippet(
+            envelope.text.first().map(|s| s.as_str()).unwrap_or(""),
+            config.snippet_length.unwrap_or(DEFAULT_SNIPPET_LENGTH),
+        );
+        let to = envelope.parsed.to.join(", ");
+        let values = [
+            ("source", mail.from_src.as_str()),
+            ("destination", dstname),
+            ("mailbox", mail.mailbox().unwrap_or("")),
+            ("from", envelope.parsed.from.as_deref().unwrap_or("")),
+            ("to", to.as_str()),
+            ("subject", envelope.parsed.subject.as_deref().unwrap_or("")),
+            (
+                "message_id",
+                envelope.parsed.message_id.as_deref().unwrap_or(""),
+            ),
+            ("snippet", snippet.as_str()),
+        ];
+        Ok(Notification {
+            title: render(config.title.as_deref().unwrap_or(DEFAULT_TITLE), &values),
+            message: render(
+                config.message.as_deref().unwrap_or(DEFAULT_MESSAGE),
+                &values,
+            ),
+        })
+    }
+
+    /// Combine the notifications of a burst of mails into a single one
+    fn aggregate(notifications: &[Notification]) -> Notification {
+        if notifications.len() == 1 {
+            return notifications[0].clone();
+        }
+        Notification {
+            title: render(
+                AGGREGATED_TITLE,
+                &[("count", &notifications.len().to_string())],
+            ),
+            message: notifications
+                .iter()
+                .map(|n| n.title.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    fn send(
+        config: &NotifyDestinationConfig,
+        agent: Option<&ureq::Agent>,
+        notification: &Notification,
+    ) -> Outcome {
+        match &config.target {
+            NotifyTarget::Http {
+                url,
+                topic,
+                priority,
+                headers,
+                auth,
+                ..
+            } => {
+                let Some(agent) = agent else {
+                    return Outcome::Failed("No HTTP agent available".to_owned());
+                };
+                // ntfy uses title/message/topic, Gotify title/message/priority and Apprise title/body
+                let mut payload = json!({
+                    "title": notification.title,
+                    "message": notification.message,
+                    "body": notification.message,
+                });
+                if let Some(topic) = topic {
+                    payload["topic"] = json!(topic);
+                }
+                if let Some(priority) = priority {
+                    payload["priority"] = json!(priority);
+                }
+                let mut request = agent.post(url).set("Content-Type", "application/json");
+                if let Some(auth) = auth {
+                    request = request.set("Authorization", &http::authorization(auth));
+                }
+                for (name, value) in headers.iter().flatten() {
+                    request = request.set(name, value);
+                }
+                match request.send_bytes(payload.to_string().as_bytes()).into() {
+                    Outcome::Delivered => Outcome::Delivered,
+                    Outcome::Rejected(reason) => {
+                        Outcome::Rejected(format!("Notification rejected: {}", reason))
+                    }
+                    Outcome::Failed(reason) => {
+                        Outcome::Failed(format!("Failed to send notification: {}", reason))
+                    }
+                }
+            }
+            NotifyTarget::File { path } => {
+                let written = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context("Failed to open notification file")
+                    .and_then(|mut file| {
+                        let line = format!("{}: {}", notification.title, notification.message);
+                        writeln!(file, "{}", line.replace('\n', " "))
+                            .context("Failed to write notification file")
+                    });
+                match written {
+                    Ok(()) => Outcome::Delivered,
+                    Err(err) => Outcome::Failed(format!("{:#}", err)),
+                }
+            }
+        }
+    }
+}
+impl MailAgent for NotifyDestination {
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
+    }
+}
+impl MailDestination for NotifyDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let name = self.name.clone();
+        let log_target = self.log_target.clone();
+        let config = Arc::new(self.config.clone());
+        self.worker = Some(task::spawn(async move {
+            let agent = match &config.target {
+                NotifyTarget::Http { timeout, .. } => {
+                    match http::build_agent(Duration::from_secs(timeout.unwrap_or(DEFAULT_TIMEOUT)))
+                    {
+                        Ok(agent) => Some(agent),
+                        Err(err) => {
+                            error!(target: &log_target, "{:#}", err);
+                            None
+                        }
+                    }
+                }
+                NotifyTarget::File { .. } => None,
+            };
+            let aggregate = Duration::from_secs(config.aggregate.unwrap_or(0));
+            let min_interval = Duration::from_secs(config.min_interval.unwrap_or(0));
+
+            // Mails are collected until the current aggregation window is over
+            let mut pending: Vec<(Mail, Notification)> = Vec::new();
+            let mut flush_at: Option<Instant> = None;
+            let mut last_sent: Option<Instant> = None;
+            loop {
+                let mut shutdown = false;
+                let msg = match flush_at {
+                    Some(flush_at) => {
+                        channel
+                            .next_timeout(flush_at.saturating_duration_since(Instant::now()))
+                            .await
+                    }
+                    None => channel
+                        .next()
+                        .await
+                        .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                match msg {
+                    Ok(DestinationMessage::Mail { mail }) => {
+                        let notification = match Self::summarize(&config, &name, &mail) {
+                            Ok(notification) => notification,
+                            Err(e) => {
+                                let reason = format!("Failed to read mail: {}", e);
+                                mail_log!(
+                                    Error,
+                                    &log_target,
+                                    mail.log_fields().destination(&name),
+                                    "{}",
+                                    reason
+                                );
+                                channel.notify_failed_send(mail, reason);
+                                continue;
+                            }
+                        };
+                        pending.push((mail, notification));
+                        if flush_at.is_none() {
+                            let mut window_end = Instant::now() + aggregate;
+                            // respect the rate limit
+                            if let Some(last_sent) = last_sent {
+                                window_end = window_end.max(last_sent + min_interval);
+                            }
+                            flush_at = Some(window_end);
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => shutdown = true,
+                }
+
+                let flush_due = flush_at.is_some_and(|t| t <= Instant::now());
+                if !pending.is_empty() && (flush_due || shutdown) {
+                    let notifications: Vec<_> = pending.iter().map(|(_, n)| n.clone()).collect();
+                    let notification = Self::aggregate(&notifications);
+                    // sending blocks, keep it off the executor
+                    let sent = {
+                        let (config, agent) = (config.clone(), agent.clone());
+                        task::spawn_blocking(move || {
+                            Self::send(&config, agent.as_ref(), &notification)
+                        })
+                        .await
+                    };
+                    match sent {
+                        Outcome::Delivered => {
+                            let count = pending.len();
+                            for (mail, _) in pending.drain(..) {
+                                mail_log!(
+                                    Info,
+                                    &log_target,
+                                    mail.log_fields().destination(&name),
+                                    "Sent notification for mail {} ({} mail(s) in total)",
+                                    mail.id,
+                                    count
+                                );
+                                channel.notify_successful_send(mail);
+                            }
+                        }
+                        Outcome::Rejected(reason) => {
+                            for (mail, _) in pending.drain(..) {
+                                mail_log!(
+                                    Warn,
+                                    &log_target,
+                                    mail.log_fields().destination(&name),
+                                    "{}, will not try again for mail {}",
+                                    reason,
+                                    mail.id
+                                );
+                                channel.notify_rejected_send(mail, reason.clone());
+                            }
+                        }
+                        Outcome::Failed(reason) => {
+                            for (mail, _) in pending.drain(..) {
+                                mail_log!(
+                                    Error,
+                                    &log_target,
+                                    mail.log_fields().destination(&name),
+                                    "{} (mail {})",
+                                    reason,
+                                    mail.id
+                                );
+                                channel.notify_failed_send(mail, reason.clone());
+                            }
+                        }
+                    }
+                    pending.clear();
+                    flush_at = None;
+                    last_sent = Some(Instant::now());
+                }
+                if shutdown {
+                    break;
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::HubMessage;
+    use async_std::channel as async_mpsc;
+    use std::fs;
+    use test_case::test_case;
+
+    #[test_case("{subject} from {from}" => "Hello from me")]
+    #[test_case("{unknown} {subject}" => "{unknown} Hello")]
+    #[test_case("{{subject}}" => "{Hello}")]
+    #[test_case("no placeholders" => "no placeholders")]
+    #[test_case("{subject" => "{subject")]
+    fn test_render(template: &str) -> String {
+        render(template, &[("subject", "Hello"), ("from", "me")])
+    }
+
+    #[test_case("short  text\nbody", 20 => "short text body")]
+    #[test_case("a long text body", 6 => "a long…")]
+    fn test_snippet(text: &str, max_len: usize) -> String {
+        snippet(text, max_len)
+    }
+
+    #[test]
+    fn test_aggregate_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notifications.log");
+        let config = NotifyDestinationConfig {
+            target: NotifyTarget::File {
+                path: path.to_string_lossy().to_string(),
+            },
+            title: Some("{subject}".to_owned()),
+            message: None,
+            snippet_length: None,
+            aggregate: Some(1),
+            min_interval: None,
+            retry: None,
+            on_permanent_failure: None,
+            queue_depth: None,
+        };
+        let mut dst = NotifyDestination::new("unit-test notify dst".to_owned(), &config);
+        let (hub_send, hub_recv) = async_mpsc::unbounded();
+        {
+            let (dst_send, dst_recv) = async_mpsc::unbounded();
+            dst.start(HubDestinationChannel {
+                name: "unit-test notify dst".to_owned(),
+                sender: hub_send,
+                recv: dst_recv,
+                queues: Default::default(),
+                in_flight: Default::default(),
+            });
+            for subject in ["First", "Second"] {
+                let mail = Mail::from_rfc822(
+                    "src".to_owned(),
+                    format!("From: a@example.org\r\nSubject: {}\r\n\r\nBody", subject).into_bytes(),
+                );
+                dst_send
+                    .try_send(DestinationMessage::Mail { mail })
+                    .unwrap();
+            }
+            // the first two mails are aggregated into one notification
+            for _ in 0..2 {
+                let msg = task::block_on(hub_recv.recv()).unwrap();
+                assert!(matches!(msg, HubMessage::SendingMailSucceeded { .. }));
+            }
+            let mail = Mail::from_rfc822(
+                "src".to_owned(),
+                b"From: b@example.org\r\nSubject: Third\r\n\r\nBody text".to_vec(),
+            );
+            dst_send
+                .try_send(DestinationMessage::Mail { mail })
+                .unwrap();
+        }
+        task::block_on(dst.join().unwrap());
+
+        assert!(!std::iter::from_fn(|| hub_recv.try_recv().ok())
+            .any(|msg| matches!(msg, HubMessage::SendingMailFailed { .. })));
+        let lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|s| s.to_owned())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "2 new mails: First Second".to_owned(),
+                "Third: Third Body text".to_owned()
+            ]
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::{ExecDestinationConfig, ExecResourceLimits};
+use std::{
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_KILL_TIMEOUT: u64 = 5;
+const DEFAULT_OUTPUT_LIMIT: usize = 64 * 1024;
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// How long the child's pipes are still read and written after it exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Output captured from one of the child's output streams.
+/// Everything beyond the configured limit is read (so the child never blocks on a full pipe),
+/// but discarded.
+#[derive(Default)]
+pub struct CapturedOutput {
+    pub data: Vec<u8>,
+    pub truncated: bool,
+}
+impl CapturedOutput {
+    pub fn as_string(&self) -> String {
+        let mut result = String::from_utf8_lossy(&self.data).into_owned();
+        if self.truncated {
+            result.push_str("\n[output truncated]");
+        }
+        result
+    }
+}
+
+pub enum ChildOutcome {
+    Exited(ExitStatus),
+    /// The child did not exit within the configured timeout and was terminated.
+    TimedOut,
+}
+
+pub struct ChildResult {
+    pub outcome: ChildOutcome,
+    /// Error while piping the mail into the child. The exit status stays authoritative,
+    /// since a child may legitimately exit without consuming its whole input.
+    pub input_error: Option<io::Error>,
+    pub stdout: CapturedOutput,
+    pub stderr: CapturedOutput,
+}
+
+/// Build the command for the configured executable, including the sandboxing
+/// options (working directory, uid/gid and resource limits).
+pub fn build_command(config: &ExecDestinationConfig) -> Command {
+    let mut command = Command::new(&config.executable);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(arguments) = config.arguments.as_ref() {
+        command.args(arguments);
+    }
+    if let Some(environment) = config.environment.as_ref() {
+        command.envs(environment);
+    }
+    if let Some(workdir) = config.workdir.as_ref() {
+        command.current_dir(workdir);
+    }
+    if let Some(gid) = config.gid {
+        command.gid(gid);
+    }
+    if let Some(uid) = config.uid {
+        command.uid(uid);
+    }
+    // Put the child into its own process group, so termination reaches everything it spawned
+    command.process_group(0);
+    if let Some(limits) = config.limits.clone() {
+        // SAFETY: apply_limits only calls the async-signal-safe setrlimit
+        unsafe {
+            command.pre_exec(move || apply_limits(&limits));
+        }
+    }
+    command
+}
+
+fn apply_limits(limits: &ExecResourceLimits) -> io::Result<()> {
+    let set_limit = |resource, value: Option<u64>| -> io::Result<()> {
+        if let Some(value) = value {
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: value as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    };
+    set_limit(libc::RLIMIT_CPU, limits.cpu)?;
+    set_limit(libc::RLIMIT_AS, limits.memory)?;
+    set_limit(libc::RLIMIT_FSIZE, limits.filesize)?;
+    set_limit(libc::RLIMIT_NOFILE, limits.files)?;
+    set_limit(libc::RLIMIT_NPROC, limits.processes)?;
+    Ok(())
+}
+
+/// Switch one of the child's pipes to non-blocking mode, so reading and writing it can be
+/// given up on (see `DRAIN_TIMEOUT`)
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Wait until the pipe is ready for the given events, or the poll interval passed
+fn poll(fd: RawFd, events: libc::c_short) {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    unsafe {
+        libc::poll(
+            &mut pollfd,
+            1,
+            WAIT_POLL_INTERVAL.as_millis() as libc::c_int,
+        );
+    }
+}
+
+fn drain<R: Read + AsRawFd>(stream: Option<R>, limit: usize, stop: &AtomicBool) -> CapturedOutput {
+    let mut output = CapturedOutput::default();
+    if let Some(mut stream) = stream {
+        if set_nonblocking(stream.as_raw_fd()).is_err() {
+            output.truncated = true;
+            return output;
+        }
+        let mut buffer = [0u8; 8192];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(cnt) => {
+                    let remaining = limit.saturating_sub(output.data.len());
+                    if cnt > remaining {
+                        output.truncated = true;
+                    }
+                    output.data.extend_from_slice(&buffer[..cnt.min(remaining)]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if stop.load(Ordering::Relaxed) {
+                        // the pipe is held open by a process that escaped the process group
+                        output.truncated = true;
+                        break;
+                    }
+                    poll(stream.as_raw_fd(), libc::POLLIN);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    output
+}
+
+/// Write all of `data` to the child's stdin, which has to be non-blocking (see
+/// `set_nonblocking`). Gives up with `TimedOut` once `stop` returns true while the child
+/// does not read.
+pub fn write_all(stdin: &mut ChildStdin, data: &[u8], stop: impl Fn() -> bool) -> io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match stdin.write(&data[written..]) {
+            Ok(cnt) => written += cnt,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if stop() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "The child did not read its input",
+                    ));
+                }
+                poll(stdin.as_raw_fd(), libc::POLLOUT);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn feed(stdin: Option<ChildStdin>, input: &[u8], stop: &AtomicBool) -> io::Result<()> {
+    // stdin is closed when dropped at the end of this function
+    let mut stdin = stdin.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "Failed to open stdin of child process",
+        )
+    })?;
+    set_nonblocking(stdin.as_raw_fd())?;
+    write_all(&mut stdin, input, || stop.load(Ordering::Relaxed))
+}
+
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // The child is the leader of its own process group (see build_command)
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
+
+/// Wait for the child to exit, terminating it when it exceeds the timeout.
+/// Termination first sends SIGTERM to the child's process group, and escalates
+/// to SIGKILL if the child did not exit after `kill_timeout`.
+pub fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    kill_timeout: Duration,
+) -> io::Result<ChildOutcome> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(ChildOutcome::Exited),
+    };
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(ChildOutcome::Exited(status));
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+
+    signal_group(child, libc::SIGTERM);
+    let deadline = Instant::now() + kill_timeout;
+    while Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            return Ok(ChildOutcome::TimedOut);
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+    signal_group(child, libc::SIGKILL);
+    child.wait()?;
+    Ok(ChildOutcome::TimedOut)
+}
+
+/// Spawn the configured executable, pipe `input` into its stdin and wait for it to exit.
+/// stdin is written and stdout/stderr are drained concurrently, so neither side
+/// can dead-lock on a full pipe.
+///
+/// Once the child exited (or timed out), the rest of its process group is killed, e.g.
+/// processes it started in the background. Processes that escaped the group may still hold
+/// the pipes open, so they are only drained for up to `DRAIN_TIMEOUT` afterwards.
+pub fn run(
+    command: &mut Command,
+    config: &ExecDestinationConfig,
+    input: &[u8],
+) -> io::Result<ChildResult> {
+    let mut child = command.spawn()?;
+    let limit = config.output_limit.unwrap_or(DEFAULT_OUTPUT_LIMIT);
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let stdout = scope.spawn(|| drain(stdout, limit, &stop));
+        let stderr = scope.spawn(|| drain(stderr, limit, &stop));
+        let stdin_writer = scope.spawn(|| feed(stdin, input, &stop));
+
+        let outcome = wait_timeout(
+            &mut child,
+            config.timeout.map(Duration::from_secs),
+            Duration::from_secs(config.kill_timeout.unwrap_or(DEFAULT_KILL_TIMEOUT)),
+        );
+        signal_group(&child, libc::SIGKILL);
+        if outcome.is_err() {
+            // the child is still running, or at least not reaped yet
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let finished =
+            || stdout.is_finished() && stderr.is_finished() && stdin_writer.is_finished();
+        while !finished() && Instant::now() < deadline {
+            thread::sleep(WAIT_POLL_INTERVAL);
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        let input_error = stdin_writer.join().expect("stdin writer panicked").err();
+        let stdout = stdout.join().expect("stdout reader panicked");
+        let stderr = stderr.join().expect("stderr reader panicked");
+        Ok(ChildResult {
+            outcome: outcome?,
+            input_error,
+            stdout,
+            stderr,
+        })
+    })
+}
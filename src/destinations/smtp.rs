@@ -1,19 +1,21 @@
 use crate::{
     config::{AuthMethod, SmtpDestinationConfig},
-    hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    hub::{metadata, DestinationMessage, HubDestinationChannel, MailAgent},
+    logging::mail_log,
 };
+use async_std::task;
 use lettre::{
     address::Envelope, transport::smtp::authentication as auth, Address, SmtpTransport, Transport,
 };
-use log::{error, info, trace, warn};
-use std::thread;
+use log::{error, info, trace};
+use std::io;
 
 use super::MailDestination;
 
 pub struct SmtpDestination {
     log_target: String,
     config: SmtpDestinationConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl SmtpDestination {
     pub fn new(name: String, config: &SmtpDestinationConfig) -> Self {
@@ -25,12 +27,8 @@ impl SmtpDestination {
     }
 }
 impl MailAgent for SmtpDestination {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailDestination for SmtpDestination {
@@ -50,7 +48,7 @@ impl MailDestination for SmtpDestination {
         trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
         let config = self.config.clone();
 
-        self.worker = Some(thread::spawn(move || {
+        self.worker = Some(task::spawn(async move {
             let mut connection_builder = match config.encryption {
                 crate::config::Encryption::None => SmtpTransport::builder_dangerous(&config.server),
                 crate::config::Encryption::Ssl => {
@@ -83,17 +81,74 @@ impl MailDestination for SmtpDestination {
 
             let mailer = connection_builder.build();
 
-            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next().await {
+                // bounces go to the postmaster, instead of the configured recipient
+                let recipient = match mail.metadata.get(metadata::RECIPIENT) {
+                    Some(address) => match address.parse::<Address>() {
+                        Ok(address) => address,
+                        Err(err) => {
+                            let reason = format!("Invalid recipient {}: {}", address, err);
+                            channel.notify_rejected_send(mail, reason);
+                            continue;
+                        }
+                    },
+                    None => recipient.clone(),
+                };
                 // Send raw mail using constructed envelope
-                let evenlope = Envelope::new(None, vec![recipient.clone()]).unwrap();
-                match mailer.send_raw(&evenlope, &mail.data) {
-                    Ok(_) => info!(target: &log_target, "Successfully sent mail"),
+                let evenlope = Envelope::new(None, vec![recipient]).unwrap();
+                // lettre's transport is blocking, keep it off the executor
+                let sent = {
+                    let mailer = mailer.clone();
+                    let data = mail.data.clone();
+                    task::spawn_blocking(move || {
+                        let data = data.bytes()?;
+                        Ok::<_, io::Error>(mailer.send_raw(&evenlope, &data))
+                    })
+                    .await
+                };
+                let sent = match sent {
+                    Ok(sent) => sent,
+                    Err(err) => {
+                        mail_log!(
+                            Error,
+                            &log_target,
+                            mail.log_fields().destination(&channel.name),
+                            "Failed to read mail:\n{}",
+                            err
+                        );
+                        channel.notify_failed_send(mail, format!("Failed to read mail: {}", err));
+                        continue;
+                    }
+                };
+                match sent {
+                    Ok(_) => {
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&channel.name),
+                            "Successfully sent mail"
+                        );
+                        channel.notify_successful_send(mail);
+                    }
                     Err(err) => {
                         if err.is_permanent() {
-                            warn!(target: &log_target, "The destination server does not accept this email, will not try again:\n{}", err);
+                            mail_log!(
+                                Warn,
+                                &log_target,
+                                mail.log_fields().destination(&channel.name),
+                                "The destination server does not accept this email, will not try again:\n{}",
+                                err
+                            );
+                            channel.notify_rejected_send(mail, err.to_string());
                         } else {
-                            error!(target: &log_target, "Error while sending mail:\n{}", err);
-                            channel.notify_failed_send(mail);
+                            mail_log!(
+                                Error,
+                                &log_target,
+                                mail.log_fields().destination(&channel.name),
+                                "Error while sending mail:\n{}",
+                                err
+                            );
+                            channel.notify_failed_send(mail, err.to_string());
                         }
                     }
                 }
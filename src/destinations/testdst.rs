@@ -1,33 +1,37 @@
 use crate::{
     config::TestDestinationConfig,
     hub::{DestinationMessage, HubDestinationChannel, MailAgent},
+    logging::mail_log,
 };
+use async_std::task;
 use log::{info, trace};
-use std::thread;
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
 
 use super::MailDestination;
 
 pub struct TestDestination {
     log_target: String,
     config: TestDestinationConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    /// Shared with the restarted workers, so the destination recovers after the crashes
+    panics_remaining: Arc<AtomicU16>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl TestDestination {
     pub fn new(name: String, config: &TestDestinationConfig) -> Self {
         Self {
             log_target: format!("TestDst[{}]", name),
             config: config.clone(),
+            panics_remaining: Arc::new(AtomicU16::new(config.panic_n_first.unwrap_or(0))),
             worker: None,
         }
     }
 }
 impl MailAgent for TestDestination {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailDestination for TestDestination {
@@ -37,15 +41,33 @@ impl MailDestination for TestDestination {
 
         let log_target = self.log_target.clone();
         let config = self.config.clone();
-        self.worker = Some(thread::spawn(move || {
+        let panics_remaining = self.panics_remaining.clone();
+        self.worker = Some(task::spawn(async move {
             let mut fails_remaining = config.fail_n_first;
-            while let Ok(DestinationMessage::Mail { mail }) = channel.next() {
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next().await {
+                let panic = panics_remaining
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if panic {
+                    panic!("Simulated crash while handling mail {}", mail.id);
+                }
                 if fails_remaining > 0 {
-                    info!(target: &log_target, "Got Mail: Simulating send failure.");
+                    mail_log!(
+                        Info,
+                        &log_target,
+                        mail.log_fields().destination(&channel.name),
+                        "Got Mail: Simulating send failure."
+                    );
                     fails_remaining -= 1;
-                    channel.notify_failed_send(mail);
+                    channel.notify_failed_send(mail, "Simulated send failure".to_owned());
                 } else {
-                    info!(target: &log_target, "Got Mail: Simulating success");
+                    mail_log!(
+                        Info,
+                        &log_target,
+                        mail.log_fields().destination(&channel.name),
+                        "Got Mail: Simulating success"
+                    );
+                    channel.notify_successful_send(mail);
                 }
             }
             info!(target: &log_target, "Stopping");
@@ -0,0 +1,258 @@
+use crate::{
+    config::{WebhookDestinationConfig, WebhookFormat},
+    hub::{DestinationMessage, HubDestinationChannel, Mail, MailAgent},
+    logging::mail_log,
+};
+use async_std::task;
+use hmac::{Hmac, Mac};
+use log::{error, info, trace};
+use sha2::Sha256;
+use std::{borrow::Cow, time::Duration};
+
+use super::{
+    envelope,
+    http::{self, Outcome},
+    MailDestination,
+};
+
+const DEFAULT_TIMEOUT: u64 = 30;
+const SIGNATURE_HEADER: &str = "X-Idlemail-Signature";
+
+/// Hex encoded HMAC-SHA256 of the given payload
+fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub struct WebhookDestination {
+    name: String,
+    log_target: String,
+    config: WebhookDestinationConfig,
+    worker: Option<task::JoinHandle<()>>,
+}
+impl WebhookDestination {
+    pub fn new(name: String, config: &WebhookDestinationConfig) -> Self {
+        Self {
+            log_target: format!("Webhook[{}]", name),
+            name,
+            config: config.clone(),
+            worker: None,
+        }
+    }
+
+    fn deliver(
+        agent: &ureq::Agent,
+        config: &WebhookDestinationConfig,
+        dstname: &str,
+        mail: &Mail,
+    ) -> Outcome {
+        let data = match mail.data.bytes() {
+            Ok(data) => data,
+            Err(e) => return Outcome::Failed(format!("Failed to read mail: {}", e)),
+        };
+        let (content_type, body) = match config.format.unwrap_or_default() {
+            WebhookFormat::Rfc822 => ("message/rfc822", data),
+            WebhookFormat::Json => {
+                let envelope = envelope::parse_envelope(mail, &data, dstname, true);
+                match serde_json::to_vec(&envelope) {
+                    Ok(body) => ("application/json", Cow::Owned(body)),
+                    Err(e) => return Outcome::Failed(format!("Failed to serialize mail: {}", e)),
+                }
+            }
+        };
+
+        let mut request = agent
+            .post(&config.url)
+            .set("Content-Type", content_type)
+            .set("X-Idlemail-Id", &mail.id)
+            .set("X-Idlemail-Source", &mail.from_src)
+            .set("X-Idlemail-Destination", dstname);
+        if let Some(auth) = &config.auth {
+            request = request.set("Authorization", &http::authorization(auth));
+        }
+        if let Some(secret) = &config.hmac_secret {
+            request = request.set(SIGNATURE_HEADER, &format!("sha256={}", sign(secret, &body)));
+        }
+        // custom headers are applied last, so they can override everything above
+        for (name, value) in config.headers.iter().flatten() {
+            request = request.set(name, value);
+        }
+
+        request.send_bytes(&body).into()
+    }
+}
+impl MailAgent for WebhookDestination {
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
+    }
+}
+impl MailDestination for WebhookDestination {
+    fn start(&mut self, channel: HubDestinationChannel) {
+        info!(target: &self.log_target, "Starting");
+        trace!(target: &self.log_target, "Using Configuration:\n{:?}", self.config);
+
+        let name = self.name.clone();
+        let log_target = self.log_target.clone();
+        let config = self.config.clone();
+        self.worker = Some(task::spawn(async move {
+            let agent = http::build_agent(Duration::from_secs(
+                config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            ));
+            while let Ok(DestinationMessage::Mail { mail }) = channel.next().await {
+                let agent = match &agent {
+                    Ok(agent) => agent.clone(),
+                    Err(err) => {
+                        error!(target: &log_target, "{:#}", err);
+                        channel.notify_failed_send(mail, format!("{:#}", err));
+                        continue;
+                    }
+                };
+                // ureq is blocking, keep it off the executor
+                let outcome = {
+                    let (config, name, mail) = (config.clone(), name.clone(), mail.clone());
+                    task::spawn_blocking(move || Self::deliver(&agent, &config, &name, &mail)).await
+                };
+                match outcome {
+                    Outcome::Delivered => {
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&channel.name),
+                            "Successfully sent mail"
+                        );
+                        channel.notify_successful_send(mail);
+                    }
+                    Outcome::Rejected(reason) => {
+                        mail_log!(
+                            Warn,
+                            &log_target,
+                            mail.log_fields().destination(&channel.name),
+                            "The webhook does not accept this email, will not try again: {}",
+                            reason
+                        );
+                        channel.notify_rejected_send(mail, reason);
+                    }
+                    Outcome::Failed(reason) => {
+                        mail_log!(
+                            Error,
+                            &log_target,
+                            mail.log_fields().destination(&channel.name),
+                            "Error while sending mail: {}",
+                            reason
+                        );
+                        channel.notify_failed_send(mail, reason);
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebhookAuth;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    /// Received request headers and body
+    type Request = (Vec<String>, Vec<u8>);
+
+    /// Serve a single request with the given status
+    fn serve_once(status: u16) -> (String, thread::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_owned());
+            }
+            let length = headers
+                .iter()
+                .find_map(|h| h.strip_prefix("Content-Length: "))
+                .map(|l| l.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                &stream,
+                "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            (headers, body)
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_deliver() {
+        let (url, server) = serve_once(201);
+        let config = WebhookDestinationConfig {
+            url,
+            auth: Some(WebhookAuth::Bearer {
+                token: "secret-token".to_owned(),
+            }),
+            hmac_secret: Some("hmac-secret".to_owned()),
+            ..Default::default()
+        };
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec());
+        let agent = http::build_agent(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            WebhookDestination::deliver(&agent, &config, "dst", &mail),
+            Outcome::Delivered
+        );
+
+        let (headers, body) = server.join().unwrap();
+        assert_eq!(body, mail.data.bytes().unwrap().as_ref());
+        assert!(headers.contains(&"Content-Type: message/rfc822".to_owned()));
+        assert!(headers.contains(&"Authorization: Bearer secret-token".to_owned()));
+        assert!(headers.contains(&format!(
+            "{}: sha256={}",
+            SIGNATURE_HEADER,
+            sign("hmac-secret", &mail.data.bytes().unwrap())
+        )));
+    }
+
+    #[test]
+    fn test_deliver_rejected() {
+        let (url, server) = serve_once(422);
+        let config = WebhookDestinationConfig {
+            url,
+            format: Some(WebhookFormat::Json),
+            ..Default::default()
+        };
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec());
+        let agent = http::build_agent(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            WebhookDestination::deliver(&agent, &config, "dst", &mail),
+            Outcome::Rejected("422 Status".to_owned())
+        );
+        let (_, body) = server.join().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["subject"], "Test");
+        assert_eq!(json["text"][0], "Body");
+    }
+}
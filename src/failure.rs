@@ -0,0 +1,177 @@
+//! Handling of mails that a destination rejected permanently
+use crate::{
+    hub::{metadata, Mail},
+    storage::sync_dir,
+};
+use anyhow::{Context, Result};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+const DEFAULT_BOUNCE_FROM: &str = "MAILER-DAEMON";
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Name that is unique for this process, following the Maildir naming convention
+fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.idlemail",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Store the mail into the Maildir at the given path, which is created if necessary.
+/// Returns the path of the stored mail.
+pub fn quarantine(maildir: &str, mail: &Mail) -> Result<PathBuf> {
+    let maildir = Path::new(maildir);
+    for folder in ["tmp", "new", "cur"] {
+        fs::create_dir_all(maildir.join(folder))
+            .with_context(|| format!("Failed to create Maildir: {}", maildir.display()))?;
+    }
+    // Maildir readers only look at new/, so the mail is written to tmp/ first
+    let name = unique_name();
+    let tmp_path = maildir.join("tmp").join(&name);
+    let path = maildir.join("new").join(&name);
+    let mut file = fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    mail.data
+        .reader()
+        .and_then(|mut data| io::copy(&mut data, &mut file))
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("Failed to rename {}", tmp_path.display()))?;
+    sync_dir(&path)?;
+    Ok(path)
+}
+
+/// Generate a delivery status notification (RFC 3464), containing the reason of the
+/// failure and the original mail. Smtp destinations deliver it to the postmaster.
+pub fn bounce(
+    from: Option<&str>,
+    postmaster: &str,
+    dstname: &str,
+    mail: &Mail,
+    reason: &str,
+) -> Result<Mail> {
+    let original = mail.data.bytes().context("Failed to read mail")?;
+    let unique = unique_name();
+    let boundary = format!("idlemail-bounce-{}", unique);
+    let date = OffsetDateTime::now_utc()
+        .format(&Rfc2822)
+        .unwrap_or_default();
+    // header fields of the status report can not span multiple lines
+    let diagnostic = reason.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut data = format!(
+        "From: {from}\r\n\
+         To: {postmaster}\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{unique}@idlemail>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         The mail {id} (SHA-256: {hash}) from source {source} was permanently rejected by destination {dstname}:\r\n\
+         \r\n\
+         {reason}\r\n\
+         \r\n\
+         The original mail is attached.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: x-idlemail; idlemail\r\n\
+         \r\n\
+         Final-Recipient: x-idlemail; {dstname}\r\n\
+         Action: failed\r\n\
+         Status: 5.0.0\r\n\
+         Diagnostic-Code: x-idlemail; {diagnostic}\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/rfc822\r\n\
+         \r\n",
+        from = from.unwrap_or(DEFAULT_BOUNCE_FROM),
+        id = mail.id,
+        hash = mail.hash,
+        source = mail.from_src,
+        reason = reason.lines().collect::<Vec<_>>().join("\r\n"),
+    )
+    .into_bytes();
+    data.extend_from_slice(&original);
+    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let mut bounce = Mail::from_rfc822(mail.from_src.clone(), data);
+    if let Some(mailbox) = mail.mailbox() {
+        bounce = bounce.with_mailbox(mailbox.to_owned());
+    }
+    bounce
+        .metadata
+        .insert(metadata::RECIPIENT.to_owned(), postmaster.to_owned());
+    Ok(bounce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    #[test]
+    fn test_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = dir.path().join("quarantine");
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec());
+        let path = quarantine(maildir.to_str().unwrap(), &mail).unwrap();
+        assert!(path.starts_with(maildir.join("new")));
+        assert_eq!(fs::read(path).unwrap(), mail.data.bytes().unwrap().as_ref());
+        assert!(maildir.join("cur").is_dir());
+        assert!(maildir.join("tmp").is_dir());
+    }
+
+    #[test]
+    fn test_bounce() {
+        let mail = Mail::from_rfc822(
+            "src".to_owned(),
+            b"Subject: Original\r\n\r\nOriginal body".to_vec(),
+        );
+        let bounce = bounce(
+            None,
+            "postmaster@example.org",
+            "dst",
+            &mail,
+            "550 mailbox\nunavailable",
+        )
+        .unwrap();
+        let data = bounce.data.bytes().unwrap();
+        let message = MessageParser::default().parse(data.as_ref()).unwrap();
+        assert_eq!(
+            message.subject(),
+            Some("Undelivered Mail Returned to Sender")
+        );
+        assert!(message
+            .body_text(0)
+            .unwrap()
+            .contains("550 mailbox\r\nunavailable"));
+        assert_eq!(
+            bounce.metadata.get(metadata::RECIPIENT).map(|r| r.as_str()),
+            Some("postmaster@example.org")
+        );
+        let text = String::from_utf8_lossy(&data);
+        assert!(text.contains("Diagnostic-Code: x-idlemail; 550 mailbox unavailable\r\n"));
+        assert!(text.contains("Original body"));
+    }
+}
@@ -0,0 +1,856 @@
+use crate::config::{
+    AuthMethod, ConfigContainer, DestinationConfig, Encryption, ExecDestinationConfig,
+    ImapIdleSourceConfig, MemoryRetryAgentConfig, RetryAgentConfig, SmtpDestinationConfig,
+    SourceConfig,
+};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Seconds after which the IDLE of imported sources is renewed
+const RENEWINTERVAL: u64 = 1500;
+/// Seconds before mails that could not be delivered are retried
+const RETRY_DELAY: u64 = 300;
+
+/// Words fetchmail ignores, to make the configuration read like english
+const NOISE: &[&str] = &["and", "with", "has", "wants", "options", "here", "there"];
+const STATEMENTS: &[&str] = &["poll", "skip", "server", "defaults", "set"];
+/// Options without arguments, which can be negated with `no`
+const FLAGS: &[&str] = &[
+    "keep",
+    "fetchall",
+    "flush",
+    "limitflush",
+    "rewrite",
+    "stripcr",
+    "forcecr",
+    "pass8bits",
+    "mimedecode",
+    "dropstatus",
+    "dropdelivered",
+    "idle",
+    "ssl",
+    "sslcertck",
+    "uidl",
+    "dns",
+    "checkalias",
+    "tracepolls",
+    "lmtp",
+    "showdots",
+];
+/// Options with exactly one argument
+const SINGLE: &[&str] = &[
+    "user",
+    "pass",
+    "proto",
+    "port",
+    "auth",
+    "timeout",
+    "interval",
+    "via",
+    "qvirtual",
+    "interface",
+    "monitor",
+    "plugin",
+    "plugout",
+    "principal",
+    "esmtpname",
+    "esmtppassword",
+    "smtpaddress",
+    "smtpname",
+    "mda",
+    "bsmtp",
+    "preconnect",
+    "postconnect",
+    "limit",
+    "warnings",
+    "batchlimit",
+    "fetchlimit",
+    "fetchsizelimit",
+    "fastuidl",
+    "expunge",
+    "properties",
+    "sslcert",
+    "sslkey",
+    "sslproto",
+    "sslcertfile",
+    "sslcertpath",
+    "sslfingerprint",
+    "sslcommonname",
+];
+/// Options with a list of arguments
+const LISTS: &[&str] = &[
+    "is",
+    "aka",
+    "localdomains",
+    "folder",
+    "smtphost",
+    "fetchdomains",
+    "antispam",
+    "envelope",
+];
+/// Options that are translated into the idlemail configuration
+const TRANSLATED: &[&str] = &[
+    "user",
+    "pass",
+    "proto",
+    "port",
+    "auth",
+    "via",
+    "is",
+    "keep",
+    "folder",
+    "ssl",
+    "smtphost",
+    "smtpaddress",
+    "mda",
+];
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+    line: usize,
+}
+impl Token {
+    fn is_keyword(&self) -> bool {
+        !self.quoted
+            && (STATEMENTS.contains(&self.text.as_str())
+                || option_name(&self.text).is_some()
+                || negated_flag(&self.text).is_some())
+    }
+}
+
+#[derive(Debug)]
+struct Opt {
+    name: String,
+    negated: bool,
+    args: Vec<String>,
+    line: usize,
+}
+
+/// A poll entry, or the defaults for all of them
+#[derive(Debug, Default)]
+struct Entry {
+    server: String,
+    skip: bool,
+    line: usize,
+    /// Options before the first user, which apply to all users
+    options: Vec<Opt>,
+    users: Vec<Vec<Opt>>,
+}
+
+/// The idlemail configuration converted from a fetchmailrc
+pub struct Import {
+    pub config: ConfigContainer,
+    /// Everything that could not be converted
+    pub unsupported: Vec<String>,
+}
+
+/// Convert the contents of a fetchmailrc into an idlemail configuration
+pub fn import(text: &str) -> Result<Import, String> {
+    let (defaults, settings, entries) = parse(tokenize(text)?)?;
+    let mut import = Importer::default();
+    for setting in &settings {
+        import.report(
+            setting,
+            &match setting.negated {
+                true => format!("set no {}", setting.name),
+                false => format!("set {}", setting.name),
+            },
+            match setting.name.as_str() {
+                "daemon" => "idlemail runs as a daemon and waits for new mails with IMAP IDLE",
+                _ => "has no equivalent",
+            },
+        );
+    }
+    for entry in &entries {
+        if entry.skip {
+            import.unsupported.push(format!(
+                "line {}: skip {}: skipped servers are not imported",
+                entry.line, entry.server
+            ));
+            continue;
+        }
+        if entry.users.is_empty() {
+            import.unsupported.push(format!(
+                "line {}: poll {}: no user is configured",
+                entry.line, entry.server
+            ));
+        }
+        for user in &entry.users {
+            let options = Options(
+                defaults
+                    .iter()
+                    .flat_map(|d| &d.options)
+                    .chain(&entry.options)
+                    .chain(user)
+                    .collect(),
+            );
+            import.add(entry, &options);
+        }
+    }
+    Ok(Import {
+        config: import.config,
+        unsupported: import.unsupported,
+    })
+}
+
+/// Serialize the imported configuration, without the unset optional parameters
+pub fn to_json(config: &ConfigContainer) -> String {
+    let mut value = serde_json::to_value(config).expect("Failed to serialize configuration");
+    if let Value::Object(sections) = &mut value {
+        sections.retain(|_, section| !section.is_null());
+        // strings of sources and destinations are interpolated when the configuration is loaded
+        for section in ["sources", "destinations"] {
+            if let Some(entries) = sections.get_mut(section) {
+                escape_variables(entries);
+            }
+        }
+    }
+    strip_nulls(&mut value);
+    serde_json::to_string_pretty(&value).expect("Failed to serialize configuration")
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|_, field| !field.is_null());
+            fields.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+fn escape_variables(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = text.replace("${", "$${"),
+        Value::Object(fields) => fields.values_mut().for_each(escape_variables),
+        Value::Array(values) => values.iter_mut().for_each(escape_variables),
+        _ => {}
+    }
+}
+
+/// Canonical name of the option, e.g. `protocol` for `proto`
+fn option_name(word: &str) -> Option<&'static str> {
+    let canonical = match word {
+        "username" => "user",
+        "password" => "pass",
+        "protocol" => "proto",
+        "service" => "port",
+        "authenticate" => "auth",
+        "to" => "is",
+        _ => word,
+    };
+    FLAGS
+        .iter()
+        .chain(SINGLE)
+        .chain(LISTS)
+        .find(|name| **name == canonical)
+        .copied()
+}
+
+/// The flag of words like `nokeep`
+fn negated_flag(word: &str) -> Option<&'static str> {
+    word.strip_prefix("no")
+        .and_then(|flag| FLAGS.iter().find(|name| **name == flag))
+        .copied()
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '"' | '\'' => {
+                let start = line;
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(format!("line {}: Unterminated string", start)),
+                        Some(quote) if quote == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some('r') => value.push('\r'),
+                            Some(escaped) => value.push(escaped),
+                            None => return Err(format!("line {}: Unterminated string", start)),
+                        },
+                        Some(other) => {
+                            if other == '\n' {
+                                line += 1;
+                            }
+                            value.push(other);
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    text: value,
+                    quoted: true,
+                    line: start,
+                });
+            }
+            c if c.is_whitespace() || matches!(c, ',' | ':' | ';') => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| {
+                    !c.is_whitespace() && !matches!(c, ',' | ':' | ';' | '#' | '"' | '\'')
+                }) {
+                    word.push(c);
+                }
+                if !NOISE.contains(&word.as_str()) {
+                    tokens.push(Token {
+                        text: word,
+                        quoted: false,
+                        line,
+                    });
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+type Parsed = (Vec<Entry>, Vec<Opt>, Vec<Entry>);
+
+/// Split the tokens into the defaults, the `set` statements and the poll entries
+fn parse(tokens: Vec<Token>) -> Result<Parsed, String> {
+    let mut defaults = Vec::new();
+    let mut settings = Vec::new();
+    let mut entries = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match (token.quoted, token.text.as_str()) {
+            (false, "set") => {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| format!("line {}: Missing setting after set", token.line))?;
+                let (name, negated) = match name.text.strip_prefix("no") {
+                    Some("") => match tokens.next() {
+                        Some(name) => (name.text, true),
+                        None => return Err(format!("line {}: Missing setting", token.line)),
+                    },
+                    _ => (name.text, false),
+                };
+                let mut args = Vec::new();
+                while let Some(arg) = tokens.next_if(|t| !t.is_keyword()) {
+                    args.push(arg.text);
+                }
+                settings.push(Opt {
+                    name,
+                    negated,
+                    args,
+                    line: token.line,
+                });
+            }
+            (false, "defaults") => {
+                let mut entry = Entry {
+                    line: token.line,
+                    ..Default::default()
+                };
+                parse_options(&mut tokens, &mut entry)?;
+                defaults.push(entry);
+            }
+            (false, "poll" | "server" | "skip") => {
+                let server = tokens
+                    .next_if(|t| !t.is_keyword())
+                    .ok_or_else(|| format!("line {}: Missing server name", token.line))?;
+                let mut entry = Entry {
+                    server: server.text,
+                    skip: token.text == "skip",
+                    line: token.line,
+                    ..Default::default()
+                };
+                parse_options(&mut tokens, &mut entry)?;
+                entries.push(entry);
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: Expected poll, skip, defaults or set, found: {}",
+                    token.line, token.text
+                ))
+            }
+        }
+    }
+    Ok((defaults, settings, entries))
+}
+
+/// Parse the options of an entry, up to the next statement
+fn parse_options(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+    entry: &mut Entry,
+) -> Result<(), String> {
+    while let Some(token) = tokens.next_if(|t| t.quoted || !STATEMENTS.contains(&t.text.as_str())) {
+        let mut negated = false;
+        let mut word = token.text.to_lowercase();
+        if word == "no" && !token.quoted {
+            negated = true;
+            word = match tokens.next() {
+                Some(next) => next.text.to_lowercase(),
+                None => return Err(format!("line {}: Missing option after no", token.line)),
+            };
+        }
+        let name = match (token.quoted, option_name(&word), negated_flag(&word)) {
+            (false, Some(name), _) => name,
+            (false, None, Some(flag)) => {
+                negated = true;
+                flag
+            }
+            _ => return Err(format!("line {}: Unknown option: {}", token.line, word)),
+        };
+        if negated && !FLAGS.contains(&name) {
+            return Err(format!("line {}: {} can not be negated", token.line, name));
+        }
+        let mut args = Vec::new();
+        if SINGLE.contains(&name) {
+            let arg = tokens
+                .next()
+                .ok_or_else(|| format!("line {}: Missing argument of {}", token.line, name))?;
+            args.push(arg.text);
+        } else if LISTS.contains(&name) {
+            while let Some(arg) = tokens.next_if(|t| !t.is_keyword()) {
+                args.push(arg.text);
+            }
+        }
+        let option = Opt {
+            name: name.to_owned(),
+            negated,
+            args,
+            line: token.line,
+        };
+        match name {
+            "user" => entry.users.push(vec![option]),
+            _ => match entry.users.last_mut() {
+                Some(user) => user.push(option),
+                None => entry.options.push(option),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Options of a single user, including the ones of its poll entry and the defaults
+struct Options<'a>(Vec<&'a Opt>);
+impl Options<'_> {
+    /// The option given last
+    fn get(&self, name: &str) -> Option<&Opt> {
+        self.0.iter().rev().find(|o| o.name == name).copied()
+    }
+    fn arg(&self, name: &str) -> Option<&str> {
+        self.get(name)
+            .and_then(|o| o.args.first())
+            .map(String::as_str)
+    }
+    fn flag(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|o| !o.negated)
+    }
+}
+
+#[derive(Default)]
+struct Importer {
+    config: ConfigContainer,
+    unsupported: Vec<String>,
+    /// Lines and options that were reported already, when they apply to several users
+    reported: HashSet<(usize, String)>,
+}
+impl Importer {
+    fn report(&mut self, option: &Opt, name: &str, message: &str) {
+        if self.reported.insert((option.line, name.to_owned())) {
+            self.unsupported
+                .push(format!("line {}: {}: {}", option.line, name, message));
+        }
+    }
+
+    fn add(&mut self, entry: &Entry, options: &Options) {
+        for option in &options.0 {
+            if TRANSLATED.contains(&option.name.as_str()) {
+                continue;
+            }
+            let message = match (option.name.as_str(), option.negated) {
+                // what idlemail always does
+                ("sslcertck" | "idle", false) => continue,
+                ("sslcertck", true) => "idlemail always verifies the server certificate",
+                ("idle", true) => "idlemail always waits for new mails with IMAP IDLE",
+                ("fetchall", _) => "idlemail only fetches unseen mails",
+                ("flush" | "limitflush", _) => "idlemail does not delete seen mails",
+                _ => "has no equivalent",
+            };
+            let name = match option.negated {
+                true => format!("no {}", option.name),
+                false => option.name.clone(),
+            };
+            self.report(option, &name, message);
+        }
+        let user = options
+            .get("user")
+            .expect("every user starts with the user option");
+        let remote = user.args[0].clone();
+
+        let protocol = options.arg("proto").unwrap_or("auto").to_lowercase();
+        if protocol != "imap" && protocol != "auto" {
+            let option = options.get("proto").expect("protocol is set");
+            self.report(
+                option,
+                "proto",
+                &format!("{} of {} is not supported, only IMAP", protocol, remote),
+            );
+            return;
+        }
+        let password = match options.arg("pass") {
+            Some(password) => password.to_owned(),
+            None => {
+                self.report(
+                    user,
+                    "pass",
+                    &format!(
+                        "{} has no password, which fetchmail would ask for or read from ~/.netrc",
+                        remote
+                    ),
+                );
+                String::new()
+            }
+        };
+        if let Some(auth) = options.get("auth") {
+            if !matches!(auth.args[0].as_str(), "password" | "any") {
+                self.report(
+                    auth,
+                    "auth",
+                    &format!(
+                        "{} is not supported, idlemail logs in with the password",
+                        auth.args[0]
+                    ),
+                );
+            }
+        }
+        let port = match (options.flag("ssl"), options.arg("port")) {
+            (true, Some(port)) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => {
+                    let option = options.get("port").expect("port is set");
+                    self.report(option, "port", &format!("{} is not a port number", port));
+                    993
+                }
+            },
+            (true, None) => 993,
+            (false, port) => {
+                let option = options
+                    .get("ssl")
+                    .or_else(|| options.get("port"))
+                    .unwrap_or(user);
+                self.report(
+                    option,
+                    "ssl",
+                    &format!(
+                        "idlemail always connects with SSL, using port 993 instead of {}",
+                        port.unwrap_or("the default")
+                    ),
+                );
+                993
+            }
+        };
+        let server = options.arg("via").unwrap_or(&entry.server).to_owned();
+        let folders = match options.get("folder") {
+            Some(folder) if !folder.args.is_empty() => folder.args.clone(),
+            _ => vec!["INBOX".to_owned()],
+        };
+
+        let srcname = format!("{}@{}", remote, entry.server);
+        let names: Vec<_> = match folders.len() {
+            1 => vec![srcname.clone()],
+            _ => folders
+                .iter()
+                .map(|folder| format!("{}/{}", srcname, folder))
+                .collect(),
+        };
+        if let Some(name) = names.iter().find(|n| self.config.sources.contains_key(*n)) {
+            self.report(
+                user,
+                "user",
+                &format!(
+                    "{} is polled more than once, only the first one is imported",
+                    name
+                ),
+            );
+            return;
+        }
+
+        let destinations = self.add_destinations(&remote, user, options);
+        for (folder, name) in folders.iter().zip(names) {
+            self.config.sources.insert(
+                name.clone(),
+                SourceConfig::ImapIdle(ImapIdleSourceConfig {
+                    server: server.clone(),
+                    port,
+                    path: folder.clone(),
+                    renewinterval: RENEWINTERVAL,
+                    keep: options.flag("keep"),
+                    auth: AuthMethod::Login {
+                        user: remote.clone(),
+                        password: password.clone(),
+                    },
+                }),
+            );
+            self.config.mappings.insert(name, destinations.clone());
+        }
+        self.config
+            .retryagent
+            .get_or_insert(RetryAgentConfig::Memory(MemoryRetryAgentConfig {
+                delay: RETRY_DELAY,
+            }));
+    }
+
+    /// Add one destination per local name, and return their names
+    fn add_destinations(&mut self, remote: &str, user: &Opt, options: &Options) -> Vec<String> {
+        let mut locals = options.get("is").map_or(Vec::new(), |is| is.args.clone());
+        if locals.is_empty() {
+            self.report(
+                user,
+                "is",
+                &format!(
+                    "{} has no local name, which fetchmail would deliver to the user running it, delivering to {} instead",
+                    remote, remote
+                ),
+            );
+            locals.push(remote.to_owned());
+        }
+        if locals.iter().any(|local| local == "*") {
+            let is = options.get("is").expect("local names are set");
+            self.report(is, "is", "multidrop with * is not supported");
+            locals.retain(|local| local != "*");
+        } else if locals.len() > 1 {
+            let is = options.get("is").expect("local names are set");
+            self.report(
+                is,
+                "is",
+                "multidrop is not supported, all mails are delivered to every local name",
+            );
+        }
+        locals
+            .iter()
+            .map(|local| {
+                let config = match options.arg("mda") {
+                    Some(mda) => DestinationConfig::Exec(ExecDestinationConfig {
+                        executable: "/bin/sh".to_owned(),
+                        arguments: Some(vec!["-c".to_owned(), mda_command(mda, local)]),
+                        ..Default::default()
+                    }),
+                    None => self.smtp_destination(local, options),
+                };
+                self.insert_destination(local, config)
+            })
+            .collect()
+    }
+
+    fn smtp_destination(&mut self, local: &str, options: &Options) -> DestinationConfig {
+        let smtphost = options.get("smtphost");
+        if let Some(smtphost) = smtphost.filter(|o| o.args.len() > 1) {
+            self.report(
+                smtphost,
+                "smtphost",
+                "only the first host is used, without falling back to the others",
+            );
+        }
+        let host = smtphost
+            .and_then(|o| o.args.first())
+            .map_or("localhost", String::as_str);
+        let (server, port) = match host.split_once('/') {
+            Some((server, port)) => (server, port.parse().unwrap_or(25)),
+            None => (host, 25),
+        };
+        let recipient = match local.contains('@') {
+            true => local.to_owned(),
+            false => format!(
+                "{}@{}",
+                local,
+                options.arg("smtpaddress").unwrap_or("localhost")
+            ),
+        };
+        DestinationConfig::Smtp(SmtpDestinationConfig {
+            server: server.to_owned(),
+            port,
+            encryption: Encryption::None,
+            auth: None,
+            recipient,
+            retry: None,
+            on_permanent_failure: None,
+            queue_depth: None,
+        })
+    }
+
+    /// Destinations are named after the local name, and shared by all users delivering to it
+    fn insert_destination(&mut self, local: &str, config: DestinationConfig) -> String {
+        let json = serde_json::to_value(&config).expect("Failed to serialize destination");
+        let existing: HashMap<_, _> = self
+            .config
+            .destinations
+            .iter()
+            .map(|(name, c)| (name.clone(), serde_json::to_value(c).unwrap_or_default()))
+            .collect();
+        let mut name = local.to_owned();
+        let mut n = 1;
+        while let Some(other) = existing.get(&name) {
+            if *other == json {
+                return name;
+            }
+            n += 1;
+            name = format!("{}-{}", local, n);
+        }
+        self.config.destinations.insert(name.clone(), config);
+        name
+    }
+}
+
+/// The shell command of the mda, with fetchmail's `%T` (local name) and `%F` (sender)
+/// substituted
+fn mda_command(mda: &str, local: &str) -> String {
+    let local = match local
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._@+-".contains(c))
+    {
+        true => local.to_owned(),
+        false => format!("'{}'", local.replace('\'', r"'\''")),
+    };
+    let mut command = String::new();
+    let mut chars = mda.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('T')) => {
+                command.push_str(&local);
+                chars.next();
+            }
+            ('%', Some('F')) => {
+                command.push_str("\"$IDLEMAIL_FROM\"");
+                chars.next();
+            }
+            ('%', Some('%')) => {
+                command.push('%');
+                chars.next();
+            }
+            (c, _) => command.push(c),
+        }
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_import() {
+        let import = import(
+            r#"
+set daemon 300
+defaults proto imap ssl
+
+poll imap.example.org port 993:
+    user "alice@example.org" there with password "se\"cret" is alice here
+        options keep fetchall folder INBOX,Archive
+    user bob pass "b" is bob here nokeep smtphost mail.local/2525 smtpaddress example.net
+
+poll pop.example.org proto pop3 user carol pass c is carol
+skip old.example.org user dave pass d is dave
+poll mda.example.org user erin pass e is erin mda "/usr/bin/procmail -d %T -f %F"
+poll imap.example.org user bob pass b2 is bob2 nosslcertck no idle
+"#,
+        )
+        .unwrap();
+        let config = import.config;
+        let mut sources: Vec<_> = config.sources.keys().map(String::as_str).collect();
+        sources.sort();
+        assert_eq!(
+            sources,
+            vec![
+                "alice@example.org@imap.example.org/Archive",
+                "alice@example.org@imap.example.org/INBOX",
+                "bob@imap.example.org",
+                "erin@mda.example.org",
+            ]
+        );
+        match &config.sources["alice@example.org@imap.example.org/Archive"] {
+            SourceConfig::ImapIdle(source) => {
+                assert_eq!(source.server, "imap.example.org");
+                assert_eq!(source.port, 993);
+                assert_eq!(source.path, "Archive");
+                assert!(source.keep);
+                assert!(matches!(
+                    &source.auth,
+                    AuthMethod::Login { user, password }
+                        if user == "alice@example.org" && password == "se\"cret"
+                ));
+            }
+            _ => panic!("not an imap_idle source"),
+        }
+        assert_eq!(config.mappings["bob@imap.example.org"], vec!["bob"]);
+        // the duplicate user does not leave a destination behind
+        assert!(!config.destinations.contains_key("bob2"));
+        match &config.destinations["bob"] {
+            DestinationConfig::Smtp(smtp) => {
+                assert_eq!(smtp.server, "mail.local");
+                assert_eq!(smtp.port, 2525);
+                assert_eq!(smtp.recipient, "bob@example.net");
+            }
+            _ => panic!("not a smtp destination"),
+        }
+        match &config.destinations["erin"] {
+            DestinationConfig::Exec(exec) => assert_eq!(
+                exec.arguments.as_deref(),
+                Some(
+                    &[
+                        "-c".to_owned(),
+                        "/usr/bin/procmail -d erin -f \"$IDLEMAIL_FROM\"".to_owned()
+                    ][..]
+                )
+            ),
+            _ => panic!("not an exec destination"),
+        }
+        assert_eq!(
+            import.unsupported,
+            vec![
+                "line 2: set daemon: idlemail runs as a daemon and waits for new mails with IMAP IDLE",
+                "line 7: fetchall: idlemail only fetches unseen mails",
+                "line 10: proto: pop3 of carol is not supported, only IMAP",
+                "line 11: skip old.example.org: skipped servers are not imported",
+                "line 13: no sslcertck: idlemail always verifies the server certificate",
+                "line 13: no idle: idlemail always waits for new mails with IMAP IDLE",
+                "line 13: user: bob@imap.example.org is polled more than once, only the first one is imported",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let import = import("poll example.org proto imap ssl user a pass \"${x}\" is a").unwrap();
+        let json: Value = serde_json::from_str(&to_json(&import.config)).unwrap();
+        let source = &json["sources"]["a@example.org"];
+        assert_eq!(source["auth"]["password"], "$${x}");
+        // unset optional parameters are left out
+        assert!(json["destinations"]["a"].get("auth").is_none());
+        assert!(json.get("journal").is_none());
+    }
+
+    #[test_case("poll a user b" => Ok("poll|a|user|b".to_owned()); "words")]
+    #[test_case("poll a, with user \"b c\" # comment\n" => Ok("poll|a|user|b c".to_owned()); "noise")]
+    #[test_case("pass \"a\\\"b\\n\"" => Ok("pass|a\"b\n".to_owned()); "escapes")]
+    #[test_case("pass 'a\\b'" => Ok("pass|a\\b".to_owned()); "single quotes")]
+    #[test_case("user\n\"a" => Err("line 2: Unterminated string".to_owned()); "unterminated")]
+    fn test_tokenize(text: &str) -> Result<String, String> {
+        let tokens = tokenize(text)?;
+        Ok(tokens
+            .into_iter()
+            .map(|t| t.text)
+            .collect::<Vec<_>>()
+            .join("|"))
+    }
+
+    #[test_case("poll a user b pass c bogus" => "line 1: Unknown option: bogus"; "unknown option")]
+    #[test_case("user b" => "line 1: Expected poll, skip, defaults or set, found: user"; "no poll")]
+    #[test_case("poll a no user b" => "line 1: user can not be negated"; "negated")]
+    fn test_parse_error(text: &str) -> String {
+        import(text).err().unwrap()
+    }
+}
@@ -1,104 +1,535 @@
-use super::config::{ConfigContainer, DestinationConfig, SourceConfig};
+use super::config::{ConfigContainer, SourceConfig};
 use crate::{
-    config::RetryAgentConfig,
-    destinations::{
-        exec::ExecDestination, smtp::SmtpDestination, testdst::TestDestination, MailDestination,
+    config::{DedupConfig, PermanentFailureConfig, RetryAgentConfig, SpillConfig},
+    dedup::Deduplicator,
+    destinations::{self, MailDestination},
+    failure,
+    journal::Journal,
+    logging::{mail_log, MailFields},
+    maildata::{MailData, SPILL_EXTENSION},
+    retryagents::{
+        filesystem::FilesystemRetryAgent, memory::MemoryRetryAgent, sqlite::SqliteRetryAgent,
+        MailRetryAgent, RetryPolicy,
     },
-    retryagents::{filesystem::FilesystemRetryAgent, memory::MemoryRetryAgent, MailRetryAgent},
     sources::{
         imap_idle::ImapIdleSource, imap_poll::ImapPollSource, testsrc::TestSource, MailSource,
     },
+    supervisor::{AgentId, AgentState, Supervisor},
 };
+use async_mpsc::RecvError;
 use async_std::{channel as async_mpsc, future::timeout as await_timeout, task};
-use log::{info, warn};
-use mpsc::RecvError;
+use event_listener::Event;
+use futures::{channel::oneshot, FutureExt};
+use log::{debug, error, info, warn};
+use mail_parser::MessageParser;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    sync::mpsc,
-    time::Duration,
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+use ulid::Ulid;
+
+/// Minimum delay before a mail, whose retry was deferred by a saturated destination, is retried
+const SATURATED_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Well-known keys of `Mail::metadata`. Sources only set the keys they know values for.
+pub mod metadata {
+    /// Path of the mailbox the mail was fetched from
+    pub const MAILBOX: &str = "mailbox";
+    /// IMAP UID of the mail in its mailbox
+    pub const UID: &str = "uid";
+    /// IMAP UIDVALIDITY of the mailbox at the time the mail was fetched
+    pub const UIDVALIDITY: &str = "uidvalidity";
+    /// Space separated list of the mail's flags (e.g. `\Seen \Flagged`)
+    pub const FLAGS: &str = "flags";
+    /// Time (RFC 3339) the server received the mail (IMAP INTERNALDATE)
+    pub const INTERNAL_DATE: &str = "internal_date";
+    /// Size of the mail in bytes, as reported by the source
+    pub const SIZE: &str = "size";
+    /// Time (RFC 3339) the mail was fetched from the source
+    pub const FETCHED: &str = "fetched";
+    /// Envelope recipient that replaces the recipient of Smtp destinations, set for bounces
+    pub const RECIPIENT: &str = "recipient";
+}
 
 #[derive(Clone, Debug)]
 pub struct Mail {
+    /// Unique identifier (ULID) assigned when the mail was received from its source
+    pub id: String,
     pub from_src: String,
-    pub data: Vec<u8>,
+    pub data: MailData,
+    /// Hex encoded SHA-256 digest of the mail's content
     pub hash: String,
+    /// Information about the mail's origin provided by the source (see `metadata` for keys).
+    /// It is preserved across retries and restarts.
+    pub metadata: BTreeMap<String, String>,
+    /// Number of the delivery attempt to the destination this mail is queued for, starting at 1
+    pub attempt: u32,
+    /// Whether the mail is recorded in the hub's journal (under its id) while it is in flight
+    pub journaled: bool,
+    /// The mail's Message-ID, without angle brackets
+    pub message_id: Option<Box<str>>,
 }
 impl Mail {
     pub fn from_rfc822(srcname: String, body: Vec<u8>) -> Self {
-        let mut hasher = DefaultHasher::new();
-        body.hash(&mut hasher);
+        let message_id = MessageParser::default()
+            .parse_headers(&body)
+            .and_then(|headers| headers.message_id().map(Box::from));
         Self {
+            id: Ulid::new().to_string(),
             from_src: srcname,
-            data: body,
-            hash: hasher.finish().to_string(),
+            hash: hex::encode(Sha256::digest(&body)),
+            data: MailData::from(body),
+            metadata: BTreeMap::new(),
+            attempt: 1,
+            journaled: false,
+            message_id,
         }
     }
+
+    /// Keep the id of a mail that was restored from persistent storage
+    pub fn with_id(mut self, id: String) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_mailbox(mut self, mailbox: String) -> Self {
+        self.metadata.insert(metadata::MAILBOX.to_owned(), mailbox);
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
+        self.metadata.extend(metadata);
+        self
+    }
+
+    /// Path of the mailbox the mail was fetched from, if the source has mailboxes
+    pub fn mailbox(&self) -> Option<&str> {
+        self.metadata.get(metadata::MAILBOX).map(|m| m.as_str())
+    }
+
+    /// Structured fields for log events about this mail
+    pub fn log_fields(&self) -> MailFields<'_> {
+        MailFields::new(self)
+    }
 }
 
 pub enum HubMessage {
     NewMail {
         srcname: String,
         mail: Mail,
+        /// Answered once the hub took the responsibility for the mail, false if it could not
+        /// record it in the journal
+        accepted: oneshot::Sender<bool>,
     },
     RetryMail {
         dstname: String,
         mail: Mail,
     },
+    SendingMailSucceeded {
+        dstname: String,
+        mail: Mail,
+    },
     SendingMailFailed {
         dstname: String,
         mail: Mail,
+        reason: String,
+    },
+    /// The destination permanently rejected the mail, retrying it is pointless
+    SendingMailRejected {
+        dstname: String,
+        mail: Mail,
+        reason: String,
     },
     Shutdown,
     /// Message sent by the RetryAgent to confirm successfull suspension
     RetryAgentSuspended,
+    /// The RetryAgent persisted the mail, it is responsible for it from then on
+    RetryMailStored {
+        dstname: String,
+        id: String,
+    },
+    /// The agent's task ended, with the message of the panic if it crashed
+    AgentStopped {
+        agent: AgentId,
+        panic: Option<String>,
+    },
+    /// The backoff after a crash of the agent is over
+    RestartAgent {
+        agent: AgentId,
+    },
+    /// Whether the last operation of the agent succeeded
+    AgentHealth {
+        agent: AgentId,
+        result: Result<(), String>,
+    },
+    /// The saturated destination takes mails again
+    QueueDrained {
+        dstname: String,
+    },
 }
+
+/// Reports the health of an agent to the hub, whenever it changes
+pub struct HealthReporter {
+    agent: AgentId,
+    sender: async_mpsc::Sender<HubMessage>,
+    /// Last reported health, see the `HEALTH_*` constants
+    last: AtomicU8,
+}
+impl HealthReporter {
+    const HEALTH_UNKNOWN: u8 = 0;
+    const HEALTH_OK: u8 = 1;
+    const HEALTH_DEGRADED: u8 = 2;
+
+    fn new(agent: AgentId, sender: async_mpsc::Sender<HubMessage>) -> Self {
+        Self {
+            agent,
+            sender,
+            last: AtomicU8::new(Self::HEALTH_UNKNOWN),
+        }
+    }
+
+    pub fn report_healthy(&self) {
+        if self.last.swap(Self::HEALTH_OK, Ordering::SeqCst) != Self::HEALTH_OK {
+            self.send(Ok(()));
+        }
+    }
+
+    pub fn report_degraded(&self, reason: String) {
+        if self.last.swap(Self::HEALTH_DEGRADED, Ordering::SeqCst) != Self::HEALTH_DEGRADED {
+            self.send(Err(reason));
+        }
+    }
+
+    fn send(&self, result: Result<(), String>) {
+        send_to_hub(
+            &self.sender,
+            HubMessage::AgentHealth {
+                agent: self.agent.clone(),
+                result,
+            },
+        );
+    }
+}
+
+/// Send a message to the hub. Only fails if the hub stopped, in which case there is nobody
+/// left to handle it.
+fn send_to_hub(sender: &async_mpsc::Sender<HubMessage>, msg: HubMessage) {
+    if sender.try_send(msg).is_err() {
+        error!(target: "HubChannel", "Hub is not running, dropping message");
+    }
+}
+
+/// Receive from the channel, waiting at most for the given time.
+/// Mirrors the interface of `mpsc::Receiver::recv_timeout`.
+async fn recv_timeout<T>(
+    recv: &async_mpsc::Receiver<T>,
+    timeout: Duration,
+) -> Result<T, mpsc::RecvTimeoutError> {
+    match await_timeout(timeout, recv.recv()).await {
+        Ok(Ok(msg)) => Ok(msg),
+        Ok(Err(_)) => Err(mpsc::RecvTimeoutError::Disconnected),
+        Err(_) => Err(mpsc::RecvTimeoutError::Timeout),
+    }
+}
+
+/// Amount of mails queued for each destination, shared between the hub, the destinations and
+/// the sources. Sources wait while one of their destinations is saturated (backpressure).
+#[derive(Default)]
+pub struct QueueMonitor {
+    depths: HashMap<String, AtomicUsize>,
+    /// Maximum depth per destination, destinations without are unbounded
+    limits: HashMap<String, usize>,
+    drained: Event,
+}
+impl QueueMonitor {
+    pub fn new(destinations: &[String], limits: HashMap<String, usize>) -> Self {
+        Self {
+            depths: destinations
+                .iter()
+                .map(|dstname| (dstname.clone(), AtomicUsize::new(0)))
+                .collect(),
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Amount of mails queued for the destination, that it did not take yet
+    pub fn depth(&self, dstname: &str) -> usize {
+        self.depths
+            .get(dstname)
+            .map_or(0, |depth| depth.load(Ordering::SeqCst))
+    }
+
+    /// Amount of mails queued for each destination
+    pub fn depths(&self) -> BTreeMap<String, usize> {
+        self.depths
+            .iter()
+            .map(|(dstname, depth)| (dstname.clone(), depth.load(Ordering::SeqCst)))
+            .collect()
+    }
+
+    pub fn saturated(&self, dstname: &str) -> bool {
+        self.limits
+            .get(dstname)
+            .is_some_and(|limit| self.depth(dstname) >= *limit)
+    }
+
+    fn push(&self, dstname: &str) {
+        if let Some(depth) = self.depths.get(dstname) {
+            let depth = depth.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.limits.get(dstname) == Some(&depth) {
+                warn!(
+                    target: "HubChannel",
+                    "Destination {} is saturated ({} mails queued), pausing its sources",
+                    dstname,
+                    depth
+                );
+            }
+        }
+    }
+
+    fn pop(&self, dstname: &str) {
+        if let Some(depth) = self.depths.get(dstname) {
+            let depth = depth.fetch_sub(1, Ordering::SeqCst) - 1;
+            if self.limits.get(dstname) == Some(&(depth + 1)) {
+                info!(
+                    target: "HubChannel",
+                    "Destination {} accepts mails again, resuming its sources", dstname
+                );
+            }
+            self.drained.notify(usize::MAX);
+        }
+    }
+
+    /// Wait until none of the given destinations is saturated, or the timeout elapsed.
+    /// Returns whether all destinations accept mails.
+    async fn wait_for_capacity(&self, dstnames: &[String], timeout: Duration) -> bool {
+        let saturated = || dstnames.iter().any(|dstname| self.saturated(dstname));
+        let deadline = Instant::now() + timeout;
+        while saturated() {
+            let listener = self.drained.listen();
+            // a destination could have taken a mail, before the listener was registered
+            if !saturated() {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if await_timeout(remaining, listener).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Why a mail could not be queued for a destination, the mail is handed back
+#[derive(Debug)]
+pub enum QueueError {
+    /// The destination does not exist or was shut down
+    Unavailable(Box<Mail>),
+    /// The destination's queue is full
+    Saturated(Box<Mail>),
+}
+
+/// Moves the content of large mails out of memory (see `SpillConfig`). Mails are spilled by
+/// the agents before they hand them to the hub, so the hub does not wait for the disk.
+#[derive(Default)]
+pub struct Spiller {
+    config: Option<SpillConfig>,
+    /// Number of mails spilled so far, to give every spill file a unique name
+    counter: AtomicU64,
+}
+impl Spiller {
+    pub fn new(config: Option<SpillConfig>) -> Self {
+        Self {
+            config,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Move the mail's content out of memory, if it exceeds the configured threshold
+    pub async fn spill(self: &Arc<Self>, mail: Mail) -> Mail {
+        let exceeds = |config: &SpillConfig| mail.data.len() > config.threshold;
+        if mail.data.is_spilled() || !self.config.as_ref().is_some_and(exceeds) {
+            return mail;
+        }
+        let spiller = self.clone();
+        task::spawn_blocking(move || spiller.spill_blocking(mail)).await
+    }
+
+    fn spill_blocking(&self, mut mail: Mail) -> Mail {
+        let Some(config) = &self.config else {
+            return mail;
+        };
+        let path = Path::new(&config.path).join(format!(
+            "{}-{}.{}",
+            mail.id,
+            self.counter.fetch_add(1, Ordering::Relaxed),
+            SPILL_EXTENSION
+        ));
+        match mail.data.spill(&path) {
+            Ok(data) => {
+                debug!(
+                    target: "MailHub",
+                    "Spilled mail {} ({} bytes) to: {}",
+                    mail.id,
+                    data.len(),
+                    path.display()
+                );
+                mail.data = data;
+            }
+            Err(e) => warn!(
+                target: "MailHub",
+                "Failed to spill mail {}, keeping it in memory: {}", mail.id, e
+            ),
+        }
+        mail
+    }
+
+    /// Remove the spill files that remained after a crash
+    fn clean(&self) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+        let entries = match fs::read_dir(&config.path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!(target: "MailHub", "Failed to read spill folder: {}", e);
+                return;
+            }
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.extension().and_then(|e| e.to_str()) == Some(SPILL_EXTENSION) {
+                debug!(target: "MailHub", "Removing stale spill file: {}", path.display());
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!(target: "MailHub", "Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
 pub struct HubChannel {
-    sender: mpsc::Sender<HubMessage>,
-    recv: mpsc::Receiver<HubMessage>,
-    destinations: HashMap<String, mpsc::Sender<DestinationMessage>>,
+    sender: async_mpsc::Sender<HubMessage>,
+    recv: async_mpsc::Receiver<HubMessage>,
+    /// The receiving ends are kept, so mails queued for a destination are not lost if it
+    /// crashes, and are taken by the restarted destination instead.
+    destinations: HashMap<
+        String,
+        (
+            async_mpsc::Sender<DestinationMessage>,
+            async_mpsc::Receiver<DestinationMessage>,
+        ),
+    >,
+    /// Mails the destinations took from their queue, without reporting their outcome yet
+    in_flight: HashMap<String, InFlight>,
     sources: HashMap<String, async_mpsc::Sender<SourceMessage>>,
-    retryagent_sender: Option<mpsc::Sender<RetryAgentMessage>>,
-    retryagent_recv: Option<mpsc::Receiver<RetryAgentMessage>>,
+    retryagent_sender: Option<async_mpsc::Sender<RetryAgentMessage>>,
+    retryagent_recv: Option<async_mpsc::Receiver<RetryAgentMessage>>,
+    queues: Arc<QueueMonitor>,
+    spiller: Arc<Spiller>,
 }
 impl HubChannel {
-    pub fn new() -> Self {
-        let (main_sender, main_recv) = mpsc::channel();
-        let (retryagent_sender, retryagent_recv) = mpsc::channel();
+    pub fn new(queues: QueueMonitor) -> Self {
+        let (main_sender, main_recv) = async_mpsc::unbounded();
+        let (retryagent_sender, retryagent_recv) = async_mpsc::unbounded();
         Self {
             sender: main_sender,
             recv: main_recv,
             destinations: HashMap::new(),
+            in_flight: HashMap::new(),
             sources: HashMap::new(),
             retryagent_sender: Some(retryagent_sender),
             retryagent_recv: Some(retryagent_recv),
+            queues: Arc::new(queues),
+            spiller: Default::default(),
         }
     }
 
-    pub fn next(&self) -> HubMessage {
-        self.recv.recv().unwrap()
+    /// Spill large mails with the given configuration, before they reach the hub
+    pub fn with_spill(mut self, config: Option<SpillConfig>) -> Self {
+        self.spiller = Arc::new(Spiller::new(config));
+        self
+    }
+
+    pub fn queues(&self) -> &QueueMonitor {
+        &self.queues
+    }
+
+    pub async fn next(&self) -> HubMessage {
+        self.recv
+            .recv()
+            .await
+            .expect("the channel can not close, it holds a sender itself")
     }
     pub fn try_next(&self) -> Option<HubMessage> {
         self.recv.try_recv().ok()
     }
 
-    pub fn queue_mail_for_sending(&self, dstname: &str, mail: Mail) -> Result<(), ()> {
-        let dst_comm = self.destinations.get(dstname).ok_or(())?;
+    /// Queue the mail for the destination. Returns the mail, if the destination does not exist,
+    /// was shut down or its queue is full.
+    pub fn queue_mail_for_sending(&self, dstname: &str, mail: Mail) -> Result<(), QueueError> {
+        let (dst_comm, _) = match self.destinations.get(dstname) {
+            Some(dst_comm) => dst_comm,
+            None => return Err(QueueError::Unavailable(Box::new(mail))),
+        };
+        // counted first, the destination might take the mail right away
+        self.queues.push(dstname);
         dst_comm
-            .send(DestinationMessage::Mail { mail })
-            .map_err(|_| ())
+            .try_send(DestinationMessage::Mail { mail })
+            .map_err(|e| {
+                self.queues.pop(dstname);
+                let full = e.is_full();
+                let DestinationMessage::Mail { mail } = e.into_inner();
+                match full {
+                    true => QueueError::Saturated(Box::new(mail)),
+                    false => QueueError::Unavailable(Box::new(mail)),
+                }
+            })
     }
 
-    pub fn queue_mail_for_retry(&self, dstname: String, mail: Mail) {
-        if self
-            .retryagent_sender
-            .as_ref()
-            .unwrap()
-            .send(RetryAgentMessage::QueueMail { dstname, mail })
-            .is_err()
-        {
-            warn!(target: "HubChannel", "Failed to queue mail for retransmission. Either no RetryAgent configured, or a bug.");
+    /// Queue the mail for the RetryAgent. Returns the mail, if there is no RetryAgent.
+    pub fn queue_mail_for_retry(
+        &self,
+        dstname: String,
+        mail: Mail,
+        reason: String,
+        delay: Duration,
+    ) -> Result<(), Box<Mail>> {
+        let sender = match &self.retryagent_sender {
+            Some(sender) => sender,
+            None => return Err(Box::new(mail)),
+        };
+        sender
+            .try_send(RetryAgentMessage::QueueMail {
+                dstname,
+                mail,
+                reason,
+                delay,
+            })
+            .map_err(|e| match e.into_inner() {
+                RetryAgentMessage::QueueMail { mail, .. } => Box::new(mail),
+                _ => unreachable!(),
+            })
+    }
+
+    /// Tell the RetryAgent, that the outcome of a mail it handed back is final
+    pub fn complete_retry(&self, dstname: String, id: String) {
+        if let Some(sender) = &self.retryagent_sender {
+            let _ = sender.try_send(RetryAgentMessage::Completed { dstname, id });
         }
     }
 
@@ -112,15 +543,14 @@ impl HubChannel {
     }
     pub fn suspend_retryagent(&mut self) {
         info!(target: "HubChannel", "Suspending retryagent");
-        let _ = self
-            .retryagent_sender
-            .as_ref()
-            .unwrap()
-            .send(RetryAgentMessage::Suspend);
+        if let Some(sender) = &self.retryagent_sender {
+            let _ = sender.try_send(RetryAgentMessage::Suspend);
+        }
     }
     pub fn shutdown_retryagent(&mut self) {
         info!(target: "HubChannel", "Signaling shutdown to retryagent");
         drop(self.retryagent_sender.take());
+        drop(self.retryagent_recv.take());
     }
 
     pub fn get_stop_channel(&self) -> HubStopSender {
@@ -128,89 +558,229 @@ impl HubChannel {
             sender: self.sender.clone(),
         }
     }
+    /// Get the channel for a destination. A restarted destination continues with the mails,
+    /// that were queued for its predecessor.
     pub fn get_destination_channel(&mut self, name: String) -> HubDestinationChannel {
-        let (dst_send, dst_recv) = mpsc::channel();
-        self.destinations.insert(name.clone(), dst_send);
+        let limit = self.queues.limits.get(&name).copied();
+        let (_, dst_recv) = self
+            .destinations
+            .entry(name.clone())
+            .or_insert_with(|| match limit {
+                Some(limit) => async_mpsc::bounded(limit),
+                None => async_mpsc::unbounded(),
+            });
         HubDestinationChannel {
+            recv: dst_recv.clone(),
+            in_flight: self.in_flight.entry(name.clone()).or_default().clone(),
             name,
             sender: self.sender.clone(),
-            recv: dst_recv,
+            queues: self.queues.clone(),
         }
     }
-    pub fn get_source_channel(&mut self, name: String) -> HubSourceChannel {
+    /// Take the mails the destination was handling, e.g. after it crashed
+    pub fn take_in_flight(&self, dstname: &str) -> Vec<Mail> {
+        self.in_flight
+            .get(dstname)
+            .map(|in_flight| {
+                let mut in_flight = in_flight.lock().unwrap_or_else(|e| e.into_inner());
+                in_flight.drain().map(|(_, mail)| mail).collect()
+            })
+            .unwrap_or_default()
+    }
+    /// Get the channel for a source, that delivers its mails to the given destinations
+    pub fn get_source_channel(
+        &mut self,
+        name: String,
+        destinations: Vec<String>,
+        once: bool,
+        dry_run: bool,
+    ) -> HubSourceChannel {
         let (src_send, src_recv) = async_mpsc::bounded(1);
         self.sources.insert(name.clone(), src_send);
         HubSourceChannel {
+            health: HealthReporter::new(AgentId::Source(name.clone()), self.sender.clone()),
             name,
             sender: self.sender.clone(),
             recv: src_recv,
+            destinations,
+            queues: self.queues.clone(),
+            spiller: self.spiller.clone(),
+            once,
+            dry_run,
         }
     }
-    pub fn get_retryagent_channel(&mut self) -> HubRetryAgentChannel {
-        HubRetryAgentChannel {
+    /// Get the channel for the RetryAgent, None after it was shut down.
+    /// A restarted RetryAgent continues with the mails, that were queued for its predecessor.
+    pub fn get_retryagent_channel(&mut self) -> Option<HubRetryAgentChannel> {
+        Some(HubRetryAgentChannel {
+            health: HealthReporter::new(AgentId::RetryAgent, self.sender.clone()),
             sender: self.sender.clone(),
-            recv: self.retryagent_recv.take().unwrap(),
-        }
+            recv: self.retryagent_recv.clone()?,
+            spiller: self.spiller.clone(),
+        })
     }
 }
 
 pub struct HubStopSender {
-    pub(crate) sender: mpsc::Sender<HubMessage>,
+    pub(crate) sender: async_mpsc::Sender<HubMessage>,
 }
 impl HubStopSender {
     pub fn stop(&self) {
-        self.sender.send(HubMessage::Shutdown).unwrap();
+        send_to_hub(&self.sender, HubMessage::Shutdown);
     }
 }
 
 pub enum DestinationMessage {
     Mail { mail: Mail },
 }
+/// Mails taken by a destination by their id, until it reports their outcome
+pub type InFlight = Arc<Mutex<HashMap<String, Mail>>>;
 pub struct HubDestinationChannel {
     pub(crate) name: String,
-    pub(crate) sender: mpsc::Sender<HubMessage>,
-    pub(crate) recv: mpsc::Receiver<DestinationMessage>,
+    pub(crate) sender: async_mpsc::Sender<HubMessage>,
+    pub(crate) recv: async_mpsc::Receiver<DestinationMessage>,
+    pub(crate) queues: Arc<QueueMonitor>,
+    pub(crate) in_flight: InFlight,
 }
 impl HubDestinationChannel {
-    pub fn next(&self) -> Result<DestinationMessage, RecvError> {
-        self.recv.recv()
+    /// Wait for the next message. Can be called from several tasks at once,
+    /// every message is only received by one of them.
+    pub async fn next(&self) -> Result<DestinationMessage, RecvError> {
+        let msg = self.recv.recv().await?;
+        self.taken(&msg);
+        Ok(msg)
+    }
+    pub async fn next_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<DestinationMessage, mpsc::RecvTimeoutError> {
+        let msg = recv_timeout(&self.recv, timeout).await?;
+        self.taken(&msg);
+        Ok(msg)
+    }
+
+    /// The mail is kept until its outcome is reported, to queue it again if the destination
+    /// crashes in the meantime
+    fn taken(&self, msg: &DestinationMessage) {
+        self.queues.pop(&self.name);
+        let DestinationMessage::Mail { mail } = msg;
+        self.lock_in_flight().insert(mail.id.clone(), mail.clone());
+    }
+
+    fn reported(&self, mail: &Mail) {
+        self.lock_in_flight().remove(&mail.id);
     }
 
-    pub fn notify_failed_send(&self, mail: Mail) {
-        self.sender
-            .send(HubMessage::SendingMailFailed {
+    fn lock_in_flight(&self) -> std::sync::MutexGuard<'_, HashMap<String, Mail>> {
+        // the map stays consistent, even if a destination panicked while holding the lock
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn notify_successful_send(&self, mail: Mail) {
+        self.reported(&mail);
+        send_to_hub(
+            &self.sender,
+            HubMessage::SendingMailSucceeded {
                 dstname: self.name.clone(),
                 mail,
-            })
-            .unwrap();
+            },
+        );
+    }
+
+    pub fn notify_failed_send(&self, mail: Mail, reason: String) {
+        self.reported(&mail);
+        send_to_hub(
+            &self.sender,
+            HubMessage::SendingMailFailed {
+                dstname: self.name.clone(),
+                mail,
+                reason,
+            },
+        );
+    }
+
+    pub fn notify_rejected_send(&self, mail: Mail, reason: String) {
+        self.reported(&mail);
+        send_to_hub(
+            &self.sender,
+            HubMessage::SendingMailRejected {
+                dstname: self.name.clone(),
+                mail,
+                reason,
+            },
+        );
     }
 }
 
 pub enum SourceMessage {}
 pub struct HubSourceChannel {
     pub(crate) name: String,
-    pub(crate) sender: mpsc::Sender<HubMessage>,
+    pub(crate) sender: async_mpsc::Sender<HubMessage>,
     pub(crate) recv: async_mpsc::Receiver<SourceMessage>,
+    /// Destinations the source's mails are distributed to
+    pub(crate) destinations: Vec<String>,
+    pub(crate) queues: Arc<QueueMonitor>,
+    pub(crate) health: HealthReporter,
+    spiller: Arc<Spiller>,
+    once: bool,
+    dry_run: bool,
 }
 impl HubSourceChannel {
+    /// Whether the source should stop, after it fetched all mails once
+    pub fn once(&self) -> bool {
+        self.once
+    }
+    /// Whether the source must not modify its mailboxes (no flags, no deletion)
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
     pub async fn next(&self) -> Option<SourceMessage> {
         self.recv.recv().await.ok()
     }
-    pub fn next_timeout(&self, timeout: Duration) -> Result<SourceMessage, mpsc::RecvTimeoutError> {
-        // match async interface to synchrnous std interface for consistency
-        match task::block_on(await_timeout(timeout, self.recv.recv())) {
-            Ok(Ok(msg)) => Ok(msg),
-            Ok(Err(_)) => Err(mpsc::RecvTimeoutError::Disconnected),
-            Err(_) => Err(mpsc::RecvTimeoutError::Timeout),
-        }
+    pub async fn next_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<SourceMessage, mpsc::RecvTimeoutError> {
+        recv_timeout(&self.recv, timeout).await
     }
-    pub fn notify_new_mail(&self, mail: Mail) {
-        self.sender
-            .send(HubMessage::NewMail {
+    /// Hand a new mail to the hub. Waits while one of the source's destinations is saturated,
+    /// unless the source is asked to stop.
+    /// Returns whether the hub took the responsibility for the mail (e.g. recorded it in the
+    /// journal), only then the source may delete it.
+    pub async fn notify_new_mail(&self, mail: Mail) -> bool {
+        let mut paused = false;
+        while !self
+            .queues
+            .wait_for_capacity(&self.destinations, Duration::from_secs(1))
+            .await
+        {
+            if self.recv.is_closed() {
+                break;
+            }
+            if !paused {
+                info!(
+                    target: "HubChannel",
+                    "Source {} paused, waiting for its destinations", self.name
+                );
+                paused = true;
+            }
+        }
+        // a dry run does not keep mails around
+        let mail = match self.dry_run {
+            true => mail,
+            false => self.spiller.spill(mail).await,
+        };
+        let (accepted, answer) = oneshot::channel();
+        send_to_hub(
+            &self.sender,
+            HubMessage::NewMail {
                 srcname: self.name.clone(),
                 mail,
-            })
-            .unwrap();
+                accepted,
+            },
+        );
+        // the hub dropped the message, if it stopped in the meantime
+        answer.await.unwrap_or(false)
     }
 }
 
@@ -218,6 +788,10 @@ pub enum RetryAgentMessage {
     QueueMail {
         dstname: String,
         mail: Mail,
+        /// Why the last delivery attempt failed
+        reason: String,
+        /// Time to wait before the mail is retried
+        delay: Duration,
     },
     /// Sending this message to a running RetryAgent suspends its re-submission attempts.
     /// This means, that the RetryAgent will still receive and handle incomming messages
@@ -226,30 +800,86 @@ pub enum RetryAgentMessage {
     /// messages for resubmission, but does not attempt actual resubmission so the destinations
     /// can shut down.
     Suspend,
+    /// The mail the RetryAgent handed back was delivered or given up on. Persistent RetryAgents
+    /// keep a mail they handed back until then, so it survives a crash in the meantime.
+    Completed { dstname: String, id: String },
 }
 pub struct HubRetryAgentChannel {
-    sender: mpsc::Sender<HubMessage>,
-    recv: mpsc::Receiver<RetryAgentMessage>,
+    sender: async_mpsc::Sender<HubMessage>,
+    recv: async_mpsc::Receiver<RetryAgentMessage>,
+    pub(crate) health: HealthReporter,
+    spiller: Arc<Spiller>,
 }
 impl HubRetryAgentChannel {
-    pub fn next_timeout(
+    pub async fn next_timeout(
         &self,
         timeout: Duration,
     ) -> Result<RetryAgentMessage, mpsc::RecvTimeoutError> {
-        self.recv.recv_timeout(timeout)
+        recv_timeout(&self.recv, timeout).await
     }
-    pub fn notify_retry_mail(&self, dstname: String, mail: Mail) {
-        self.sender
-            .send(HubMessage::RetryMail { dstname, mail })
-            .unwrap();
+    pub async fn notify_retry_mail(&self, dstname: String, mail: Mail) {
+        let mail = self.spiller.spill(mail).await;
+        send_to_hub(&self.sender, HubMessage::RetryMail { dstname, mail });
     }
     pub fn confirm_suspension(&self) {
-        self.sender.send(HubMessage::RetryAgentSuspended).unwrap();
+        send_to_hub(&self.sender, HubMessage::RetryAgentSuspended);
+    }
+    /// Confirm that the mail is stored persistently, so the hub can remove it from its journal
+    pub fn confirm_stored(&self, dstname: &str, mail: &Mail) {
+        send_to_hub(
+            &self.sender,
+            HubMessage::RetryMailStored {
+                dstname: dstname.to_owned(),
+                id: mail.id.clone(),
+            },
+        );
     }
 }
 
+/// Agents run as tasks on the shared executor
 pub trait MailAgent {
-    fn join(&mut self);
+    /// Take the handle of the agent's task, to wait for it to stop.
+    /// None, if the agent did not start a task.
+    fn join(&mut self) -> Option<task::JoinHandle<()>>;
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Mails the hub is still responsible for, to know when a run-once is done
+#[derive(Default)]
+struct Outstanding {
+    /// Mails queued for a destination, that did not report the outcome yet
+    delivering: Cell<usize>,
+    /// Destination and id of the mails waiting in the RetryAgent
+    retrying: RefCell<HashSet<(String, String)>>,
+    /// Mails that were dropped, quarantined or bounced
+    undelivered: Cell<usize>,
+}
+impl Outstanding {
+    fn delivered(&self) {
+        self.delivering.set(self.delivering.get().saturating_sub(1));
+    }
+
+    /// Mails that are neither delivered nor given up on yet
+    fn pending(&self) -> usize {
+        self.delivering.get() + self.retrying.borrow().len()
+    }
+}
+
+/// Result of `MailHub::run_once`
+pub struct RunOnceResult {
+    /// Mails that were not delivered, because they were given up on or the deadline passed
+    pub undelivered: usize,
+    /// Sources that failed to fetch their mails
+    pub failed_sources: Vec<String>,
 }
 
 pub struct MailHub {
@@ -257,30 +887,73 @@ pub struct MailHub {
     source_agents: HashMap<String, Box<dyn MailSource>>,
     retryagent: Option<Box<dyn MailRetryAgent>>,
     mappings: HashMap<String, Vec<String>>,
+    retry_policies: HashMap<String, RetryPolicy>,
+    failure_policies: HashMap<String, PermanentFailureConfig>,
+    journal: Option<Journal>,
+    dedup_config: Option<DedupConfig>,
+    /// Opened when the hub starts
+    dedup: Option<Deduplicator>,
     hubchannel: HubChannel,
+    /// Mails waiting for their saturated destination, in the order they were distributed
+    parked: RefCell<HashMap<String, VecDeque<Mail>>>,
+    /// Destination and id of the mails the RetryAgent handed back, it keeps them until their
+    /// outcome is final
+    retried: RefCell<HashSet<(String, String)>>,
+    /// Tasks watching the running agents, that report when an agent stops
+    watchers: HashMap<AgentId, task::JoinHandle<()>>,
+    supervisor: Supervisor,
+    /// Whether the hub is shutting down, so agents stopping is expected
+    stopping: bool,
+    /// Whether sources fetch their mails only once, and stop afterwards
+    once: bool,
+    /// Whether mails are only reported, instead of delivered
+    dry_run: bool,
+    outstanding: Outstanding,
 }
 impl MailHub {
     pub fn from_config(config: &ConfigContainer) -> Self {
         let mut destination_agents = HashMap::new();
         let mut source_agents = HashMap::new();
-        let hubchannel = HubChannel::new();
+        let dstnames: Vec<_> = config.destinations.keys().cloned().collect();
+        let queue_limits = config
+            .destinations
+            .iter()
+            .filter_map(|(dstname, dstcfg)| dstcfg.queue_depth().map(|d| (dstname.clone(), d)))
+            .collect();
+        let mut hubchannel = HubChannel::new(QueueMonitor::new(&dstnames, queue_limits))
+            .with_spill(config.spill.clone());
+        if config.retryagent.is_none() {
+            // failed mails are not retried, instead of waiting in a queue nobody takes them from
+            hubchannel.shutdown_retryagent();
+        }
 
         // Create destinations
         for (dstname, dstcfg) in &config.destinations {
-            let destination_agent: Box<dyn MailDestination> = match dstcfg {
-                DestinationConfig::Test(config) => {
-                    Box::new(TestDestination::new(dstname.clone(), config))
-                }
-                DestinationConfig::Smtp(config) => {
-                    Box::new(SmtpDestination::new(dstname.clone(), config))
-                }
-                DestinationConfig::Exec(config) => {
-                    Box::new(ExecDestination::new(dstname.clone(), config))
-                }
-            };
-            destination_agents.insert(dstname.clone(), destination_agent);
+            destination_agents.insert(dstname.clone(), destinations::create(dstname, dstcfg));
         }
 
+        let default_delay = config.retryagent.as_ref().map_or(0, |c| c.delay());
+        let retry_policies = config
+            .destinations
+            .iter()
+            .map(|(dstname, dstcfg)| {
+                (
+                    dstname.clone(),
+                    RetryPolicy::new(default_delay, dstcfg.default_max_attempts(), dstcfg.retry()),
+                )
+            })
+            .collect();
+
+        let failure_policies = config
+            .destinations
+            .iter()
+            .filter_map(|(dstname, dstcfg)| {
+                dstcfg
+                    .on_permanent_failure()
+                    .map(|policy| (dstname.clone(), policy.clone()))
+            })
+            .collect();
+
         // Create sources
         for (srcname, srccfg) in &config.sources {
             let source_agent: Box<dyn MailSource> = match srccfg {
@@ -299,6 +972,7 @@ impl MailHub {
             let retryagent: Box<dyn MailRetryAgent> = match c {
                 RetryAgentConfig::Memory(config) => Box::new(MemoryRetryAgent::new(config)),
                 RetryAgentConfig::Filesystem(config) => Box::new(FilesystemRetryAgent::new(config)),
+                RetryAgentConfig::Sqlite(config) => Box::new(SqliteRetryAgent::new(config)),
             };
             retryagent
         });
@@ -308,11 +982,428 @@ impl MailHub {
             source_agents,
             retryagent,
             mappings: config.mappings.clone(),
+            retry_policies,
+            failure_policies,
+            journal: config.journal.as_ref().map(Journal::new),
+            dedup_config: config.dedup.clone(),
+            dedup: None,
+            supervisor: Supervisor::new(config.supervision.as_ref(), hubchannel.queues.clone()),
             hubchannel,
+            parked: RefCell::new(HashMap::new()),
+            retried: RefCell::new(HashSet::new()),
+            watchers: HashMap::new(),
+            stopping: false,
+            once: false,
+            dry_run: false,
+            outstanding: Outstanding::default(),
         }
     }
 
-    fn handle_message(&self, msg: HubMessage) -> bool {
+    /// Only report which destinations the mails would be delivered to. Destinations, the
+    /// RetryAgent, the journal and the dedup database are not used, and sources do not modify their mailboxes.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Record in the journal, that the destination is done with the mail.
+    /// If the RetryAgent handed the mail back, it can let go of the mail as well.
+    fn complete_journal_entry(&self, dstname: &str, mail: &Mail) {
+        if let (Some(journal), true) = (&self.journal, mail.journaled) {
+            if let Err(e) = journal.complete(&mail.id, dstname) {
+                error!(target: "MailHub", "Failed to update journal entry {}: {:#}", mail.id, e);
+            }
+        }
+        let key = (dstname.to_owned(), mail.id.clone());
+        if self.retried.borrow_mut().remove(&key) {
+            let (dstname, id) = key;
+            self.hubchannel.complete_retry(dstname, id);
+        }
+    }
+
+    /// Remove the destinations that already received the mail from the list
+    fn skip_duplicates(&self, srcname: &str, mail: &Mail, dstlist: &[String]) -> Vec<String> {
+        let dedup = match &self.dedup {
+            Some(dedup) => dedup,
+            None => return dstlist.to_vec(),
+        };
+        match dedup.unseen(srcname, mail, dstlist) {
+            Ok(unseen) => {
+                if unseen.len() < dstlist.len() {
+                    mail_log!(
+                        Info,
+                        "MailHub",
+                        mail.log_fields().outcome("duplicate"),
+                        "Mail {} is a duplicate, skipping {} of {} destinations",
+                        mail.id,
+                        dstlist.len() - unseen.len(),
+                        dstlist.len()
+                    );
+                }
+                unseen
+            }
+            Err(e) => {
+                error!(target: "MailHub", "Failed to check mail {} for duplicates: {:#}", mail.id, e);
+                dstlist.to_vec()
+            }
+        }
+    }
+
+    /// Apply the destination's on_permanent_failure policy to a mail it can not deliver
+    fn handle_permanent_failure(&self, dstname: &str, mut mail: Mail, reason: &str) {
+        if !matches!(
+            self.failure_policies.get(dstname),
+            Some(PermanentFailureConfig::Fallback { .. })
+        ) {
+            let undelivered = &self.outstanding.undelivered;
+            undelivered.set(undelivered.get() + 1);
+        }
+        match self.failure_policies.get(dstname) {
+            None | Some(PermanentFailureConfig::Drop) => {
+                mail_log!(
+                    Error,
+                    "MailHub",
+                    mail.log_fields().destination(dstname).outcome("dropped"),
+                    "Dropping mail {} that destination {} can not deliver",
+                    mail.id,
+                    dstname
+                );
+            }
+            Some(PermanentFailureConfig::Quarantine { path }) => {
+                match failure::quarantine(path, &mail) {
+                    Ok(path) => mail_log!(
+                        Warn,
+                        "MailHub",
+                        mail.log_fields()
+                            .destination(dstname)
+                            .outcome("quarantined"),
+                        "Quarantined mail {} that destination {} can not deliver in: {}",
+                        mail.id,
+                        dstname,
+                        path.display()
+                    ),
+                    Err(e) => mail_log!(
+                        Error,
+                        "MailHub",
+                        mail.log_fields().destination(dstname).outcome("lost"),
+                        "Failed to quarantine mail {}, it is permanently lost: {:#}",
+                        mail.id,
+                        e
+                    ),
+                }
+            }
+            Some(PermanentFailureConfig::Fallback { destination }) => {
+                mail_log!(
+                    Warn,
+                    "MailHub",
+                    mail.log_fields().destination(dstname).outcome("fallback"),
+                    "Handing mail {} that destination {} can not deliver to {}",
+                    mail.id,
+                    dstname,
+                    destination
+                );
+                mail.attempt = 1;
+                mail.journaled = false;
+                self.distribute(destination, mail);
+            }
+            Some(PermanentFailureConfig::Bounce {
+                destination,
+                postmaster,
+                from,
+            }) => {
+                mail_log!(
+                    Warn,
+                    "MailHub",
+                    mail.log_fields().destination(dstname).outcome("bounced"),
+                    "Bouncing mail {} that destination {} can not deliver to {} via {}",
+                    mail.id,
+                    dstname,
+                    postmaster,
+                    destination
+                );
+                match failure::bounce(from.as_deref(), postmaster, dstname, &mail, reason) {
+                    Ok(bounce) => self.distribute(destination, bounce),
+                    Err(e) => mail_log!(
+                        Error,
+                        "MailHub",
+                        mail.log_fields().destination(dstname).outcome("lost"),
+                        "Failed to bounce mail {}, it is permanently lost: {:#}",
+                        mail.id,
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Queue the mail for the destination. If the destination is saturated, the mail waits in
+    /// the hub until it takes mails again. If the destination does not take mails, the mail is
+    /// handed to the RetryAgent instead.
+    fn distribute(&self, dstname: &str, mail: Mail) {
+        // mails do not overtake the ones waiting for the destination
+        let result = match self.is_parking(dstname) {
+            true => Err(QueueError::Saturated(Box::new(mail))),
+            false => self.hubchannel.queue_mail_for_sending(dstname, mail),
+        };
+        match result {
+            Ok(()) => {
+                let delivering = &self.outstanding.delivering;
+                delivering.set(delivering.get() + 1);
+            }
+            Err(QueueError::Saturated(mail)) => {
+                let delivering = &self.outstanding.delivering;
+                delivering.set(delivering.get() + 1);
+                self.park(dstname, *mail);
+            }
+            Err(QueueError::Unavailable(mail)) => self.unavailable(dstname, *mail),
+        }
+    }
+
+    fn is_parking(&self, dstname: &str) -> bool {
+        self.parked
+            .borrow()
+            .get(dstname)
+            .is_some_and(|parked| !parked.is_empty())
+    }
+
+    /// Keep the mail until the saturated destination takes mails again
+    fn park(&self, dstname: &str, mail: Mail) {
+        if !self.is_parking(dstname) {
+            self.await_capacity(dstname);
+        }
+        debug!(
+            target: "MailHub",
+            "Destination {} is saturated, mail {} waits for it",
+            dstname,
+            mail.id
+        );
+        self.parked
+            .borrow_mut()
+            .entry(dstname.to_owned())
+            .or_default()
+            .push_back(mail);
+    }
+
+    /// Tell the hub, once the destination takes mails again
+    fn await_capacity(&self, dstname: &str) {
+        let queues = self.hubchannel.queues.clone();
+        let sender = self.hubchannel.sender.clone();
+        let dstnames = [dstname.to_owned()];
+        task::spawn(async move {
+            while !queues
+                .wait_for_capacity(&dstnames, Duration::from_secs(60))
+                .await
+            {
+                if sender.is_closed() {
+                    return;
+                }
+            }
+            let [dstname] = dstnames;
+            send_to_hub(&sender, HubMessage::QueueDrained { dstname });
+        });
+    }
+
+    /// Queue the mails waiting for the destination, until it is saturated again
+    fn unpark(&self, dstname: &str) {
+        loop {
+            let mail = match self.parked.borrow_mut().get_mut(dstname) {
+                Some(parked) => parked.pop_front(),
+                None => None,
+            };
+            let Some(mail) = mail else {
+                return;
+            };
+            match self.hubchannel.queue_mail_for_sending(dstname, mail) {
+                Ok(()) => {}
+                Err(QueueError::Saturated(mail)) => {
+                    self.await_capacity(dstname);
+                    if let Some(parked) = self.parked.borrow_mut().get_mut(dstname) {
+                        parked.push_front(*mail);
+                    }
+                    return;
+                }
+                Err(QueueError::Unavailable(mail)) => {
+                    self.outstanding.delivered();
+                    self.unavailable(dstname, *mail);
+                }
+            }
+        }
+    }
+
+    /// The mails still waiting for their destination when stopping are handed to the RetryAgent
+    fn release_parked(&self) {
+        let parked = self.parked.take();
+        for (dstname, mails) in parked {
+            for mail in mails {
+                self.outstanding.delivered();
+                let reason = format!("Destination {} was saturated when stopping", dstname);
+                self.retry(&dstname, mail, reason, Duration::ZERO);
+            }
+        }
+    }
+
+    /// The destination does not take mails, they are handed to the RetryAgent instead
+    fn unavailable(&self, dstname: &str, mail: Mail) {
+        mail_log!(
+            Error,
+            "MailHub",
+            mail.log_fields()
+                .destination(dstname)
+                .outcome("unavailable"),
+            "Destination {} does not take mails, queueing mail {} for retransmission",
+            dstname,
+            mail.id
+        );
+        let delay = self.retry_delay(dstname, &mail);
+        let reason = format!("Destination {} is not available", dstname);
+        self.retry(dstname, mail, reason, delay);
+    }
+
+    fn retry_delay(&self, dstname: &str, mail: &Mail) -> Duration {
+        self.retry_policies
+            .get(dstname)
+            .map_or(Duration::ZERO, |policy| policy.delay(mail.attempt))
+    }
+
+    /// Hand the mail to the RetryAgent. The mail stays in the journal, until the RetryAgent
+    /// confirms that it stored the mail persistently.
+    /// Without RetryAgent, the destination's on_permanent_failure policy applies.
+    fn retry(&self, dstname: &str, mail: Mail, reason: String, delay: Duration) {
+        let id = mail.id.clone();
+        match self
+            .hubchannel
+            .queue_mail_for_retry(dstname.to_owned(), mail, reason.clone(), delay)
+        {
+            Ok(()) => {
+                let key = (dstname.to_owned(), id);
+                // the RetryAgent queues a mail it handed back again, instead of letting go of it
+                self.retried.borrow_mut().remove(&key);
+                self.outstanding.retrying.borrow_mut().insert(key);
+            }
+            Err(mail) => {
+                mail_log!(
+                    Warn,
+                    "MailHub",
+                    mail.log_fields().destination(dstname),
+                    "No RetryAgent to retry mail {}",
+                    mail.id
+                );
+                self.complete_journal_entry(dstname, &mail);
+                self.handle_permanent_failure(dstname, *mail, &reason);
+            }
+        }
+    }
+
+    /// Start the agent and watch its task
+    fn start_agent(&mut self, agent: &AgentId) {
+        let worker = match agent {
+            AgentId::Source(name) => {
+                let Some(src) = self.source_agents.get_mut(name) else {
+                    return;
+                };
+                info!(target: "MailHub", "Starting source: {}", name);
+                let destinations = self.mappings.get(name).cloned().unwrap_or_default();
+                src.start(self.hubchannel.get_source_channel(
+                    name.clone(),
+                    destinations,
+                    self.once,
+                    self.dry_run,
+                ));
+                src.join()
+            }
+            AgentId::Destination(name) => {
+                let Some(dst) = self.destination_agents.get_mut(name) else {
+                    return;
+                };
+                info!(target: "MailHub", "Starting destination: {}", name);
+                dst.start(self.hubchannel.get_destination_channel(name.clone()));
+                dst.join()
+            }
+            AgentId::RetryAgent => {
+                let (Some(retryagent), Some(comm)) = (
+                    self.retryagent.as_mut(),
+                    self.hubchannel.get_retryagent_channel(),
+                ) else {
+                    return;
+                };
+                info!(target: "MailHub", "Starting retryagent");
+                retryagent.start(comm);
+                retryagent.join()
+            }
+        };
+        self.supervisor.started(agent);
+        if let Some(worker) = worker {
+            let sender = self.hubchannel.sender.clone();
+            let agent = agent.clone();
+            let watcher = task::spawn({
+                let agent = agent.clone();
+                async move {
+                    let panic = AssertUnwindSafe(worker)
+                        .catch_unwind()
+                        .await
+                        .err()
+                        .map(|payload| panic_message(payload.as_ref()));
+                    send_to_hub(&sender, HubMessage::AgentStopped { agent, panic });
+                }
+            });
+            self.watchers.insert(agent, watcher);
+        }
+    }
+
+    /// Wait for the agent's task to end
+    async fn join_agent(&mut self, agent: &AgentId) {
+        if let Some(watcher) = self.watchers.remove(agent) {
+            watcher.await;
+        }
+    }
+
+    /// Schedule the restart of an agent that stopped while the hub is running
+    fn handle_agent_stopped(&mut self, agent: AgentId, panic: Option<String>) {
+        if let (true, AgentId::Source(name)) = (self.once, &agent) {
+            // sources stop once they fetched their mails
+            match panic {
+                Some(panic) => self.supervisor.failed(&agent, panic),
+                None => info!(target: "MailHub", "Source: {} is done", name),
+            }
+            return;
+        }
+        let reason = panic.unwrap_or_else(|| "stopped unexpectedly".to_owned());
+        if let Some(delay) = self.supervisor.crashed(&agent, reason) {
+            let sender = self.hubchannel.sender.clone();
+            task::spawn(async move {
+                task::sleep(delay).await;
+                send_to_hub(&sender, HubMessage::RestartAgent { agent });
+            });
+        }
+    }
+
+    /// Queue the mails a stopped destination was handling again, its restarted instance takes
+    /// them. While stopping, they are handed to the RetryAgent instead.
+    fn requeue_in_flight(&self, dstname: &str) {
+        for mail in self.hubchannel.take_in_flight(dstname) {
+            mail_log!(
+                Warn,
+                "MailHub",
+                mail.log_fields().destination(dstname).outcome("queued"),
+                "Destination {} stopped while handling mail {}, queueing it again",
+                dstname,
+                mail.id
+            );
+            // it is counted again when it is distributed
+            self.outstanding.delivered();
+            self.distribute(dstname, mail);
+        }
+    }
+
+    /// The health of destinations is deduced from the outcome of their deliveries
+    fn report_destination(&mut self, dstname: &str, result: Result<(), String>) {
+        if !self.stopping {
+            self.supervisor
+                .report(&AgentId::Destination(dstname.to_owned()), result);
+        }
+    }
+
+    fn handle_message(&mut self, msg: HubMessage) -> bool {
         match msg {
             HubMessage::Shutdown => {
                 return true;
@@ -320,87 +1411,477 @@ impl MailHub {
             HubMessage::RetryAgentSuspended => {
                 return true;
             }
-            HubMessage::NewMail { srcname, mail } => {
-                info!(target: "MailHub", "Mail from source {}", srcname);
+            HubMessage::RetryMailStored { dstname, id } => {
+                if let Some(journal) = &self.journal {
+                    if let Err(e) = journal.complete(&id, &dstname) {
+                        error!(target: "MailHub", "Failed to update journal entry {}: {:#}", id, e);
+                    }
+                }
+            }
+            HubMessage::AgentStopped { agent, panic } => {
+                self.watchers.remove(&agent);
+                if let AgentId::Destination(dstname) = &agent {
+                    self.requeue_in_flight(dstname);
+                }
+                if !self.stopping {
+                    self.handle_agent_stopped(agent, panic);
+                } else if agent == AgentId::RetryAgent {
+                    // it will not confirm its suspension anymore
+                    if let Some(panic) = panic {
+                        error!(target: "MailHub", "The retryagent crashed: {}", panic);
+                    }
+                    return true;
+                }
+            }
+            HubMessage::RestartAgent { agent } => {
+                if !self.stopping {
+                    self.start_agent(&agent);
+                }
+            }
+            HubMessage::AgentHealth { agent, result } => {
+                if !self.stopping {
+                    self.supervisor.report(&agent, result);
+                }
+            }
+            HubMessage::NewMail {
+                srcname,
+                mail,
+                accepted,
+            } if self.dry_run => {
+                self.report_dry_run(&srcname, &mail);
+                let _ = accepted.send(true);
+            }
+            HubMessage::NewMail {
+                srcname,
+                mail,
+                accepted,
+            } => {
+                mail_log!(
+                    Info,
+                    "MailHub",
+                    mail.log_fields().outcome("received"),
+                    "Mail {} from source {}",
+                    mail.id,
+                    srcname
+                );
+                let mut mail = mail;
+                let mut recorded = true;
                 if let Some(dstlist) = self.mappings.get(&srcname) {
-                    for dstname in dstlist {
-                        info!(target: "MailHub", "Distributing Mail {} => {}", srcname, dstname);
-                        self.hubchannel
-                            .queue_mail_for_sending(dstname, mail.clone())
-                            .expect("Failed to distribute mail");
+                    let dstlist = self.skip_duplicates(&srcname, &mail, dstlist);
+                    if let (Some(journal), false) = (&self.journal, dstlist.is_empty()) {
+                        match journal.record(&srcname, &mail, &dstlist) {
+                            Ok(_) => mail.journaled = true,
+                            Err(e) => {
+                                error!(
+                                    target: "MailHub",
+                                    "Failed to record mail {} in journal: {:#}", mail.id, e
+                                );
+                                recorded = false;
+                            }
+                        }
+                    }
+                    // copies arriving in the meantime are duplicates, but the mail is only
+                    // remembered persistently once delivered
+                    if let Some(dedup) = &self.dedup {
+                        dedup.reserve(&srcname, &mail, &dstlist);
+                    }
+                    for dstname in &dstlist {
+                        mail_log!(
+                            Info,
+                            "MailHub",
+                            mail.log_fields().destination(dstname).outcome("queued"),
+                            "Distributing Mail {} {} => {} ({} queued)",
+                            mail.id,
+                            srcname,
+                            dstname,
+                            self.hubchannel.queues().depth(dstname)
+                        );
+                        self.distribute(dstname, mail.clone());
                     }
                 }
+                // the source keeps the mail, if the hub could not record it
+                let _ = accepted.send(recorded);
             }
-            HubMessage::SendingMailFailed { dstname, mail } => {
-                info!(target: "MailHub", "Queueing failed mail for retransmission");
-                self.hubchannel.queue_mail_for_retry(dstname, mail);
+            HubMessage::SendingMailSucceeded { dstname, mail } => {
+                mail_log!(
+                    Info,
+                    "MailHub",
+                    mail.log_fields().destination(&dstname).outcome("delivered"),
+                    "Mail {} was delivered to {}",
+                    mail.id,
+                    dstname
+                );
+                self.outstanding.delivered();
+                self.report_destination(&dstname, Ok(()));
+                if let Some(dedup) = &self.dedup {
+                    if let Err(e) =
+                        dedup.record(&mail.from_src, &mail, std::slice::from_ref(&dstname))
+                    {
+                        error!(target: "MailHub", "Failed to remember mail {}: {:#}", mail.id, e);
+                    }
+                }
+                self.complete_journal_entry(&dstname, &mail);
+            }
+            HubMessage::SendingMailFailed {
+                dstname,
+                mut mail,
+                reason,
+            } => {
+                mail_log!(
+                    Warn,
+                    "MailHub",
+                    mail.log_fields().destination(&dstname).outcome("failed"),
+                    "Mail {} could not be delivered to {}: {}",
+                    mail.id,
+                    dstname,
+                    reason
+                );
+                self.outstanding.delivered();
+                self.report_destination(&dstname, Err(reason.clone()));
+                mail.attempt += 1;
+                let policy = &self.retry_policies[&dstname];
+                if !policy.exhausted(mail.attempt) {
+                    mail_log!(
+                        Info,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("retry"),
+                        "Queueing failed mail {} for retransmission",
+                        mail.id
+                    );
+                    let delay = policy.delay(mail.attempt);
+                    self.retry(&dstname, mail, reason, delay);
+                } else if let Some(dead_letter) = &policy.dead_letter {
+                    mail_log!(
+                        Warn,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("dead_letter"),
+                        "Giving up on mail {} for destination {} after {} attempts, handing it to {}: {}",
+                        mail.id,
+                        dstname,
+                        mail.attempt - 1,
+                        dead_letter,
+                        reason
+                    );
+                    self.complete_journal_entry(&dstname, &mail);
+                    mail.journaled = false;
+                    mail.attempt = 1;
+                    self.distribute(dead_letter, mail);
+                } else {
+                    mail_log!(
+                        Error,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("given_up"),
+                        "Giving up on mail {} for destination {} after {} attempts: {}",
+                        mail.id,
+                        dstname,
+                        mail.attempt - 1,
+                        reason
+                    );
+                    self.complete_journal_entry(&dstname, &mail);
+                    self.handle_permanent_failure(&dstname, mail, &reason);
+                }
+            }
+            HubMessage::SendingMailRejected {
+                dstname,
+                mail,
+                reason,
+            } => {
+                self.outstanding.delivered();
+                // the destination is reachable, the mail itself is the problem
+                self.report_destination(&dstname, Ok(()));
+                mail_log!(
+                    Warn,
+                    "MailHub",
+                    mail.log_fields().destination(&dstname).outcome("rejected"),
+                    "Mail {} was rejected by destination {}, will not try again: {}",
+                    mail.id,
+                    dstname,
+                    reason
+                );
+                self.complete_journal_entry(&dstname, &mail);
+                self.handle_permanent_failure(&dstname, mail, &reason);
             }
             HubMessage::RetryMail { dstname, mail } => {
-                info!(target: "MailHub", "Distributing Mail [retry] => {}", dstname);
-                self.hubchannel
-                    .queue_mail_for_sending(&dstname, mail)
-                    .expect("Failed to distribute mail");
+                let key = (dstname.clone(), mail.id.clone());
+                self.outstanding.retrying.borrow_mut().remove(&key);
+                self.retried.borrow_mut().insert(key);
+                if self.hubchannel.queues().saturated(&dstname) || self.is_parking(&dstname) {
+                    // the mail stays with the RetryAgent, instead of waiting in memory
+                    mail_log!(
+                        Info,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("deferred"),
+                        "Destination {} is saturated, deferring retry of mail {}",
+                        dstname,
+                        mail.id
+                    );
+                    let delay = self.retry_delay(&dstname, &mail).max(SATURATED_RETRY_DELAY);
+                    let reason = format!("Destination {} is saturated", dstname);
+                    self.retry(&dstname, mail, reason, delay);
+                } else {
+                    mail_log!(
+                        Info,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("queued"),
+                        "Distributing Mail {} [retry] => {} ({} queued)",
+                        mail.id,
+                        dstname,
+                        self.hubchannel.queues().depth(&dstname)
+                    );
+                    self.distribute(&dstname, mail);
+                }
+            }
+            HubMessage::QueueDrained { dstname } => {
+                self.unpark(&dstname);
             }
         }
         false
     }
 
-    pub fn run(&mut self) {
+    /// Log which destinations the mail would be delivered to
+    fn report_dry_run(&self, srcname: &str, mail: &Mail) {
+        let dstlist = self
+            .mappings
+            .get(srcname)
+            .map(|dstlist| self.skip_duplicates(srcname, mail, dstlist))
+            .unwrap_or_default();
+        let data = mail.data.bytes().unwrap_or_default();
+        let headers = MessageParser::default().parse_headers(data.as_ref());
+        let from = headers
+            .as_ref()
+            .and_then(|message| message.from())
+            .and_then(|from| from.first())
+            .and_then(|from| from.address());
+        let subject = headers.as_ref().and_then(|message| message.subject());
+        mail_log!(
+            Info,
+            "DryRun",
+            mail.log_fields().outcome("dry_run"),
+            "Mail from source {} in {}, from: {}, subject: {} ({} bytes) => {}",
+            srcname,
+            mail.mailbox().unwrap_or("-"),
+            from.unwrap_or("-"),
+            subject.unwrap_or("-"),
+            mail.data.len(),
+            match dstlist.is_empty() {
+                true => "no destinations".to_owned(),
+                false => dstlist.join(", "),
+            }
+        );
+    }
+
+    /// Re-queue the mails that were in flight when idlemail stopped
+    async fn recover_journal(&self) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        let recovered = match journal.recover() {
+            Ok(recovered) => recovered,
+            Err(e) => {
+                error!(target: "MailHub", "Failed to recover journal: {:#}", e);
+                return;
+            }
+        };
+        for recovered_mail in recovered {
+            let mail = self.hubchannel.spiller.spill(recovered_mail.mail).await;
+            for dstname in recovered_mail.pending {
+                let mail = mail.clone();
+                if self.destination_agents.contains_key(&dstname) {
+                    mail_log!(
+                        Info,
+                        "MailHub",
+                        mail.log_fields().destination(&dstname).outcome("queued"),
+                        "Distributing Mail {} [journal] => {}",
+                        mail.id,
+                        dstname
+                    );
+                    self.distribute(&dstname, mail);
+                } else {
+                    warn!(
+                        target: "MailHub",
+                        "Destination {} of journaled mail {} no longer exists, dropping it",
+                        dstname,
+                        mail.id
+                    );
+                    self.complete_journal_entry(&dstname, &mail);
+                }
+            }
+        }
+    }
+
+    /// Open the dedup database. A dry run uses an in-memory database instead, so the
+    /// configured one is neither created nor modified.
+    fn open_dedup(&mut self) {
+        let Some(config) = &self.dedup_config else {
+            return;
+        };
+        let config = DedupConfig {
+            path: config.path.clone().filter(|_| !self.dry_run),
+            ..config.clone()
+        };
+        self.dedup = match Deduplicator::new(&config) {
+            Ok(dedup) => Some(dedup),
+            Err(e) => {
+                error!(target: "MailHub", "Failed to open dedup database, deduplication is disabled: {:#}", e);
+                None
+            }
+        };
+    }
+
+    /// Start the destinations, the RetryAgent and the given sources as tasks
+    async fn start(&mut self, sources: &[String]) -> anyhow::Result<()> {
         info!(target: "MailHub", "Starting.");
-        for (dst_name, dst) in &mut self.destination_agents {
-            info!(target: "MailHub", "Starting destination: {}", dst_name);
-            let comm = self.hubchannel.get_destination_channel(dst_name.clone());
-            dst.start(comm);
+        self.open_dedup();
+        if self.dry_run {
+            warn!(target: "MailHub", "Dry run, mails are only reported and not delivered");
+            for src_name in sources {
+                self.start_agent(&AgentId::Source(src_name.clone()));
+            }
+            return Ok(());
         }
-        if let Some(ref mut retryagent) = self.retryagent {
-            info!(target: "MailHub", "Starting retryagent");
-            let comm = self.hubchannel.get_retryagent_channel();
-            retryagent.start(comm);
+        if let Some(retryagent) = &mut self.retryagent {
+            retryagent.open()?;
+        }
+        self.hubchannel.spiller.clean();
+        let destinations: Vec<_> = self.destination_agents.keys().cloned().collect();
+        for dst_name in &destinations {
+            self.start_agent(&AgentId::Destination(dst_name.clone()));
         }
-        for (src_name, src) in &mut self.source_agents {
-            info!(target: "MailHub", "Starting source: {}", src_name);
-            let comm = self.hubchannel.get_source_channel(src_name.clone());
-            src.start(comm);
+        self.start_agent(&AgentId::RetryAgent);
+        self.recover_journal().await;
+        for src_name in sources {
+            self.start_agent(&AgentId::Source(src_name.clone()));
         }
+        Ok(())
+    }
+
+    /// Start all agents as tasks and distribute mails, until the hub is told to stop.
+    /// Agents that stop in the meantime are restarted.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let sources: Vec<_> = self.source_agents.keys().cloned().collect();
+        self.start(&sources).await?;
 
         info!(target: "MailHub", "Starting distribution loop");
         loop {
-            let msg = self.hubchannel.next();
+            let msg = self.hubchannel.next().await;
             if self.handle_message(msg) {
                 break;
             }
         }
         info!(target: "MailHub", "Exited distribution loop");
+        self.shutdown().await;
+        Ok(())
+    }
+
+    /// Fetch the mails of the given sources once, and distribute them until all of them are
+    /// delivered or given up on, the deadline passed, or the hub is told to stop.
+    pub async fn run_once(
+        &mut self,
+        sources: &[String],
+        deadline: Duration,
+    ) -> anyhow::Result<RunOnceResult> {
+        self.once = true;
+        self.start(sources).await?;
+        let deadline = Instant::now() + deadline;
+
+        info!(target: "MailHub", "Starting distribution loop");
+        loop {
+            let fetching = sources.iter().any(|src_name| {
+                self.watchers
+                    .contains_key(&AgentId::Source(src_name.clone()))
+            });
+            if !fetching && self.outstanding.pending() == 0 {
+                info!(target: "MailHub", "All mails are handled");
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match await_timeout(remaining, self.hubchannel.next()).await {
+                Ok(msg) => {
+                    if self.handle_message(msg) {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    warn!(
+                        target: "MailHub",
+                        "Deadline passed, {} mails are not delivered yet",
+                        self.outstanding.pending()
+                    );
+                    break;
+                }
+            }
+        }
+        info!(target: "MailHub", "Exited distribution loop");
+        // destinations finish their queued mails while shutting down
+        self.shutdown().await;
+
+        let failed_sources = sources
+            .iter()
+            .filter(|src_name| {
+                let state = self
+                    .supervisor
+                    .state(&AgentId::Source(src_name.to_string()));
+                matches!(state, Some(AgentState::Degraded | AgentState::Failed))
+            })
+            .cloned()
+            .collect();
+        Ok(RunOnceResult {
+            undelivered: self.outstanding.undelivered.get() + self.outstanding.pending(),
+            failed_sources,
+        })
+    }
+
+    async fn shutdown(&mut self) {
         info!(target: "MailHub", "Shutting down");
+        self.stopping = true;
+        let sources: Vec<_> = self.source_agents.keys().cloned().collect();
+        let destinations: Vec<_> = self.destination_agents.keys().cloned().collect();
 
         // Shutdown procedure
         // ####################
 
         // First, we suspend the sources to stop the stream of new mails incomming
         self.hubchannel.shutdown_sources();
-        for (src_name, src) in &mut self.source_agents {
-            src.join();
+        for src_name in &sources {
+            self.join_agent(&AgentId::Source(src_name.clone())).await;
             info!(target: "MailHub", "Source: {} stopped", src_name);
         }
 
         // Then, we suspend the retry-agent, so it does still take incomming mails to-be
         // retried, but it does not actually schedule them (send them to the hub).
-        self.hubchannel.suspend_retryagent();
-
         // Wait for retryagent to confirm suspension and handle all messages until then
         // (there might still be some resubmissions sent to destinations here)
-        loop {
-            let msg = self.hubchannel.next();
-            if self.handle_message(msg) {
-                break;
+        // A retryagent waiting for its restart is started right away, to take the mails for retry.
+        if !self.watchers.contains_key(&AgentId::RetryAgent) && !self.dry_run {
+            self.start_agent(&AgentId::RetryAgent);
+        }
+        if self.watchers.contains_key(&AgentId::RetryAgent) {
+            self.hubchannel.suspend_retryagent();
+            loop {
+                let msg = self.hubchannel.next().await;
+                if self.handle_message(msg) {
+                    break;
+                }
             }
         }
 
+        // Mails waiting for a saturated destination are not delivered anymore
+        self.release_parked();
+
         // The destinations can now finish the mails they have queued (which might schedule
         // new mails in the retryagents), but no new mails are queued into destinations to send.
+        // Destinations waiting for their restart are started right away, to take their mails.
+        for dst_name in &destinations {
+            let agent = AgentId::Destination(dst_name.clone());
+            if !self.watchers.contains_key(&agent) && !self.dry_run {
+                self.start_agent(&agent);
+            }
+        }
         self.hubchannel.shutdown_destinations();
-        for (dst_name, dst) in &mut self.destination_agents {
-            dst.join();
+        for dst_name in &destinations {
+            self.join_agent(&AgentId::Destination(dst_name.clone()))
+                .await;
             info!(target: "MailHub", "Destination: {} stopped", dst_name);
         }
 
@@ -413,13 +1894,360 @@ impl MailHub {
 
         // Last, the retryagent is shutdown.
         self.hubchannel.shutdown_retryagent();
-        if let Some(retryagent) = &mut self.retryagent {
-            retryagent.join();
+        if self.retryagent.is_some() {
+            self.join_agent(&AgentId::RetryAgent).await;
             info!(target: "MailHub", "Retryagent stopped");
         }
+        self.supervisor.stopped().await;
     }
 
     pub fn get_stop_sender(&self) -> HubStopSender {
         self.hubchannel.get_stop_channel()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_queue_backpressure() {
+        let dstnames = vec!["dst0".to_owned(), "dst1".to_owned()];
+        let queues = Arc::new(QueueMonitor::new(
+            &dstnames,
+            HashMap::from([("dst0".to_owned(), 2)]),
+        ));
+        for _ in 0..2 {
+            queues.push("dst0");
+            queues.push("dst1");
+        }
+        assert!(queues.saturated("dst0"));
+        assert!(!queues.saturated("dst1"));
+        assert!(!task::block_on(
+            queues.wait_for_capacity(&dstnames, Duration::from_millis(10))
+        ));
+        assert!(task::block_on(
+            queues.wait_for_capacity(&dstnames[1..], Duration::from_millis(10))
+        ));
+
+        // a waiting source is woken up, once the destination took a mail
+        let waiter = {
+            let queues = queues.clone();
+            let dstnames = dstnames.clone();
+            task::spawn(async move {
+                queues
+                    .wait_for_capacity(&dstnames, Duration::from_secs(10))
+                    .await
+            })
+        };
+        task::block_on(task::sleep(Duration::from_millis(50)));
+        queues.pop("dst0");
+        assert!(task::block_on(waiter));
+        assert_eq!(queues.depth("dst0"), 1);
+        assert_eq!(queues.depth("dst1"), 2);
+    }
+
+    #[test]
+    fn test_sources_spill_large_mails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut hubchannel = HubChannel::new(Default::default()).with_spill(Some(SpillConfig {
+            threshold: 16,
+            path: dir.path().to_string_lossy().to_string(),
+        }));
+        let source = hubchannel.get_source_channel("src".to_owned(), vec![], false, false);
+        let spilled: Vec<_> = [b"Subject: A\r\n\r\n".to_vec(), vec![b'x'; 1024]]
+            .into_iter()
+            .map(|body| {
+                let notified = source.notify_new_mail(Mail::from_rfc822("src".to_owned(), body));
+                let received = async {
+                    match hubchannel.next().await {
+                        HubMessage::NewMail { mail, accepted, .. } => {
+                            let _ = accepted.send(true);
+                            mail.data.is_spilled()
+                        }
+                        _ => panic!("mail was not handed to the hub"),
+                    }
+                };
+                let (accepted, spilled) = task::block_on(futures::future::join(notified, received));
+                assert!(accepted);
+                spilled
+            })
+            .collect();
+        assert_eq!(spilled, vec![false, true]);
+    }
+
+    #[test]
+    fn test_queue_survives_destination_restart() {
+        let mut hubchannel = HubChannel::new(Default::default());
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\n".to_vec());
+        let crashed = hubchannel.get_destination_channel("dst".to_owned());
+        drop(crashed);
+        hubchannel
+            .queue_mail_for_sending("dst", mail.clone())
+            .unwrap();
+        let restarted = hubchannel.get_destination_channel("dst".to_owned());
+        match task::block_on(restarted.next()) {
+            Ok(DestinationMessage::Mail { mail: received }) => assert_eq!(received.id, mail.id),
+            Err(_) => panic!("queued mail was lost"),
+        }
+
+        // unknown destinations and missing RetryAgents hand the mail back
+        assert!(hubchannel
+            .queue_mail_for_sending("unknown", mail.clone())
+            .is_err());
+        hubchannel.shutdown_retryagent();
+        assert!(hubchannel
+            .queue_mail_for_retry("dst".to_owned(), mail, String::new(), Duration::ZERO)
+            .is_err());
+    }
+
+    #[test_case(1, 0; "delivered after a retry")]
+    #[test_case(100, 1; "retried until the deadline")]
+    fn test_run_once(fail_n_first: u16, undelivered: usize) {
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": {} }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "retryagent": {{ "type": "memory", "delay": 1 }}
+            }}"#,
+            fail_n_first
+        ))
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        let result =
+            task::block_on(hub.run_once(&["src".to_owned()], Duration::from_secs(3))).unwrap();
+        assert_eq!(result.undelivered, undelivered);
+        assert!(result.failed_sources.is_empty());
+    }
+
+    #[test]
+    fn test_crashed_destination_is_restarted() {
+        let config: ConfigContainer = serde_json::from_str(
+            r#"{
+                "destinations": { "dst": { "type": "test", "fail_n_first": 0, "panic_n_first": 1 } },
+                "sources": { "src": { "type": "test", "delay": 0, "interval": 1 } },
+                "mappings": { "src": ["dst"] }
+            }"#,
+        )
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        let result =
+            task::block_on(hub.run_once(&["src".to_owned()], Duration::from_secs(5))).unwrap();
+        // the restarted destination delivered the mail its predecessor crashed on
+        assert_eq!(result.undelivered, 0);
+        assert_eq!(
+            hub.supervisor
+                .state(&AgentId::Destination("dst".to_owned())),
+            Some(AgentState::Running)
+        );
+    }
+
+    #[test_case(r#""type": "memory""#, 1; "until the retry with a memory retryagent")]
+    #[test_case(r#""type": "filesystem", "path": "{}""#, 0; "until stored by a persistent retryagent")]
+    fn test_journal_keeps_mails_for_retry(retryagent: &str, journaled: usize) {
+        let (journal, retry) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let retryagent = retryagent.replace("{}", &retry.path().display().to_string());
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": 100 }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "retryagent": {{ {}, "delay": 60 }},
+                "journal": {{ "path": "{}" }}
+            }}"#,
+            retryagent,
+            journal.path().display()
+        ))
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        let result =
+            task::block_on(hub.run_once(&["src".to_owned()], Duration::from_secs(1))).unwrap();
+        assert_eq!(result.undelivered, 1);
+        // only a persistent RetryAgent takes over the responsibility for the mail
+        let recovered = Journal::new(config.journal.as_ref().unwrap())
+            .recover()
+            .unwrap();
+        assert_eq!(recovered.len(), journaled);
+    }
+
+    #[test]
+    fn test_dedup_remembers_delivered_mails() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": 0 }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "journal": {{ "path": "{}" }},
+                "dedup": {{ "window": 3600, "path": "{}" }}
+            }}"#,
+            dir.path().join("missing").display(),
+            dir.path().join("dedup.db").display()
+        ))
+        .unwrap();
+        let mail = Mail::from_rfc822(
+            "src".to_owned(),
+            b"Message-ID: <1@example.org>\r\n\r\nBody".to_vec(),
+        );
+        // hand the mail to a new hub, returns whether it was distributed
+        let fetch = |deliver: bool| {
+            let mut hub = MailHub::from_config(&config);
+            hub.open_dedup();
+            let dst = hub.hubchannel.get_destination_channel("dst".to_owned());
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "src".to_owned(),
+                mail: mail.clone(),
+                accepted: oneshot::channel().0,
+            });
+            let distributed = dst.recv.try_recv().is_ok();
+            if deliver {
+                hub.handle_message(HubMessage::SendingMailSucceeded {
+                    dstname: "dst".to_owned(),
+                    mail: mail.clone(),
+                });
+            }
+            distributed
+        };
+        // the journal write fails, and idlemail crashes before the delivery
+        assert!(fetch(false));
+        // the re-fetched mail is no duplicate
+        assert!(fetch(true));
+        assert!(!fetch(false));
+    }
+
+    #[test]
+    fn test_sources_keep_unrecorded_mails() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = dir.path().join("journal");
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": 0 }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "journal": {{ "path": "{}" }}
+            }}"#,
+            journal.display()
+        ))
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        // hand a new mail to the hub, returns whether the source may delete it
+        let mut notify = || {
+            let (accepted, answer) = oneshot::channel();
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "src".to_owned(),
+                mail: Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\n".to_vec()),
+                accepted,
+            });
+            task::block_on(answer).unwrap()
+        };
+        // the journal folder is missing, the mail can not be recorded
+        assert!(!notify());
+        fs::create_dir(&journal).unwrap();
+        assert!(notify());
+    }
+
+    #[test]
+    fn test_saturated_destination() {
+        let config: ConfigContainer = serde_json::from_str(
+            r#"{
+                "destinations": { "dst": { "type": "test", "fail_n_first": 0, "queue_depth": 1 } },
+                "sources": { "src": { "type": "test", "delay": 0, "interval": 1 } },
+                "mappings": { "src": ["dst"] },
+                "retryagent": { "type": "memory", "delay": 1 }
+            }"#,
+        )
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        let dst = hub.hubchannel.get_destination_channel("dst".to_owned());
+        let mails: Vec<_> = (0..3)
+            .map(|i| Mail::from_rfc822("src".to_owned(), format!("Subject: {}\r\n\r\n", i).into()))
+            .collect();
+        for mail in &mails[..2] {
+            hub.handle_message(HubMessage::NewMail {
+                srcname: "src".to_owned(),
+                mail: mail.clone(),
+                accepted: oneshot::channel().0,
+            });
+        }
+        // the second mail waits in the hub
+        assert_eq!(dst.recv.len(), 1);
+        assert!(hub.is_parking("dst"));
+        assert_eq!(hub.outstanding.pending(), 2);
+
+        // retries stay with the RetryAgent, while the destination is saturated
+        hub.handle_message(HubMessage::RetryMail {
+            dstname: "dst".to_owned(),
+            mail: mails[2].clone(),
+        });
+        let retryagent = hub.hubchannel.get_retryagent_channel().unwrap();
+        match retryagent.recv.try_recv() {
+            Ok(RetryAgentMessage::QueueMail { mail, delay, .. }) => {
+                assert_eq!(mail.id, mails[2].id);
+                assert!(delay >= SATURATED_RETRY_DELAY);
+            }
+            _ => panic!("retry was not deferred"),
+        }
+
+        // the parked mail follows, once the destination took the first one
+        task::block_on(async {
+            for mail in &mails[..2] {
+                match dst.next().await {
+                    Ok(DestinationMessage::Mail { mail: received }) => {
+                        assert_eq!(received.id, mail.id)
+                    }
+                    Err(_) => panic!("mail was lost"),
+                }
+                while hub.is_parking("dst") {
+                    let msg = hub.hubchannel.next().await;
+                    hub.handle_message(msg);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_retryagent_storage_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": 0 }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "retryagent": {{ "type": "sqlite", "delay": 1, "path": "{}" }}
+            }}"#,
+            dir.path().join("missing").join("retry.db").display()
+        ))
+        .unwrap();
+        let mut hub = MailHub::from_config(&config);
+        // no source is started, that would hand over mails
+        assert!(task::block_on(hub.run_once(&["src".to_owned()], Duration::from_secs(3))).is_err());
+        assert!(hub.watchers.is_empty());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let journal = tempfile::tempdir().unwrap();
+        let config: ConfigContainer = serde_json::from_str(&format!(
+            r#"{{
+                "destinations": {{ "dst": {{ "type": "test", "fail_n_first": 100 }} }},
+                "sources": {{ "src": {{ "type": "test", "delay": 0, "interval": 1 }} }},
+                "mappings": {{ "src": ["dst"] }},
+                "retryagent": {{ "type": "memory", "delay": 1 }},
+                "journal": {{ "path": "{}" }},
+                "dedup": {{ "window": 3600, "path": "{}" }}
+            }}"#,
+            journal.path().display(),
+            journal.path().join("dedup.db").display()
+        ))
+        .unwrap();
+        let mut hub = MailHub::from_config(&config).dry_run(true);
+        let result =
+            task::block_on(hub.run_once(&["src".to_owned()], Duration::from_secs(3))).unwrap();
+        // the mail was neither delivered, nor handed to the RetryAgent
+        assert_eq!(result.undelivered, 0);
+        assert!(hub.supervisor.state(&AgentId::RetryAgent).is_none());
+        assert_eq!(fs::read_dir(journal.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    config::JournalConfig,
+    hub::Mail,
+    storage::{append_extension, sync_dir, write_atomic, write_atomic_from, TMP_EXTENSION},
+};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const FORMAT_VERSION: u32 = 1;
+const META_EXTENSION: &str = "meta";
+const MAIL_EXTENSION: &str = "eml";
+const DONE_EXTENSION: &str = "done";
+/// Subfolder that unreadable entries are moved to
+const CORRUPT_FOLDER: &str = "corrupt";
+
+/// Metadata of a journal entry. The mail itself is stored in a separate file next to it,
+/// the destinations that finished the mail are appended to a third file, one per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct JournalEntryModel {
+    pub version: u32,
+    pub srcname: String,
+    /// Only written by older versions, replaced by `metadata`
+    #[serde(default, skip_serializing)]
+    pub mailbox: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub destinations: Vec<String>,
+}
+
+/// A mail that was found in the journal, with the destinations that did not finish it.
+pub struct RecoveredMail {
+    pub mail: Mail,
+    pub pending: Vec<String>,
+}
+
+/// Write-ahead journal of the mails in flight between the hub and the destinations.
+///
+/// Every new mail is persisted before it is distributed to the destinations. Once all
+/// destinations finished the mail (delivered, rejected or handed to the RetryAgent),
+/// the entry is removed. Entries that remain after a crash are recovered on the next start.
+pub struct Journal {
+    log_target: String,
+    path: PathBuf,
+    /// Destinations that did not yet finish the mail, per entry
+    pending: Mutex<HashMap<String, HashSet<String>>>,
+}
+impl Journal {
+    pub fn new(config: &JournalConfig) -> Self {
+        Self {
+            log_target: "Journal".to_string(),
+            path: PathBuf::from(&config.path),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn file_base(&self, entry: &str) -> PathBuf {
+        self.path.join(entry)
+    }
+
+    /// Persist the mail, before it is distributed to the given destinations.
+    /// The journal entry is named after the mail's id.
+    pub fn record(&self, srcname: &str, mail: &Mail, destinations: &[String]) -> Result<()> {
+        let file_base = self.file_base(&mail.id);
+        let meta = serde_json::to_vec(&JournalEntryModel {
+            version: FORMAT_VERSION,
+            srcname: srcname.to_owned(),
+            mailbox: None,
+            metadata: mail.metadata.clone(),
+            destinations: destinations.to_vec(),
+        })?;
+        // the metadata is written last, an entry without it was never completely written
+        write_atomic_from(
+            &append_extension(&file_base, MAIL_EXTENSION),
+            mail.data.reader()?,
+        )?;
+        write_atomic(&append_extension(&file_base, META_EXTENSION), &meta)?;
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(mail.id.clone(), destinations.iter().cloned().collect());
+        debug!(target: &self.log_target, "Recorded mail {}", mail.id);
+        Ok(())
+    }
+
+    /// Record that the destination finished the mail of the given entry.
+    /// The entry is removed, once all its destinations finished.
+    pub fn complete(&self, entry: &str, dstname: &str) -> Result<()> {
+        let finished = {
+            let mut pending = self.pending.lock().unwrap();
+            let destinations = match pending.get_mut(entry) {
+                Some(destinations) => destinations,
+                None => return Ok(()),
+            };
+            destinations.remove(dstname);
+            let finished = destinations.is_empty();
+            if finished {
+                pending.remove(entry);
+            }
+            finished
+        };
+
+        let file_base = self.file_base(entry);
+        if finished {
+            Self::remove(&file_base)?;
+            debug!(target: &self.log_target, "Removed finished entry {}", entry);
+        } else {
+            let mut done = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(append_extension(&file_base, DONE_EXTENSION))?;
+            done.write_all(format!("{}\n", dstname).as_bytes())?;
+            done.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn remove(file_base: &Path) -> Result<()> {
+        // The metadata is removed first, so an interrupted removal never leaves an
+        // entry without its mail behind.
+        fs::remove_file(append_extension(file_base, META_EXTENSION))?;
+        for extension in [MAIL_EXTENSION, DONE_EXTENSION] {
+            let path = append_extension(file_base, extension);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        sync_dir(file_base)
+    }
+
+    fn load_entry(&self, meta_path: &Path) -> Result<(String, RecoveredMail)> {
+        let meta: JournalEntryModel =
+            serde_json::from_slice(&fs::read(meta_path).context("Failed to read metadata")?)
+                .context("Failed to parse metadata")?;
+        if meta.version != FORMAT_VERSION {
+            return Err(anyhow!("Unsupported format version {}", meta.version));
+        }
+        let file_base = meta_path.with_extension("");
+        let entry = file_base
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid entry name"))?
+            .to_owned();
+        let data = fs::read(append_extension(&file_base, MAIL_EXTENSION))
+            .context("Failed to read mail")?;
+        // Only complete lines count. A partial line is the remain of an interrupted write.
+        let done =
+            fs::read_to_string(append_extension(&file_base, DONE_EXTENSION)).unwrap_or_default();
+        let done: HashSet<&str> = done
+            .split_inclusive('\n')
+            .filter_map(|line| line.strip_suffix('\n'))
+            .collect();
+
+        let mut mail = Mail::from_rfc822(meta.srcname, data)
+            .with_id(entry.clone())
+            .with_metadata(meta.metadata);
+        if let Some(mailbox) = meta.mailbox {
+            mail = mail.with_mailbox(mailbox);
+        }
+        mail.journaled = true;
+        let pending = meta
+            .destinations
+            .into_iter()
+            .filter(|dstname| !done.contains(dstname.as_str()))
+            .collect();
+        Ok((entry, RecoveredMail { mail, pending }))
+    }
+
+    /// Move the files of an entry that can not be read into the quarantine folder.
+    fn quarantine(&self, file_base: &Path, reason: &anyhow::Error) {
+        error!(
+            target: &self.log_target,
+            "Failed to recover journal entry: {}\n{:#}\nMoving it to: {}",
+            file_base.display(),
+            reason,
+            self.path.join(CORRUPT_FOLDER).display()
+        );
+        for extension in [META_EXTENSION, MAIL_EXTENSION, DONE_EXTENSION] {
+            let path = append_extension(file_base, extension);
+            if path.exists() {
+                self.quarantine_file(&path);
+            }
+        }
+    }
+
+    fn quarantine_file(&self, path: &Path) {
+        let corrupt_folder = self.path.join(CORRUPT_FOLDER);
+        let target = corrupt_folder.join(path.file_name().unwrap_or_default());
+        if let Err(e) = fs::create_dir_all(&corrupt_folder).and_then(|_| fs::rename(path, &target))
+        {
+            error!(
+                target: &self.log_target,
+                "Failed to move journal file to {}: {}",
+                target.display(),
+                e
+            );
+        }
+    }
+
+    /// Whether the entry that the file belongs to has its metadata
+    fn has_metadata(&self, path: &Path) -> bool {
+        let mut file_base = path.to_path_buf();
+        if file_base.extension() == Some(TMP_EXTENSION.as_ref()) {
+            file_base = file_base.with_extension("");
+        }
+        append_extension(&file_base.with_extension(""), META_EXTENSION).exists()
+    }
+
+    /// Load all entries that were not finished before the last shutdown.
+    /// Entries that can not be recovered are skipped.
+    pub fn recover(&self) -> Result<Vec<RecoveredMail>> {
+        let mut paths = fs::read_dir(&self.path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut recovered = Vec::new();
+        for path in &paths {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(META_EXTENSION) => match self.load_entry(path) {
+                    // all destinations finished, but the entry was not removed anymore
+                    Ok((entry, mail)) if mail.pending.is_empty() => {
+                        if let Err(e) = Self::remove(&path.with_extension("")) {
+                            error!(
+                                target: &self.log_target,
+                                "Failed to remove finished entry {}: {:#}", entry, e
+                            );
+                        }
+                    }
+                    Ok((entry, mail)) => {
+                        info!(
+                            target: &self.log_target,
+                            "Recovered mail {} for: {}",
+                            mail.mail.id,
+                            mail.pending.join(", ")
+                        );
+                        self.pending
+                            .lock()
+                            .unwrap()
+                            .insert(entry, mail.pending.iter().cloned().collect());
+                        recovered.push(mail);
+                    }
+                    Err(e) => self.quarantine(&path.with_extension(""), &e),
+                },
+                // Remains of interrupted writes or removals. Mails without metadata were never
+                // distributed, but the source might have deleted them already: they are kept.
+                Some(MAIL_EXTENSION) | Some(TMP_EXTENSION) if !self.has_metadata(path) => {
+                    warn!(
+                        target: &self.log_target,
+                        "Incomplete journal file: {}, moving it to: {}",
+                        path.display(),
+                        self.path.join(CORRUPT_FOLDER).display()
+                    );
+                    self.quarantine_file(path);
+                }
+                Some(DONE_EXTENSION) if !self.has_metadata(path) => {
+                    warn!(
+                        target: &self.log_target,
+                        "Removing incomplete journal file: {}",
+                        path.display()
+                    );
+                    if let Err(e) = fs::remove_file(path) {
+                        error!(
+                            target: &self.log_target,
+                            "Failed to remove {}: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(dir: &Path) -> Journal {
+        Journal::new(&JournalConfig {
+            path: dir.to_string_lossy().to_string(),
+        })
+    }
+
+    #[test]
+    fn test_record_and_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec())
+            .with_mailbox("INBOX".to_owned())
+            .with_metadata(BTreeMap::from([("uid".to_owned(), "42".to_owned())]));
+        let destinations = vec!["dst0".to_owned(), "dst1".to_owned(), "dst2".to_owned()];
+        {
+            let journal = journal(dir.path());
+            journal.record("src", &mail, &destinations).unwrap();
+            journal.complete(&mail.id, "dst1").unwrap();
+            // simulate a crash while recording the next mail
+            fs::write(
+                dir.path().join("01ARZ3NDEKTSV4RRFFQ69G5FAV.eml.tmp"),
+                b"Subject: Te",
+            )
+            .unwrap();
+        }
+
+        let journal = journal(dir.path());
+        let recovered = journal.recover().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].mail.id, mail.id);
+        assert_eq!(recovered[0].mail.data, mail.data);
+        assert_eq!(recovered[0].mail.metadata, mail.metadata);
+        let mut pending = recovered[0].pending.clone();
+        pending.sort();
+        assert_eq!(pending, vec!["dst0", "dst2"]);
+
+        let entry = recovered[0].mail.id.clone();
+        journal.complete(&entry, "dst0").unwrap();
+        journal.complete(&entry, "dst2").unwrap();
+        // the incomplete mail is kept, the source might have deleted it already
+        let files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec![CORRUPT_FOLDER]);
+        assert_eq!(
+            fs::read(
+                dir.path()
+                    .join(CORRUPT_FOLDER)
+                    .join("01ARZ3NDEKTSV4RRFFQ69G5FAV.eml.tmp")
+            )
+            .unwrap(),
+            b"Subject: Te"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::hub::{metadata, Mail};
+use log::{
+    kv::{self, Key, VisitSource},
+    LevelFilter, Record,
+};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, including the structured fields of the event
+    Json,
+}
+
+pub fn init(format: LogFormat, dry_run: bool) {
+    let mut log_builder = pretty_env_logger::formatted_builder();
+    if dry_run {
+        // the report is the purpose of a dry run
+        log_builder.filter(Some("DryRun"), LevelFilter::Info);
+    }
+    if format == LogFormat::Json {
+        log_builder.format(format_json);
+    }
+
+    if let Ok(level) = std::env::var("RUST_LOG") {
+        log_builder.parse_filters(&level);
+    }
+    if let Ok(write_style) = std::env::var("RUST_LOG_STYLE") {
+        log_builder.parse_write_style(&write_style);
+    }
+
+    log_builder.init();
+}
+
+fn format_json<W: Write>(buf: &mut W, record: &Record) -> io::Result<()> {
+    let mut event = Map::new();
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    event.insert("timestamp".to_owned(), timestamp.into());
+    event.insert("level".to_owned(), record.level().as_str().into());
+    event.insert("target".to_owned(), record.target().into());
+    event.insert("message".to_owned(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut event));
+    writeln!(buf, "{}", Value::Object(event))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Structured fields of an event about a mail, to follow it from its source to its
+/// destinations
+pub struct MailFields<'a> {
+    mail: &'a Mail,
+    destination: Option<&'a str>,
+    outcome: Option<&'static str>,
+}
+impl<'a> MailFields<'a> {
+    pub fn new(mail: &'a Mail) -> Self {
+        Self {
+            mail,
+            destination: None,
+            outcome: None,
+        }
+    }
+
+    pub fn destination(mut self, destination: &'a str) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// What happened to the mail, e.g. `fetched`, `queued` or `delivered`
+    pub fn outcome(mut self, outcome: &'static str) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+}
+impl kv::Source for MailFields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        let mail = self.mail;
+        visitor.visit_pair("mail_id".into(), mail.id.as_str().into())?;
+        visitor.visit_pair("hash".into(), mail.hash.as_str().into())?;
+        if let Some(message_id) = &mail.message_id {
+            visitor.visit_pair("message_id".into(), message_id.as_ref().into())?;
+        }
+        visitor.visit_pair("source".into(), mail.from_src.as_str().into())?;
+        if let Some(destination) = self.destination {
+            visitor.visit_pair("destination".into(), destination.into())?;
+        }
+        if let Some(mailbox) = mail.mailbox() {
+            visitor.visit_pair("mailbox".into(), mailbox.into())?;
+        }
+        visitor.visit_pair("attempt".into(), mail.attempt.into())?;
+        if let Some(outcome) = self.outcome {
+            visitor.visit_pair("outcome".into(), outcome.into())?;
+        }
+        // time since the mail was fetched, across retries and restarts
+        let fetched = mail
+            .metadata
+            .get(metadata::FETCHED)
+            .and_then(|fetched| OffsetDateTime::parse(fetched, &Rfc3339).ok());
+        if let Some(fetched) = fetched {
+            let latency = (OffsetDateTime::now_utc() - fetched)
+                .whole_milliseconds()
+                .max(0);
+            visitor.visit_pair("latency_ms".into(), (latency as u64).into())?;
+        }
+        Ok(())
+    }
+}
+
+/// Structured fields of an event of a source that is not about a fetched mail, e.g. a
+/// mail that could not be fetched
+pub struct SourceFields<'a> {
+    source: &'a str,
+    mailbox: &'a str,
+    outcome: Option<&'static str>,
+}
+impl<'a> SourceFields<'a> {
+    pub fn new(source: &'a str, mailbox: &'a str) -> Self {
+        Self {
+            source,
+            mailbox,
+            outcome: None,
+        }
+    }
+
+    pub fn outcome(mut self, outcome: &'static str) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+}
+impl kv::Source for SourceFields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        visitor.visit_pair("source".into(), self.source.into())?;
+        visitor.visit_pair("mailbox".into(), self.mailbox.into())?;
+        if let Some(outcome) = self.outcome {
+            visitor.visit_pair("outcome".into(), outcome.into())?;
+        }
+        Ok(())
+    }
+}
+
+/// Log an event about a mail like `log::log!`, with the given `MailFields` attached
+macro_rules! mail_log {
+    ($level:ident, $target:expr, $fields:expr, $($arg:tt)+) => {
+        if log::log_enabled!(target: $target, log::Level::$level) {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(format_args!($($arg)+))
+                    .level(log::Level::$level)
+                    .target($target)
+                    .module_path_static(Some(module_path!()))
+                    .file_static(Some(file!()))
+                    .line(Some(line!()))
+                    .key_values(&$fields)
+                    .build(),
+            )
+        }
+    };
+}
+pub(crate) use mail_log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_format_json() {
+        let mail = Mail::from_rfc822(
+            "src".to_owned(),
+            b"Message-ID: <1@example.org>\r\nSubject: Test\r\n\r\nBody".to_vec(),
+        )
+        .with_mailbox("INBOX".to_owned())
+        .with_metadata(BTreeMap::from([(
+            metadata::FETCHED.to_owned(),
+            "2000-01-01T00:00:00Z".to_owned(),
+        )]));
+        let fields = MailFields::new(&mail)
+            .destination("dst")
+            .outcome("delivered");
+        let mut buf = Vec::new();
+        format_json(
+            &mut buf,
+            &Record::builder()
+                .args(format_args!("Mail {} delivered", mail.id))
+                .target("MailHub")
+                .key_values(&fields)
+                .build(),
+        )
+        .unwrap();
+        let event: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(event["target"], "MailHub");
+        assert_eq!(event["message"], format!("Mail {} delivered", mail.id));
+        assert_eq!(event["mail_id"], mail.id);
+        assert_eq!(event["hash"], mail.hash);
+        assert_eq!(event["message_id"], "1@example.org");
+        assert_eq!(event["source"], "src");
+        assert_eq!(event["destination"], "dst");
+        assert_eq!(event["mailbox"], "INBOX");
+        assert_eq!(event["attempt"], 1);
+        assert_eq!(event["outcome"], "delivered");
+        assert!(event["latency_ms"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_format_json_source_fields() {
+        let fields = SourceFields::new("src", "INBOX").outcome("fetch_failed");
+        let mut buf = Vec::new();
+        format_json(
+            &mut buf,
+            &Record::builder()
+                .args(format_args!("Failed to fetch unread mail from INBOX"))
+                .target("ImapIdle[src]")
+                .key_values(&fields)
+                .build(),
+        )
+        .unwrap();
+        let event: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(event["source"], "src");
+        assert_eq!(event["mailbox"], "INBOX");
+        assert_eq!(event["outcome"], "fetch_failed");
+        assert!(event.get("mail_id").is_none());
+    }
+}
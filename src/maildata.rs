@@ -0,0 +1,127 @@
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Extension of the files that mails are spilled to
+pub const SPILL_EXTENSION: &str = "spill";
+
+enum Storage {
+    Memory(Vec<u8>),
+    /// Spilled to a file, which is removed when the last reference is dropped
+    File {
+        path: PathBuf,
+        len: usize,
+    },
+}
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Storage::File { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Raw content of a mail.
+///
+/// The content is immutable and shared between all clones, so handing a mail to several
+/// destinations or the RetryAgent does not copy it. Large mails can be spilled to a file,
+/// to keep them out of memory while they are queued.
+#[derive(Clone)]
+pub struct MailData(Arc<Storage>);
+impl MailData {
+    pub fn len(&self) -> usize {
+        match &*self.0 {
+            Storage::Memory(data) => data.len(),
+            Storage::File { len, .. } => *len,
+        }
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(&*self.0, Storage::File { .. })
+    }
+
+    /// Get the content, which is read from its file if the mail was spilled.
+    /// Consumers that only copy the content elsewhere should use `reader` instead, so a
+    /// spilled mail is not brought back into memory.
+    pub fn bytes(&self) -> io::Result<Cow<'_, [u8]>> {
+        match &*self.0 {
+            Storage::Memory(data) => Ok(Cow::Borrowed(data)),
+            Storage::File { path, .. } => fs::read(path).map(Cow::Owned),
+        }
+    }
+
+    /// Stream the content, directly from its file if the mail was spilled.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send + '_>> {
+        match &*self.0 {
+            Storage::Memory(data) => Ok(Box::new(data.as_slice())),
+            Storage::File { path, .. } => Ok(Box::new(fs::File::open(path)?)),
+        }
+    }
+
+    /// Write the content to the given file, and return a MailData that refers to it.
+    /// The file is removed once the returned MailData and all its clones are dropped.
+    pub fn spill(&self, path: &Path) -> io::Result<Self> {
+        io::copy(&mut self.reader()?, &mut fs::File::create(path)?)?;
+        Ok(Self(Arc::new(Storage::File {
+            path: path.to_owned(),
+            len: self.len(),
+        })))
+    }
+}
+impl From<Vec<u8>> for MailData {
+    fn from(data: Vec<u8>) -> Self {
+        Self(Arc::new(Storage::Memory(data)))
+    }
+}
+impl PartialEq for MailData {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.bytes(), other.bytes()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+impl fmt::Debug for MailData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &*self.0 {
+            Storage::Memory(data) => write!(f, "MailData({} bytes)", data.len()),
+            Storage::File { path, len } => {
+                write!(f, "MailData({} bytes in {})", len, path.display())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("mail.{}", SPILL_EXTENSION));
+        let data = MailData::from(b"Subject: Test\r\n\r\nBody".to_vec());
+        let spilled = data.spill(&path).unwrap();
+        assert!(spilled.is_spilled());
+        assert_eq!(spilled.len(), data.len());
+        assert_eq!(spilled, data);
+
+        // the file is shared between clones and removed with the last one
+        let clone = spilled.clone();
+        drop(spilled);
+        assert!(path.exists());
+        assert_eq!(
+            clone.bytes().unwrap().as_ref(),
+            b"Subject: Test\r\n\r\nBody"
+        );
+        let mut streamed = Vec::new();
+        clone.reader().unwrap().read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, b"Subject: Test\r\n\r\nBody");
+        drop(clone);
+        assert!(!path.exists());
+    }
+}
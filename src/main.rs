@@ -1,42 +1,117 @@
 mod config;
+mod dedup;
 mod destinations;
+mod failure;
+mod fetchmailrc;
 mod hub;
+mod journal;
+mod logging;
+mod maildata;
 mod retryagents;
 mod sources;
+mod storage;
+mod supervisor;
 
-use log::{debug, error, info};
+use async_std::task;
+use clap::{Parser, Subcommand};
+use log::{debug, error, info, warn};
+use logging::LogFormat;
 use signal::{trap::Trap, Signal};
-use std::time::{Duration, Instant};
+use std::{
+    process,
+    time::{Duration, Instant},
+};
 
-fn init_logging() {
-    let mut log_builder = pretty_env_logger::formatted_builder();
-
-    if let Ok(level) = std::env::var("RUST_LOG") {
-        log_builder.parse_filters(&level);
-    }
-    if let Ok(write_style) = std::env::var("RUST_LOG_STYLE") {
-        log_builder.parse_write_style(&write_style);
-    }
-
-    log_builder.init();
+/// Forwards mails from IMAP accounts to other destinations
+#[derive(Parser)]
+#[command(
+    version,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file to run with, same as the `run` command
+    config: Option<String>,
+    /// Format of the log
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
 }
 
-fn main() {
-    init_logging();
+#[derive(Subcommand)]
+enum Command {
+    /// Run until terminated
+    Run {
+        config: String,
+        /// Only report the mails and their destinations, without delivering them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Fetch the mails of all or the selected sources once, deliver them and exit
+    Once {
+        config: String,
+        /// Only fetch the mails of this source, can be given multiple times
+        #[arg(short, long = "source", value_name = "NAME")]
+        sources: Vec<String>,
+        /// Seconds to deliver and retry the fetched mails, before exiting anyway
+        #[arg(short, long, default_value_t = 300)]
+        deadline: u64,
+        /// Only report the mails and their destinations, without delivering them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the configuration file and exit
+    CheckConfig { config: String },
+    /// Print the JSON schema of the configuration file, for autocompletion in editors
+    Schema,
+    /// Convert a fetchmailrc into an idlemail configuration, and report what has no equivalent
+    ImportFetchmailrc {
+        fetchmailrc: String,
+        /// File to write the configuration to, instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Connect to the source and count its unseen mails, without fetching them
+    TestSource { config: String, name: String },
+    /// Send a probe mail through the destination
+    TestDestination {
+        config: String,
+        name: String,
+        /// Seconds to wait for the outcome of the delivery
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u64,
+    },
+}
 
-    // dirty hack to read the config-file name from first argument for now
-    let config_file = std::env::args().skip(1).take(1).next().unwrap();
+// Exit codes. Invalid arguments exit with 2.
+const EXIT_CONFIG: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// A source failed to fetch its mails
+const EXIT_SOURCE_FAILED: i32 = 3;
+/// Mails were not delivered
+const EXIT_UNDELIVERED: i32 = 4;
+/// The hub failed to start, e.g. the RetryAgent's storage is not available
+const EXIT_STARTUP: i32 = 5;
 
+fn load_config(config_file: &str) -> config::ConfigContainer {
     info!(target: "Idlemail", "Parsing configuration file");
-    let config = match config::ConfigContainer::from_file(&config_file) {
-        Ok(config) => config,
+    match config::ConfigContainer::from_file(config_file) {
+        Ok(config) => {
+            for warning in config.warnings() {
+                warn!(target: "Idlemail", "{}", warning);
+            }
+            config
+        }
         Err(err) => {
-            error!(target: "Idlemail", "Failed to parse configuration file: {}\n{}", &config_file, err);
-            panic!();
+            error!(target: "Idlemail", "Failed to parse configuration file: {}\n{}", config_file, err);
+            process::exit(EXIT_CONFIG);
         }
-    };
-    let mut mailhub = hub::MailHub::from_config(&config);
+    }
+}
 
+/// Stop the hub on SIGINT and SIGTERM
+fn trap_signals(mailhub: &hub::MailHub) {
     #[cfg(target_os = "linux")]
     {
         debug!(target: "Idlemail", "Registering Signal traps (INT, TERM)");
@@ -55,6 +130,214 @@ fn main() {
             }
         });
     }
+}
+
+fn run(config_file: &str, dry_run: bool) -> i32 {
+    let config = load_config(config_file);
+    let mut mailhub = hub::MailHub::from_config(&config).dry_run(dry_run);
+    trap_signals(&mailhub);
+    match task::block_on(mailhub.run()) {
+        Ok(()) => 0,
+        Err(err) => {
+            error!(target: "Idlemail", "Failed to start: {:#}", err);
+            EXIT_STARTUP
+        }
+    }
+}
+
+fn once(config_file: &str, sources: Vec<String>, deadline: u64, dry_run: bool) -> i32 {
+    let config = load_config(config_file);
+    if let Some(unknown) = sources
+        .iter()
+        .find(|name| !config.sources.contains_key(*name))
+    {
+        error!(target: "Idlemail", "Unknown source: {}", unknown);
+        return EXIT_USAGE;
+    }
+    let sources = match sources.is_empty() {
+        true => config.sources.keys().cloned().collect(),
+        false => sources,
+    };
+    let mut mailhub = hub::MailHub::from_config(&config).dry_run(dry_run);
+    trap_signals(&mailhub);
+    let result = match task::block_on(mailhub.run_once(&sources, Duration::from_secs(deadline))) {
+        Ok(result) => result,
+        Err(err) => {
+            error!(target: "Idlemail", "Failed to start: {:#}", err);
+            return EXIT_STARTUP;
+        }
+    };
+    if result.undelivered > 0 {
+        error!(target: "Idlemail", "{} mails were not delivered", result.undelivered);
+        EXIT_UNDELIVERED
+    } else if !result.failed_sources.is_empty() {
+        error!(
+            target: "Idlemail",
+            "Failed to fetch the mails of: {}",
+            result.failed_sources.join(", ")
+        );
+        EXIT_SOURCE_FAILED
+    } else {
+        0
+    }
+}
+
+fn check_config(config_file: &str) -> i32 {
+    let config = match config::ConfigContainer::from_file(config_file) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Configuration is invalid:\n{}", err);
+            return EXIT_CONFIG;
+        }
+    };
+    for warning in config.warnings() {
+        println!("Warning: {}", warning);
+    }
+    println!(
+        "Configuration is valid: {} sources, {} destinations",
+        config.sources.len(),
+        config.destinations.len()
+    );
+    0
+}
 
-    mailhub.run();
+fn import_fetchmailrc(fetchmailrc: &str, output: Option<String>) -> i32 {
+    let text = match std::fs::read_to_string(fetchmailrc) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", fetchmailrc, e);
+            return EXIT_CONFIG;
+        }
+    };
+    let import = match fetchmailrc::import(&text) {
+        Ok(import) => import,
+        Err(e) => {
+            eprintln!("Failed to parse {}: {}", fetchmailrc, e);
+            return EXIT_CONFIG;
+        }
+    };
+    for unsupported in &import.unsupported {
+        eprintln!("Not imported: {}", unsupported);
+    }
+    let json = fetchmailrc::to_json(&import.config);
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(&output, json + "\n") {
+                eprintln!("Failed to write {}: {}", output, e);
+                return EXIT_CONFIG;
+            }
+        }
+        None => println!("{}", json),
+    }
+    0
+}
+
+fn test_source(config_file: &str, name: &str) -> i32 {
+    let config = load_config(config_file);
+    let Some(srccfg) = config.sources.get(name) else {
+        error!(target: "Idlemail", "Unknown source: {}", name);
+        return EXIT_USAGE;
+    };
+    if let config::SourceConfig::Test(_) = srccfg {
+        println!(
+            "Source {} is a test source, there is nothing to connect to",
+            name
+        );
+        return 0;
+    }
+    match task::block_on(sources::probe(srccfg)) {
+        Ok(mailboxes) if mailboxes.is_empty() => {
+            println!("Source {} has no mailboxes", name);
+            0
+        }
+        Ok(mailboxes) => {
+            println!("Source {}:", name);
+            let mut failed = false;
+            for (mailbox, unseen) in mailboxes {
+                match unseen {
+                    Ok(unseen) => println!("  {}: {} unseen", mailbox, unseen),
+                    Err(e) => {
+                        println!("  {}: failed to count unseen mails: {:#}", mailbox, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                EXIT_SOURCE_FAILED
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            println!("Source {} failed: {:#}", name, e);
+            EXIT_SOURCE_FAILED
+        }
+    }
+}
+
+fn test_destination(config_file: &str, name: &str, timeout: u64) -> i32 {
+    let config = load_config(config_file);
+    let Some(dstcfg) = config.destinations.get(name) else {
+        error!(target: "Idlemail", "Unknown destination: {}", name);
+        return EXIT_USAGE;
+    };
+    match task::block_on(destinations::probe(
+        name,
+        dstcfg,
+        Duration::from_secs(timeout),
+    )) {
+        Ok(()) => {
+            println!("Destination {} delivered the probe mail", name);
+            0
+        }
+        Err(reason) => {
+            println!("Destination {} failed: {}", name, reason);
+            EXIT_UNDELIVERED
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let command = match (cli.command, cli.config) {
+        (Some(command), _) => command,
+        (None, Some(config)) => Command::Run {
+            config,
+            dry_run: false,
+        },
+        (None, None) => unreachable!("clap requires arguments"),
+    };
+    logging::init(
+        cli.log_format,
+        matches!(
+            command,
+            Command::Run { dry_run: true, .. } | Command::Once { dry_run: true, .. }
+        ),
+    );
+
+    let code = match command {
+        Command::Run { config, dry_run } => run(&config, dry_run),
+        Command::Once {
+            config,
+            sources,
+            deadline,
+            dry_run,
+        } => once(&config, sources, deadline, dry_run),
+        Command::CheckConfig { config } => check_config(&config),
+        Command::ImportFetchmailrc {
+            fetchmailrc,
+            output,
+        } => import_fetchmailrc(&fetchmailrc, output),
+        Command::Schema => {
+            println!("{}", config::schema());
+            0
+        }
+        Command::TestSource { config, name } => test_source(&config, &name),
+        Command::TestDestination {
+            config,
+            name,
+            timeout,
+        } => test_destination(&config, &name, timeout),
+    };
+    process::exit(code);
 }
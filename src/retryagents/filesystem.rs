@@ -1,50 +1,155 @@
 use crate::{
     config::FilesystemRetryAgentConfig,
     hub::{Mail, MailAgent, RetryAgentMessage},
+    logging::mail_log,
+    storage::{append_extension, sync_dir, write_atomic, write_atomic_from, TMP_EXTENSION},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use async_std::task;
 use log::{debug, error, info, warn};
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, HashMap, VecDeque},
     fs,
+    path::{Path, PathBuf},
     sync::mpsc,
-    thread,
     time::{Duration, SystemTime},
 };
 
 use super::MailRetryAgent;
 
+/// Version of the on-disk format written by this agent.
+/// 1: Single `.json` file containing metadata and mail
+/// 2: Raw mail in `.eml` file, metadata in `.meta` file
+/// 3: Adds the mail's id to the metadata, files are named after the id
+/// 4: Replaces the mail's mailbox with the map of metadata provided by the source
+const FORMAT_VERSION: u32 = 4;
+/// Oldest version of the `.meta` format that can still be read
+const MIN_META_VERSION: u32 = 2;
+const META_EXTENSION: &str = "meta";
+const MAIL_EXTENSION: &str = "eml";
+const LEGACY_EXTENSION: &str = "json";
+/// Subfolder that unreadable files are moved to
+const CORRUPT_FOLDER: &str = "corrupt";
+
+/// Format version 1
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
-struct QueuedRetryMailModel {
+struct LegacyRetryMailModel {
     pub due_time: SystemTime,
     pub dstname: String,
     pub mail_from_src: String,
     pub mail_data: Vec<u8>,
+    #[serde(default)]
+    pub mail_mailbox: Option<String>,
+    #[serde(default = "default_attempt")]
+    pub mail_attempt: u32,
+}
+fn default_attempt() -> u32 {
+    1
+}
+
+/// Metadata of a queued mail. The mail itself is stored in a separate file next to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RetryMailMetaModel {
+    pub version: u32,
+    pub due_time: SystemTime,
+    pub dstname: String,
+    /// Missing in version 2
+    #[serde(default)]
+    pub mail_id: Option<String>,
+    pub mail_from_src: String,
+    /// Versions 2 and 3, replaced by `mail_metadata`
+    #[serde(default, skip_serializing)]
+    pub mail_mailbox: Option<String>,
+    /// Missing before version 4
+    #[serde(default)]
+    pub mail_metadata: BTreeMap<String, String>,
+    pub mail_attempt: u32,
 }
-impl From<&QueuedRetryMail> for QueuedRetryMailModel {
+impl From<&QueuedRetryMail> for RetryMailMetaModel {
     fn from(retry_mail: &QueuedRetryMail) -> Self {
         Self {
+            version: FORMAT_VERSION,
             due_time: retry_mail.due_time,
             dstname: retry_mail.dstname.clone(),
+            mail_id: Some(retry_mail.mail.id.clone()),
             mail_from_src: retry_mail.mail.from_src.clone(),
-            mail_data: retry_mail.mail.data.clone(),
+            mail_mailbox: None,
+            mail_metadata: retry_mail.mail.metadata.clone(),
+            mail_attempt: retry_mail.mail.attempt,
         }
     }
 }
 
+/// Only used to determine the version of a metadata file, before parsing it completely.
+#[derive(Deserialize)]
+struct VersionModel {
+    pub version: u32,
+}
+
 struct QueuedRetryMail {
     pub due_time: SystemTime,
     pub dstname: String,
     pub mail: Mail,
-    pub file_path: String,
+    /// Path of the files without extension
+    pub file_base: PathBuf,
+}
+
+/// Store the mail and its metadata. The metadata is written last, so it is only present
+/// once the mail was completely written.
+fn store(retry_mail: &QueuedRetryMail) -> Result<()> {
+    let meta = serde_json::to_vec(&RetryMailMetaModel::from(retry_mail))?;
+    write_atomic_from(
+        &append_extension(&retry_mail.file_base, MAIL_EXTENSION),
+        retry_mail.mail.data.reader()?,
+    )?;
+    write_atomic(
+        &append_extension(&retry_mail.file_base, META_EXTENSION),
+        &meta,
+    )
+}
+
+/// Remove the mail's files. The metadata is removed first, so an interrupted removal never
+/// leaves metadata without a mail behind.
+fn remove(file_base: &Path) -> Result<()> {
+    let meta_path = append_extension(file_base, META_EXTENSION);
+    fs::remove_file(&meta_path)
+        .with_context(|| format!("Failed to delete {}", meta_path.display()))?;
+    let mail_path = append_extension(file_base, MAIL_EXTENSION);
+    fs::remove_file(&mail_path)
+        .with_context(|| format!("Failed to delete {}", mail_path.display()))?;
+    sync_dir(file_base)
+}
+
+/// Percent-encode everything but ASCII alphanumerics, `.`, `-` and `_`, so a name can not
+/// leave the folder (e.g. `../dst` or `a/b`). The name itself is kept in the metadata.
+fn escape_file_name(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Find an unused path (without extension) for the given mail in the folder.
+fn free_file_base(folder: &str, mail: &Mail, dstname: &str) -> Option<PathBuf> {
+    let dstname = escape_file_name(dstname);
+    // try 10 append-indices, in case the mail is queued for the destination more than once
+    (0..10)
+        .map(|i| Path::new(folder).join(format!("{}_to_{}-{}", mail.id, dstname, i)))
+        .find(|base| {
+            !append_extension(base, META_EXTENSION).exists()
+                && !append_extension(base, MAIL_EXTENSION).exists()
+        })
 }
 
 pub struct FilesystemRetryAgent {
     log_target: String,
     config: FilesystemRetryAgentConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl FilesystemRetryAgent {
     pub fn new(config: &FilesystemRetryAgentConfig) -> Self {
@@ -55,41 +160,136 @@ impl FilesystemRetryAgent {
         }
     }
 
+    /// Move a file that can not be read into the quarantine folder, so it is not lost.
+    fn quarantine(&self, path: &Path, reason: &anyhow::Error) {
+        let corrupt_folder = Path::new(&self.config.path).join(CORRUPT_FOLDER);
+        let target = corrupt_folder.join(path.file_name().unwrap_or_default());
+        error!(
+            target: &self.log_target,
+            "Failed to load retry-file: {}\n{:#}\nMoving it to: {}",
+            path.display(),
+            reason,
+            target.display()
+        );
+        if let Err(e) = fs::create_dir_all(&corrupt_folder).and_then(|_| fs::rename(path, &target))
+        {
+            error!(
+                target: &self.log_target,
+                "Failed to move retry-file to {}: {}",
+                target.display(),
+                e
+            );
+        }
+    }
+
+    fn load_mail(&self, meta_path: &Path) -> Result<QueuedRetryMail> {
+        let meta_data = fs::read(meta_path).context("Failed to read metadata")?;
+        let version: VersionModel =
+            serde_json::from_slice(&meta_data).context("Failed to parse metadata")?;
+        if !(MIN_META_VERSION..=FORMAT_VERSION).contains(&version.version) {
+            return Err(anyhow!("Unsupported format version {}", version.version));
+        }
+        let meta: RetryMailMetaModel =
+            serde_json::from_slice(&meta_data).context("Failed to parse metadata")?;
+        let file_base = meta_path.with_extension("");
+        let mail_data = fs::read(append_extension(&file_base, MAIL_EXTENSION))
+            .context("Failed to read mail")?;
+        let mut mail =
+            Mail::from_rfc822(meta.mail_from_src, mail_data).with_metadata(meta.mail_metadata);
+        if let Some(id) = meta.mail_id {
+            mail = mail.with_id(id);
+        }
+        if let Some(mailbox) = meta.mail_mailbox {
+            mail = mail.with_mailbox(mailbox);
+        }
+        mail.attempt = meta.mail_attempt;
+        Ok(QueuedRetryMail {
+            due_time: meta.due_time,
+            dstname: meta.dstname,
+            mail,
+            file_base,
+        })
+    }
+
+    /// Convert a file of format version 1 to the current format
+    fn migrate_legacy(&self, legacy_path: &Path) -> Result<QueuedRetryMail> {
+        let legacy: LegacyRetryMailModel =
+            serde_json::from_slice(&fs::read(legacy_path).context("Failed to read file")?)
+                .context("Failed to parse file")?;
+        let mut mail = Mail::from_rfc822(legacy.mail_from_src, legacy.mail_data);
+        if let Some(mailbox) = legacy.mail_mailbox {
+            mail = mail.with_mailbox(mailbox);
+        }
+        mail.attempt = legacy.mail_attempt;
+        let file_base = free_file_base(&self.config.path, &mail, &legacy.dstname)
+            .ok_or_else(|| anyhow!("No free filename available"))?;
+        let retry_mail = QueuedRetryMail {
+            due_time: legacy.due_time,
+            dstname: legacy.dstname,
+            mail,
+            file_base,
+        };
+        store(&retry_mail)?;
+        fs::remove_file(legacy_path).context("Failed to delete migrated file")?;
+        info!(
+            target: &self.log_target,
+            "Migrated retry-file: {} to format version {}",
+            legacy_path.display(),
+            FORMAT_VERSION
+        );
+        Ok(retry_mail)
+    }
+
     fn load_from_fs(&self) -> Result<Vec<QueuedRetryMail>> {
-        let mail_files: Vec<QueuedRetryMail> = fs::read_dir(&self.config.path)?
-			.filter_map(|file| {
-				let file_path = file.ok()?.path();
-				let file_path_str = file_path.to_str()?.to_owned();
-				if !file_path_str.ends_with(".json") {
-					return None;
-				}
-				let file_reader = match fs::File::open(file_path) {
-					Ok(file_reader) => file_reader,
-					Err(e) => {
-						error!(target: &self.log_target, "Failed to open retry-file: {}\n{}", file_path_str, e);
-						return None;
-					}
-				};
-				let retry_mail: QueuedRetryMailModel = serde_json::from_reader(file_reader).ok()?;
-				info!(target: &self.log_target, "Successfully parsed retry-file: {}", file_path_str);
-				Some(QueuedRetryMail {
-					due_time: retry_mail.due_time,
-					dstname: retry_mail.dstname,
-					mail: Mail::from_rfc822(retry_mail.mail_from_src, retry_mail.mail_data),
-					file_path: file_path_str
-				})
-			})
-			.collect();
-        Ok(mail_files)
+        let mut paths = fs::read_dir(&self.config.path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut mails = Vec::new();
+        for path in &paths {
+            let result = match path.extension().and_then(|e| e.to_str()) {
+                Some(META_EXTENSION) => self.load_mail(path),
+                Some(LEGACY_EXTENSION) => self.migrate_legacy(path),
+                // Remains of an interrupted write, which may contain an incomplete mail
+                Some(TMP_EXTENSION) => Err(anyhow!("Incomplete write")),
+                Some(MAIL_EXTENSION) => {
+                    if append_extension(&path.with_extension(""), META_EXTENSION).exists() {
+                        continue;
+                    }
+                    Err(anyhow!("Mail without metadata"))
+                }
+                _ => continue,
+            };
+            match result {
+                Ok(retry_mail) => {
+                    info!(
+                        target: &self.log_target,
+                        "Successfully loaded retry-file: {}",
+                        path.display()
+                    );
+                    mails.push(retry_mail);
+                }
+                Err(e) => {
+                    self.quarantine(path, &e);
+                    // keep the mail belonging to broken metadata together with it
+                    let mail_path = append_extension(&path.with_extension(""), MAIL_EXTENSION);
+                    if path.extension().and_then(|e| e.to_str()) == Some(META_EXTENSION)
+                        && mail_path.exists()
+                    {
+                        self.quarantine(&mail_path, &e);
+                    }
+                }
+            }
+        }
+        Ok(mails)
     }
 }
 impl MailAgent for FilesystemRetryAgent {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailRetryAgent for FilesystemRetryAgent {
@@ -111,63 +311,128 @@ impl MailRetryAgent for FilesystemRetryAgent {
             }
         };
 
-        self.worker = Some(thread::spawn(move || {
+        self.worker = Some(task::spawn(async move {
             // We depend on the VecDequeue to be sorted by ascending due-time
             restored_mails.sort_by_key(|rm| rm.due_time);
             let mut queue: VecDeque<QueuedRetryMail> = VecDeque::from(restored_mails);
+            // files of the mails handed back to the hub by destination and id, they are kept
+            // until the hub reports the outcome of the mail as final
+            let mut handed_back: HashMap<(String, String), PathBuf> = HashMap::new();
+            channel.health.report_healthy();
 
             let mut suspended = false;
 
             loop {
-                match channel.next_timeout(Duration::from_secs(1)) {
+                match channel.next_timeout(Duration::from_secs(1)).await {
                     Err(mpsc::RecvTimeoutError::Timeout) => {}
                     Err(mpsc::RecvTimeoutError::Disconnected) => break, // shutdown
-                    Ok(RetryAgentMessage::QueueMail { dstname, mail }) => {
-                        let retransmission_timepoint =
-                            SystemTime::now() + Duration::from_secs(config.delay);
-                        info!(
-                            target: &log_target,
-                            "Queueing mail {} for retransmission in {}s", mail.hash, config.delay
+                    Ok(RetryAgentMessage::QueueMail {
+                        dstname,
+                        mail,
+                        reason,
+                        delay,
+                    }) => {
+                        let retransmission_timepoint = SystemTime::now() + delay;
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&dstname),
+                            "Queueing mail {} for retransmission in {}s (failed with: {})",
+                            mail.id,
+                            delay.as_secs(),
+                            reason
                         );
 
-                        // construct QueuedRetryMail structure, and attempt to find a non-taken filename
-                        // for it in our designated filesystem path.
-                        let mut retry_mail = QueuedRetryMail {
-                            due_time: retransmission_timepoint,
-                            dstname,
-                            mail,
-                            file_path: "".to_owned(),
+                        // a mail that failed again replaces the files it was handed back from
+                        let previous = handed_back.remove(&(dstname.clone(), mail.id.clone()));
+                        // filesystem access blocks, keep it off the executor
+                        let path = config.path.clone();
+                        let stored = task::spawn_blocking(move || {
+                            // find a non-taken filename for it in our designated filesystem path.
+                            let file_base =
+                                previous.or_else(|| free_file_base(&path, &mail, &dstname));
+                            let Some(file_base) = file_base else {
+                                return Err(Box::new((dstname, mail)));
+                            };
+                            let retry_mail = QueuedRetryMail {
+                                due_time: retransmission_timepoint,
+                                dstname,
+                                mail,
+                                file_base,
+                            };
+                            let stored = store(&retry_mail);
+                            Ok((retry_mail, stored))
+                        })
+                        .await;
+                        let (mut retry_mail, stored) = match stored {
+                            Ok(stored) => stored,
+                            Err(lost) => {
+                                let (dstname, mail) = *lost;
+                                mail_log!(
+                                    Error,
+                                    &log_target,
+                                    mail.log_fields().destination(&dstname).outcome("lost"),
+                                    "No free filename for mail {}. It is permanently lost.",
+                                    mail.id
+                                );
+                                continue;
+                            }
                         };
-                        for i in 0..10 {
-                            // try 10 append-indices against hash-collision
-                            let file_name = format!(
-                                "{}/{}_to_{}-{}.json",
-                                config.path, &retry_mail.mail.hash, retry_mail.dstname, i
-                            );
-                            if let Ok(retry_file) = fs::File::create(&file_name) {
-                                retry_mail.file_path = file_name.clone();
-                                match serde_json::to_writer(
-                                    retry_file,
-                                    &QueuedRetryMailModel::from(&retry_mail),
-                                ) {
-                                    Ok(_) => {
-                                        debug!(
-                                            target: &log_target,
-                                            "Stored retry-mail in: {}", retry_mail.file_path
-                                        );
-                                        queue.push_back(retry_mail);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        error!(
-                                            target: &log_target,
-                                            "Failed to create retry-mail file: {}\n{}",
-                                            retry_mail.file_path,
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                }
+                        match stored {
+                            Ok(_) => {
+                                debug!(
+                                    target: &log_target,
+                                    "Stored retry-mail in: {}",
+                                    retry_mail.file_base.display()
+                                );
+                                channel.confirm_stored(&retry_mail.dstname, &retry_mail.mail);
+                                retry_mail.mail.journaled = false;
+                                channel.health.report_healthy();
+                            }
+                            Err(e) => {
+                                mail_log!(
+                                    Error,
+                                    &log_target,
+                                    retry_mail.mail.log_fields().destination(&retry_mail.dstname),
+                                    "Failed to store retry-mail {}. It will be lost on restart.\n{:#}",
+                                    retry_mail.mail.id,
+                                    e
+                                );
+                                channel
+                                    .health
+                                    .report_degraded(format!("Failed to store mails: {:#}", e));
+                            }
+                        }
+                        // keep the queue sorted by due time, delays differ between destinations
+                        let index = queue.partition_point(|rm| rm.due_time <= retry_mail.due_time);
+                        queue.insert(index, retry_mail);
+                    }
+                    Ok(RetryAgentMessage::Completed { dstname, id }) => {
+                        let file_base = match handed_back.remove(&(dstname.clone(), id.clone())) {
+                            Some(file_base) => Some(file_base),
+                            // handed back by the predecessor of a restarted agent, that loaded
+                            // the mail again
+                            None => queue
+                                .iter()
+                                .position(|rm| rm.dstname == dstname && rm.mail.id == id)
+                                .and_then(|index| queue.remove(index))
+                                .map(|rm| rm.file_base),
+                        };
+                        if let Some(file_base) = file_base {
+                            let removed = {
+                                let file_base = file_base.clone();
+                                task::spawn_blocking(move || remove(&file_base)).await
+                            };
+                            match removed {
+                                Ok(()) => debug!(
+                                    target: &log_target,
+                                    "Deleted retry-mail files: {}",
+                                    file_base.display()
+                                ),
+                                Err(e) => warn!(
+                                    target: &log_target,
+                                    "Failed to delete retry-mail files:\n{:#}", e
+                                ),
                             }
                         }
                     }
@@ -184,24 +449,20 @@ impl MailRetryAgent for FilesystemRetryAgent {
                     while !queue.is_empty() {
                         if queue.front().unwrap().due_time < now {
                             let mail = queue.pop_front().unwrap();
-                            info!(
-                                target: &log_target,
-                                "Mail {} due for retransmission. Queueing.", mail.mail.hash
+                            mail_log!(
+                                Info,
+                                &log_target,
+                                mail.mail.log_fields().destination(&mail.dstname),
+                                "Mail {} due for retransmission. Queueing.",
+                                mail.mail.id
                             );
-                            channel.notify_retry_mail(mail.dstname, mail.mail);
-                            if let Err(e) = fs::remove_file(&mail.file_path) {
-                                warn!(
-                                    target: &log_target,
-                                    "Failed to delete retry-mail file: {}\n{}", mail.file_path, e
-                                );
-                            } else {
-                                debug!(
-                                    target: &log_target,
-                                    "Deleted retry-mail file: {}", mail.file_path
-                                );
-                            }
+                            handed_back.insert(
+                                (mail.dstname.clone(), mail.mail.id.clone()),
+                                mail.file_base,
+                            );
+                            channel.notify_retry_mail(mail.dstname, mail.mail).await;
                         } else {
-                            // The mails are stored in the order in which they were queued.
+                            // The mails are sorted by their due time.
                             // If the first isn't due, neither is every mail behind that.
                             break;
                         }
@@ -212,3 +473,163 @@ impl MailRetryAgent for FilesystemRetryAgent {
         }));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::{HubChannel, HubMessage};
+
+    fn agent(dir: &Path) -> FilesystemRetryAgent {
+        FilesystemRetryAgent::new(&FilesystemRetryAgentConfig {
+            delay: 1,
+            path: dir.to_string_lossy().to_string(),
+        })
+    }
+
+    fn mail() -> Mail {
+        let mut mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\nBody".to_vec())
+            .with_mailbox("INBOX".to_owned())
+            .with_metadata(BTreeMap::from([("uid".to_owned(), "42".to_owned())]));
+        mail.attempt = 2;
+        mail
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mail = mail();
+        let retry_mail = QueuedRetryMail {
+            due_time: SystemTime::now(),
+            dstname: "dst.with.dots".to_owned(),
+            file_base: free_file_base(dir.path().to_str().unwrap(), &mail, "dst.with.dots")
+                .unwrap(),
+            mail,
+        };
+        store(&retry_mail).unwrap();
+
+        let loaded = agent(dir.path()).load_from_fs().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].file_base, retry_mail.file_base);
+        assert_eq!(loaded[0].dstname, "dst.with.dots");
+        assert_eq!(loaded[0].due_time, retry_mail.due_time);
+        assert_eq!(loaded[0].mail.id, retry_mail.mail.id);
+        assert_eq!(loaded[0].mail.data, retry_mail.mail.data);
+        assert_eq!(loaded[0].mail.metadata, retry_mail.mail.metadata);
+        assert_eq!(loaded[0].mail.attempt, 2);
+
+        remove(&retry_mail.file_base).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_files_kept_until_completed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut hubchannel = HubChannel::new(Default::default());
+        let mut retryagent = agent(dir.path());
+        retryagent.start(hubchannel.get_retryagent_channel().unwrap());
+        let mail = mail();
+        hubchannel
+            .queue_mail_for_retry(
+                "dst".to_owned(),
+                mail.clone(),
+                String::new(),
+                Duration::ZERO,
+            )
+            .unwrap();
+        task::block_on(async {
+            loop {
+                if let HubMessage::RetryMail { mail: retried, .. } = hubchannel.next().await {
+                    assert_eq!(retried.id, mail.id);
+                    break;
+                }
+            }
+        });
+        // a crash before the outcome of the retry is final does not lose the mail
+        let loaded = agent(dir.path()).load_from_fs().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].mail.id, mail.id);
+
+        hubchannel.complete_retry("dst".to_owned(), mail.id.clone());
+        hubchannel.shutdown_retryagent();
+        task::block_on(retryagent.join().unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_escaped_dstname() {
+        let dir = tempfile::tempdir().unwrap();
+        let mail = mail();
+        let folder = dir.path().join("retry");
+        fs::create_dir(&folder).unwrap();
+        let retry_mail = QueuedRetryMail {
+            due_time: SystemTime::now(),
+            dstname: "../dst/a b%".to_owned(),
+            file_base: free_file_base(folder.to_str().unwrap(), &mail, "../dst/a b%").unwrap(),
+            mail,
+        };
+        assert_eq!(retry_mail.file_base.parent(), Some(folder.as_path()));
+        assert!(retry_mail
+            .file_base
+            .to_str()
+            .unwrap()
+            .ends_with("_to_..%2Fdst%2Fa%20b%25-0"));
+        store(&retry_mail).unwrap();
+
+        let loaded = agent(&folder).load_from_fs().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].dstname, "../dst/a b%");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mail = mail();
+        let legacy_path = dir.path().join(format!("{}_to_dst-0.json", mail.hash));
+        let legacy = LegacyRetryMailModel {
+            due_time: SystemTime::now(),
+            dstname: "dst".to_owned(),
+            mail_from_src: mail.from_src.clone(),
+            mail_data: mail.data.bytes().unwrap().into_owned(),
+            mail_mailbox: Some("INBOX".to_owned()),
+            mail_attempt: 3,
+        };
+        fs::write(&legacy_path, serde_json::to_vec(&legacy).unwrap()).unwrap();
+
+        let loaded = agent(dir.path()).load_from_fs().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].mail.data, mail.data);
+        assert_eq!(loaded[0].mail.mailbox(), Some("INBOX"));
+        assert_eq!(loaded[0].mail.attempt, 3);
+        assert!(!legacy_path.exists());
+        assert!(append_extension(&loaded[0].file_base, MAIL_EXTENSION).exists());
+        assert!(append_extension(&loaded[0].file_base, META_EXTENSION).exists());
+    }
+
+    #[test]
+    fn test_quarantine_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("broken-0.json"), b"{\"due_time\":").unwrap();
+        fs::write(dir.path().join("broken-1.meta"), b"{\"version\":99}").unwrap();
+        fs::write(dir.path().join("broken-1.eml"), b"Subject: Test\r\n\r\n").unwrap();
+        fs::write(dir.path().join("broken-2.eml"), b"Subject: Test\r\n\r\n").unwrap();
+        fs::write(dir.path().join("broken-3.eml.tmp"), b"Subject: Te").unwrap();
+
+        assert!(agent(dir.path()).load_from_fs().unwrap().is_empty());
+        let mut quarantined: Vec<_> = fs::read_dir(dir.path().join(CORRUPT_FOLDER))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        quarantined.sort();
+        assert_eq!(
+            quarantined,
+            vec![
+                "broken-0.json",
+                "broken-1.eml",
+                "broken-1.meta",
+                "broken-2.eml",
+                "broken-3.eml.tmp"
+            ]
+        );
+    }
+}
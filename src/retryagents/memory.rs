@@ -1,12 +1,13 @@
 use crate::{
     config::MemoryRetryAgentConfig,
     hub::{Mail, MailAgent, RetryAgentMessage},
+    logging::mail_log,
 };
-use log::{info, warn};
+use async_std::task;
+use log::{info, trace};
 use std::{
     collections::VecDeque,
     sync::mpsc,
-    thread,
     time::{Duration, SystemTime},
 };
 
@@ -15,7 +16,7 @@ use super::MailRetryAgent;
 pub struct MemoryRetryAgent {
     log_target: String,
     config: MemoryRetryAgentConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl MemoryRetryAgent {
     pub fn new(config: &MemoryRetryAgentConfig) -> Self {
@@ -27,47 +28,61 @@ impl MemoryRetryAgent {
     }
 }
 impl MailAgent for MemoryRetryAgent {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailRetryAgent for MemoryRetryAgent {
     fn start(&mut self, channel: crate::hub::HubRetryAgentChannel) {
-        let config = self.config.clone();
         let log_target = self.log_target.clone();
+        trace!(target: &log_target, "Using Configuration:\n{:?}", self.config);
 
-        self.worker = Some(thread::spawn(move || {
+        self.worker = Some(task::spawn(async move {
+            channel.health.report_healthy();
             let mut queue: VecDeque<(SystemTime, String, Mail)> = VecDeque::new();
 
             let mut suspended = false;
 
             loop {
-                match channel.next_timeout(Duration::from_secs(1)) {
+                match channel.next_timeout(Duration::from_secs(1)).await {
                     Err(mpsc::RecvTimeoutError::Timeout) => {}
                     Err(mpsc::RecvTimeoutError::Disconnected) => {
                         // shutdown
-                        if !queue.is_empty() {
-                            warn!(
-                                target: &log_target,
-                                "There were {} mails queued for retry. These are permanently lost.",
-                                queue.len()
+                        for (_, dstname, mail) in &queue {
+                            mail_log!(
+                                Warn,
+                                &log_target,
+                                mail.log_fields().destination(dstname).outcome("lost"),
+                                "Mail {} was queued for retry. It is permanently lost.",
+                                mail.id
                             );
                         }
                         break;
                     }
-                    Ok(RetryAgentMessage::QueueMail { dstname, mail }) => {
-                        let retransmission_timepoint =
-                            SystemTime::now() + Duration::from_secs(config.delay);
-                        info!(
-                            target: &log_target,
-                            "Queueing mail for retransmission in {}s", config.delay
+                    Ok(RetryAgentMessage::QueueMail {
+                        dstname,
+                        mail,
+                        reason,
+                        delay,
+                    }) => {
+                        let retransmission_timepoint = SystemTime::now() + delay;
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&dstname),
+                            "Queueing mail {} for retransmission in {}s (failed with: {})",
+                            mail.id,
+                            delay.as_secs(),
+                            reason
                         );
-                        queue.push_back((retransmission_timepoint, dstname, mail));
+                        // keep the queue sorted by due time, delays differ between destinations
+                        let index = queue.partition_point(|(due_time, _, _)| {
+                            *due_time <= retransmission_timepoint
+                        });
+                        queue.insert(index, (retransmission_timepoint, dstname, mail));
                     }
+                    // the mail handed back is not kept, there is nothing to let go of
+                    Ok(RetryAgentMessage::Completed { .. }) => {}
                     Ok(RetryAgentMessage::Suspend) => {
                         info!(target: &log_target, "Suspending");
                         suspended = true;
@@ -78,19 +93,19 @@ impl MailRetryAgent for MemoryRetryAgent {
                 if !suspended {
                     // see if any of the queued mails is due
                     let now = SystemTime::now();
-                    for i in 0..queue.len() {
-                        if queue.get(i).unwrap().0 < now {
-                            info!(
-                                target: &log_target,
-                                "Mail due for retransmission. Queueing."
-                            );
-                            let mail = queue.pop_front().unwrap();
-                            channel.notify_retry_mail(mail.1, mail.2)
-                        } else {
-                            // The mails are stored in the order in which they were queued.
-                            // If the first isn't due, neither is every mail behind that.
-                            break;
-                        }
+                    while queue
+                        .front()
+                        .is_some_and(|(due_time, _, _)| *due_time < now)
+                    {
+                        let (_, dstname, mail) = queue.pop_front().unwrap();
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&dstname),
+                            "Mail {} due for retransmission. Queueing.",
+                            mail.id
+                        );
+                        channel.notify_retry_mail(dstname, mail).await;
                     }
                 }
             }
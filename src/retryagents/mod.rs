@@ -1,8 +1,99 @@
-use crate::hub::{HubRetryAgentChannel, MailAgent};
+use crate::{
+    config::RetryPolicyConfig,
+    hub::{HubRetryAgentChannel, MailAgent},
+};
+use std::time::Duration;
+
+/// Upper bound of every retry delay, also keeps the computed delay within `Duration`'s range
+const MAX_DELAY: u64 = 24 * 60 * 60;
 
 pub mod filesystem;
 pub mod memory;
+pub mod sqlite;
 
 pub trait MailRetryAgent: MailAgent {
+    /// Open the agent's storage, once before it is started.
+    /// The hub does not start without a RetryAgent that can store the mails for retry.
+    fn open(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn start(&mut self, channel: HubRetryAgentChannel);
 }
+
+/// Retry behaviour of a destination, resolved from its `retry` configuration
+/// and the RetryAgent's settings.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    delay: u64,
+    backoff: f64,
+    max_delay: Option<u64>,
+    max_attempts: Option<u32>,
+    pub dead_letter: Option<String>,
+}
+impl RetryPolicy {
+    pub fn new(
+        default_delay: u64,
+        default_max_attempts: Option<u32>,
+        config: Option<&RetryPolicyConfig>,
+    ) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            delay: config.delay.unwrap_or(default_delay),
+            backoff: config.backoff.unwrap_or(1.0),
+            max_delay: config.max_delay,
+            max_attempts: config.max_attempts.or(default_max_attempts),
+            dead_letter: config.dead_letter,
+        }
+    }
+
+    /// Delay before the given delivery attempt, where attempt 2 is the first retry
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let retries = attempt.saturating_sub(2).min(i32::MAX as u32) as i32;
+        let max_delay = self.max_delay.unwrap_or(MAX_DELAY).min(MAX_DELAY) as f64;
+        let delay = self.delay as f64 * self.backoff.powi(retries);
+        // NaN, if the backoff is not a number
+        Duration::from_secs_f64(if delay.is_nan() {
+            max_delay
+        } else {
+            delay.min(max_delay)
+        })
+    }
+
+    /// Whether the given delivery attempt exceeds the maximum amount of attempts
+    pub fn exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(None, 2 => 60)]
+    #[test_case(None, 5 => 60)]
+    #[test_case(Some(RetryPolicyConfig { backoff: Some(2.0), ..Default::default() }), 2 => 60)]
+    #[test_case(Some(RetryPolicyConfig { backoff: Some(2.0), ..Default::default() }), 4 => 240)]
+    #[test_case(Some(RetryPolicyConfig { delay: Some(10), backoff: Some(3.0), max_delay: Some(100), ..Default::default() }), 5 => 100)]
+    #[test_case(Some(RetryPolicyConfig { backoff: Some(10.0), ..Default::default() }), u32::MAX => MAX_DELAY)]
+    #[test_case(Some(RetryPolicyConfig { backoff: Some(f64::INFINITY), ..Default::default() }), 3 => MAX_DELAY)]
+    #[test_case(Some(RetryPolicyConfig { delay: Some(u64::MAX), max_delay: Some(u64::MAX), ..Default::default() }), 2 => MAX_DELAY)]
+    fn test_delay(config: Option<RetryPolicyConfig>, attempt: u32) -> u64 {
+        RetryPolicy::new(60, None, config.as_ref())
+            .delay(attempt)
+            .as_secs()
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let config = RetryPolicyConfig {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        let policy = RetryPolicy::new(60, Some(5), Some(&config));
+        assert!(!policy.exhausted(3));
+        assert!(policy.exhausted(4));
+        assert!(!RetryPolicy::new(60, None, None).exhausted(1000));
+        assert!(RetryPolicy::new(60, Some(5), None).exhausted(6));
+    }
+}
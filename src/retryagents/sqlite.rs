@@ -0,0 +1,578 @@
+use crate::{
+    config::SqliteRetryAgentConfig,
+    hub::{Mail, MailAgent, RetryAgentMessage},
+    logging::mail_log,
+};
+use anyhow::{Context, Result};
+use async_std::task;
+use log::{debug, error, info, warn};
+use rusqlite::{params, Connection, MAIN_DB};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::MailRetryAgent;
+
+/// Maximum amount of due mails that are re-submitted per iteration
+const DEQUEUE_BATCH_SIZE: usize = 64;
+
+/// Schema of the retry queue. The table is meant to be queryable by external tooling,
+/// so columns are only ever added (see `MIGRATIONS`), never renamed or removed.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS retry_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        due_time INTEGER NOT NULL,
+        queued_time INTEGER NOT NULL,
+        dstname TEXT NOT NULL,
+        mail_from_src TEXT NOT NULL,
+        mail_mailbox TEXT,
+        mail_hash TEXT NOT NULL,
+        attempt INTEGER NOT NULL,
+        last_error TEXT,
+        mail_data BLOB NOT NULL,
+        mail_id TEXT,
+        mail_metadata TEXT,
+        in_flight INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS retry_queue_due_time ON retry_queue (due_time);
+    CREATE INDEX IF NOT EXISTS retry_queue_dstname ON retry_queue (dstname);
+";
+
+/// Columns added after the initial schema, with their definition
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("mail_id", "TEXT"),
+    ("mail_metadata", "TEXT"),
+    ("in_flight", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub struct QueuedRetryMail {
+    pub id: i64,
+    pub dstname: String,
+    pub mail: Mail,
+}
+
+/// Mail that could not be stored yet, it is kept in memory until it can be
+struct UnstoredRetryMail {
+    due_time: SystemTime,
+    dstname: String,
+    mail: Mail,
+    reason: String,
+}
+
+/// Persistent retry queue in an SQLite database
+pub struct RetryQueue {
+    connection: Connection,
+}
+impl RetryQueue {
+    pub fn open(path: &str) -> Result<Self> {
+        let connection = Connection::open(path).context("Failed to open database")?;
+        // WAL allows external tools to read the queue while we are writing to it
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .context("Failed to enable WAL mode")?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .context("Failed to set busy timeout")?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to create database schema")?;
+        Self::migrate(&connection).context("Failed to migrate database schema")?;
+        // the outcome of mails handed back by a previous run is unknown, they are retried again
+        connection
+            .execute(
+                "UPDATE retry_queue SET in_flight = 0 WHERE in_flight = 1",
+                [],
+            )
+            .context("Failed to reset mails in flight")?;
+        Ok(Self { connection })
+    }
+
+    /// Add the columns that databases created by older versions are missing
+    fn migrate(connection: &Connection) -> Result<()> {
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('retry_queue')")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (column, definition) in MIGRATIONS {
+            if !columns.iter().any(|c| c == column) {
+                connection.execute_batch(&format!(
+                    "ALTER TABLE retry_queue ADD COLUMN {} {}",
+                    column, definition
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue the mail. A mail that was handed back is queued again in place of its entry.
+    pub fn enqueue(
+        &self,
+        due_time: SystemTime,
+        dstname: &str,
+        mail: &Mail,
+        reason: &str,
+    ) -> Result<()> {
+        let requeued = self.connection.execute(
+            "UPDATE retry_queue SET due_time = ?1, attempt = ?2, last_error = ?3, in_flight = 0
+                WHERE dstname = ?4 AND mail_id = ?5 AND in_flight = 1",
+            params![unix_time(due_time), mail.attempt, reason, dstname, mail.id],
+        )?;
+        if requeued > 0 {
+            return Ok(());
+        }
+        // the mail is streamed into the row, so a spilled mail is not read into memory
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "INSERT INTO retry_queue
+                (due_time, queued_time, dstname, mail_from_src, mail_mailbox, mail_hash, attempt, last_error, mail_data, mail_id, mail_metadata)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ZEROBLOB(?9), ?10, ?11)",
+            params![
+                unix_time(due_time),
+                unix_time(SystemTime::now()),
+                dstname,
+                mail.from_src,
+                mail.mailbox(),
+                mail.hash,
+                mail.attempt,
+                reason,
+                mail.data.len() as i64,
+                mail.id,
+                serde_json::to_string(&mail.metadata)?,
+            ],
+        )?;
+        let mut blob = transaction.blob_open(
+            MAIN_DB,
+            c"retry_queue",
+            c"mail_data",
+            transaction.last_insert_rowid(),
+            false,
+        )?;
+        io::copy(&mut mail.data.reader()?, &mut blob)?;
+        blob.close()?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Get the mails that are due at the given time, ordered by their due time.
+    /// Mails in flight are skipped.
+    pub fn due(&self, now: SystemTime) -> Result<Vec<QueuedRetryMail>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, dstname, mail_from_src, mail_mailbox, attempt, mail_data, mail_id, mail_metadata
+                FROM retry_queue WHERE due_time <= ?1 AND in_flight = 0 ORDER BY due_time, id LIMIT ?2",
+        )?;
+        let rows =
+            statement.query_map(params![unix_time(now), DEQUEUE_BATCH_SIZE as i64], |row| {
+                let mut mail = Mail::from_rfc822(row.get(2)?, row.get(5)?);
+                if let Some(id) = row.get(6)? {
+                    mail = mail.with_id(id);
+                }
+                // rows queued by older versions only have the mailbox
+                let metadata: Option<String> = row.get(7)?;
+                match metadata.and_then(|m| serde_json::from_str(&m).ok()) {
+                    Some(metadata) => mail = mail.with_metadata(metadata),
+                    None => {
+                        if let Some(mailbox) = row.get(3)? {
+                            mail = mail.with_mailbox(mailbox);
+                        }
+                    }
+                }
+                mail.attempt = row.get(4)?;
+                Ok(QueuedRetryMail {
+                    id: row.get(0)?,
+                    dstname: row.get(1)?,
+                    mail,
+                })
+            })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Mark the entry as handed back to the hub, it is kept until the hub completes the mail.
+    /// The id of the mail is stored, as entries queued by older versions miss it.
+    pub fn hand_back(&self, id: i64, mail_id: &str) -> Result<()> {
+        self.connection.execute(
+            "UPDATE retry_queue SET in_flight = 1, mail_id = ?2 WHERE id = ?1",
+            params![id, mail_id],
+        )?;
+        Ok(())
+    }
+
+    /// Remove the entry of a mail handed back to the hub, once its outcome is final
+    pub fn complete(&self, dstname: &str, mail_id: &str) -> Result<()> {
+        self.connection.execute(
+            "DELETE FROM retry_queue WHERE dstname = ?1 AND mail_id = ?2 AND in_flight = 1",
+            params![dstname, mail_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        let count: i64 =
+            self.connection
+                .query_row("SELECT COUNT(*) FROM retry_queue", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+/// Store the mail in the queue, off the executor
+async fn store(
+    queue: &Arc<Mutex<RetryQueue>>,
+    unstored: UnstoredRetryMail,
+) -> (UnstoredRetryMail, Result<()>) {
+    let queue = queue.clone();
+    task::spawn_blocking(move || {
+        let result = queue.lock().unwrap().enqueue(
+            unstored.due_time,
+            &unstored.dstname,
+            &unstored.mail,
+            &unstored.reason,
+        );
+        (unstored, result)
+    })
+    .await
+}
+
+pub struct SqliteRetryAgent {
+    log_target: String,
+    config: SqliteRetryAgentConfig,
+    /// Opened once, and shared with the restarted agent; database access blocks, it is done
+    /// from the blocking pool
+    queue: Option<Arc<Mutex<RetryQueue>>>,
+    worker: Option<task::JoinHandle<()>>,
+}
+impl SqliteRetryAgent {
+    pub fn new(config: &SqliteRetryAgentConfig) -> Self {
+        Self {
+            log_target: "RetryAgent[Sqlite]".to_string(),
+            config: config.clone(),
+            queue: None,
+            worker: None,
+        }
+    }
+}
+impl MailAgent for SqliteRetryAgent {
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
+    }
+}
+impl MailRetryAgent for SqliteRetryAgent {
+    fn open(&mut self) -> Result<()> {
+        info!(target: &self.log_target, "Opening database: {}", self.config.path);
+        let queue = RetryQueue::open(&self.config.path)
+            .with_context(|| format!("Failed to open database: {}", self.config.path))?;
+        match queue.len() {
+            Ok(len) => info!(target: &self.log_target, "{} mails queued for retry", len),
+            Err(e) => warn!(target: &self.log_target, "Failed to count queued mails: {}", e),
+        }
+        self.queue = Some(Arc::new(Mutex::new(queue)));
+        Ok(())
+    }
+
+    fn start(&mut self, channel: crate::hub::HubRetryAgentChannel) {
+        let log_target = self.log_target.clone();
+        let queue = self
+            .queue
+            .clone()
+            .expect("The database is opened before the agent is started");
+
+        self.worker = Some(task::spawn(async move {
+            channel.health.report_healthy();
+            let mut unstored: VecDeque<UnstoredRetryMail> = VecDeque::new();
+            let mut suspended = false;
+
+            loop {
+                match channel.next_timeout(Duration::from_secs(1)).await {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        // shutdown
+                        for unstored in &unstored {
+                            let mail = &unstored.mail;
+                            let fields = mail.log_fields().destination(&unstored.dstname);
+                            // the journal keeps the mails that were not confirmed as stored
+                            match mail.journaled {
+                                true => mail_log!(
+                                    Warn,
+                                    &log_target,
+                                    fields,
+                                    "Mail {} could not be stored for retry. It is recovered from the journal.",
+                                    mail.id
+                                ),
+                                false => mail_log!(
+                                    Error,
+                                    &log_target,
+                                    fields.outcome("lost"),
+                                    "Mail {} could not be stored for retry. It is permanently lost.",
+                                    mail.id
+                                ),
+                            }
+                        }
+                        break;
+                    }
+                    Ok(RetryAgentMessage::QueueMail {
+                        dstname,
+                        mail,
+                        reason,
+                        delay,
+                    }) => {
+                        let retransmission_timepoint = SystemTime::now() + delay;
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            mail.log_fields().destination(&dstname),
+                            "Queueing mail {} for retransmission in {}s (failed with: {})",
+                            mail.id,
+                            delay.as_secs(),
+                            reason
+                        );
+                        let retry_mail = UnstoredRetryMail {
+                            due_time: retransmission_timepoint,
+                            dstname,
+                            mail,
+                            reason,
+                        };
+                        match store(&queue, retry_mail).await {
+                            (retry_mail, Ok(())) => {
+                                channel.confirm_stored(&retry_mail.dstname, &retry_mail.mail);
+                                channel.health.report_healthy();
+                            }
+                            (retry_mail, Err(e)) => {
+                                mail_log!(
+                                    Error,
+                                    &log_target,
+                                    retry_mail.mail.log_fields().destination(&retry_mail.dstname),
+                                    "Failed to store mail {} for retry. It is kept in memory until it can be stored.\n{:#}",
+                                    retry_mail.mail.id,
+                                    e
+                                );
+                                channel
+                                    .health
+                                    .report_degraded(format!("Failed to store mails: {:#}", e));
+                                unstored.push_back(retry_mail);
+                            }
+                        }
+                    }
+                    Ok(RetryAgentMessage::Completed { dstname, id }) => {
+                        let (id, removed) = {
+                            let queue = queue.clone();
+                            task::spawn_blocking(move || {
+                                let removed = queue.lock().unwrap().complete(&dstname, &id);
+                                (id, removed)
+                            })
+                            .await
+                        };
+                        match removed {
+                            Ok(_) => debug!(target: &log_target, "Removed entry of mail {}", id),
+                            Err(e) => error!(
+                                target: &log_target,
+                                "Failed to remove entry of mail {}, it is retried again after a restart: {:#}",
+                                id,
+                                e
+                            ),
+                        }
+                    }
+                    Ok(RetryAgentMessage::Suspend) => {
+                        info!(target: &log_target, "Suspending");
+                        suspended = true;
+                        channel.confirm_suspension();
+                    }
+                }
+
+                // try again to store the mails that could not be stored
+                while let Some(retry_mail) = unstored.pop_front() {
+                    match store(&queue, retry_mail).await {
+                        (retry_mail, Ok(())) => {
+                            mail_log!(
+                                Info,
+                                &log_target,
+                                retry_mail
+                                    .mail
+                                    .log_fields()
+                                    .destination(&retry_mail.dstname),
+                                "Stored mail {} for retry",
+                                retry_mail.mail.id
+                            );
+                            channel.confirm_stored(&retry_mail.dstname, &retry_mail.mail);
+                            channel.health.report_healthy();
+                        }
+                        (retry_mail, Err(e)) => {
+                            debug!(
+                                target: &log_target,
+                                "Failed to store mail {}: {:#}", retry_mail.mail.id, e
+                            );
+                            unstored.push_front(retry_mail);
+                            break;
+                        }
+                    }
+                }
+
+                if !suspended {
+                    // see if any of the queued mails is due
+                    let due_mails = {
+                        let queue = queue.clone();
+                        task::spawn_blocking(move || queue.lock().unwrap().due(SystemTime::now()))
+                            .await
+                    };
+                    let due_mails = match due_mails {
+                        Ok(due_mails) => due_mails,
+                        Err(e) => {
+                            error!(target: &log_target, "Failed to query due mails: {:#}", e);
+                            continue;
+                        }
+                    };
+                    for due_mail in due_mails {
+                        // The entry is kept until the hub completes the mail. A crash in between
+                        // results in a duplicate delivery attempt, but never in a lost mail.
+                        let handed_back = {
+                            let queue = queue.clone();
+                            let mail_id = due_mail.mail.id.clone();
+                            task::spawn_blocking(move || {
+                                queue.lock().unwrap().hand_back(due_mail.id, &mail_id)
+                            })
+                            .await
+                        };
+                        if let Err(e) = handed_back {
+                            error!(
+                                target: &log_target,
+                                "Failed to mark entry {} as handed back: {:#}", due_mail.id, e
+                            );
+                            break;
+                        }
+                        mail_log!(
+                            Info,
+                            &log_target,
+                            due_mail.mail.log_fields().destination(&due_mail.dstname),
+                            "Mail {} due for retransmission. Queueing.",
+                            due_mail.mail.id
+                        );
+                        channel
+                            .notify_retry_mail(due_mail.dstname, due_mail.mail)
+                            .await;
+                    }
+                }
+            }
+            info!(target: &log_target, "Stopping");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::{HubChannel, HubMessage};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_queue_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("retry.db");
+        let queue = RetryQueue::open(path.to_str().unwrap()).unwrap();
+
+        let now = SystemTime::now();
+        let mut mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\n\0Body".to_vec())
+            .with_mailbox("INBOX".to_owned())
+            .with_metadata(BTreeMap::from([("uid".to_owned(), "42".to_owned())]));
+        mail.attempt = 3;
+        queue
+            .enqueue(now, "dst0", &mail, "connection refused")
+            .unwrap();
+        queue
+            .enqueue(now + Duration::from_secs(60), "dst1", &mail, "timeout")
+            .unwrap();
+        assert_eq!(queue.len().unwrap(), 2);
+
+        // reopening the database keeps the queue
+        drop(queue);
+        let queue = RetryQueue::open(path.to_str().unwrap()).unwrap();
+        let due = queue.due(now).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].dstname, "dst0");
+        assert_eq!(due[0].mail.data, mail.data);
+        assert_eq!(due[0].mail.id, mail.id);
+        assert_eq!(due[0].mail.hash, mail.hash);
+        assert_eq!(due[0].mail.metadata, mail.metadata);
+        assert_eq!(due[0].mail.attempt, 3);
+
+        queue.hand_back(due[0].id, &due[0].mail.id).unwrap();
+        assert!(queue.due(now).unwrap().is_empty());
+        queue.complete("dst0", &mail.id).unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+        assert_eq!(queue.due(now + Duration::from_secs(60)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_handed_back_mail_is_requeued_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = RetryQueue::open(dir.path().join("retry.db").to_str().unwrap()).unwrap();
+        let now = SystemTime::now();
+        let mut mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\n".to_vec());
+        queue.enqueue(now, "dst", &mail, "timeout").unwrap();
+        let due = queue.due(now).unwrap();
+        queue.hand_back(due[0].id, &mail.id).unwrap();
+
+        mail.attempt = 3;
+        queue
+            .enqueue(now + Duration::from_secs(60), "dst", &mail, "timeout")
+            .unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+        assert!(queue.due(now).unwrap().is_empty());
+        let due = queue.due(now + Duration::from_secs(60)).unwrap();
+        assert_eq!(due[0].mail.attempt, 3);
+        // only mails handed back are completed
+        queue.complete("dst", &mail.id).unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_agent_killed_before_outcome() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SqliteRetryAgentConfig {
+            delay: 1,
+            path: dir.path().join("retry.db").to_string_lossy().to_string(),
+        };
+        let mail = Mail::from_rfc822("src".to_owned(), b"Subject: Test\r\n\r\n".to_vec());
+        let mut hubchannel = HubChannel::new(Default::default());
+        let retried = |hubchannel: &HubChannel| {
+            task::block_on(async_std::future::timeout(Duration::from_secs(10), async {
+                loop {
+                    if let HubMessage::RetryMail { mail, .. } = hubchannel.next().await {
+                        return mail;
+                    }
+                }
+            }))
+            .expect("mail was not handed back")
+        };
+
+        let mut retryagent = SqliteRetryAgent::new(&config);
+        retryagent.open().unwrap();
+        retryagent.start(hubchannel.get_retryagent_channel().unwrap());
+        hubchannel
+            .queue_mail_for_retry(
+                "dst".to_owned(),
+                mail.clone(),
+                String::new(),
+                Duration::ZERO,
+            )
+            .unwrap();
+        assert_eq!(retried(&hubchannel).id, mail.id);
+        // killed after handing the mail back, before the hub reported its outcome
+        task::block_on(retryagent.join().unwrap().cancel());
+        drop(retryagent);
+
+        let mut retryagent = SqliteRetryAgent::new(&config);
+        retryagent.open().unwrap();
+        retryagent.start(hubchannel.get_retryagent_channel().unwrap());
+        assert_eq!(retried(&hubchannel).id, mail.id);
+        hubchannel.complete_retry("dst".to_owned(), mail.id.clone());
+        hubchannel.shutdown_retryagent();
+        task::block_on(retryagent.join().unwrap());
+        assert_eq!(RetryQueue::open(&config.path).unwrap().len().unwrap(), 0);
+    }
+}
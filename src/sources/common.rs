@@ -1,14 +1,17 @@
-use crate::config::AuthMethod;
+use crate::{config::AuthMethod, hub::metadata};
 use anyhow::{anyhow, Context, Result};
-use async_imap::types::Seq;
+use async_imap::types::{Flag, Seq};
 use async_native_tls::{TlsConnector, TlsStream};
 use async_std::{
     net::TcpStream,
     sync::{Mutex, MutexGuard},
-    task,
 };
-use futures::StreamExt;
-use std::{collections::VecDeque, vec};
+use futures::{future::BoxFuture, StreamExt};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    vec,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 pub type ImapClient = async_imap::Client<TlsStream<TcpStream>>;
 pub type MailboxName = async_imap::types::Name;
@@ -53,6 +56,8 @@ pub struct ImapConnection {
     port: u16,
     auth: AuthMethod,
     session: Mutex<Option<ImapSession>>,
+    /// Whether mailboxes are opened with EXAMINE, and mails fetched without setting \Seen
+    read_only: bool,
 }
 impl ImapConnection {
     pub fn new(server: String, port: u16, auth: AuthMethod) -> Self {
@@ -61,29 +66,29 @@ impl ImapConnection {
             port,
             auth,
             session: Mutex::new(None),
+            read_only: false,
         }
     }
-    fn client(&self) -> Result<ImapClient> {
+
+    /// Never modify the mailboxes, neither flags nor mails
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    async fn client(&self) -> Result<ImapClient> {
         let tls = TlsConnector::new();
-        let client = task::block_on(async_imap::connect(
-            (self.server.as_str(), self.port),
-            self.server.clone(),
-            tls,
-        ))
-        .context("Failed to connect to IMAP server.")?;
+        let client =
+            async_imap::connect((self.server.as_str(), self.port), self.server.clone(), tls)
+                .await
+                .context("Failed to connect to IMAP server.")?;
         Ok(client)
     }
     async fn session(&self) -> Result<SessionHandle<'_>> {
         if self.session.lock().await.is_none() {
-            let client = self.client()?;
+            let client = self.client().await?;
             let session = match self.auth.clone() {
-                AuthMethod::Login { user, password } => {
-                    task::block_on(client.login(user, password))
-                }
-                _ => {
-                    //TODO: implement
-                    unimplemented!();
-                }
+                AuthMethod::Login { user, password } => client.login(user, password).await,
+                _ => return Err(anyhow!("Authentication method is not supported for IMAP")),
             }
             .map_err(|(e, _)| e)
             .context("Failed to authenticate with the IMAP server.")?;
@@ -98,14 +103,15 @@ impl ImapConnection {
             .take()
             .ok_or_else(|| anyhow!("Failed to take IMAP session"))
     }
+    /// Run the given request on the session, reconnecting if the connection was lost
     pub async fn run<F, R>(&self, runfn: F) -> Result<R>
     where
-        F: Fn(&mut ImapSession) -> ImapResult<R>,
+        F: for<'s> Fn(&'s mut ImapSession) -> BoxFuture<'s, ImapResult<R>>,
     {
         let mut retry = 0;
         loop {
             let mut session_handle = self.session().await?;
-            let run_result = runfn(session_handle.get());
+            let run_result = runfn(session_handle.get()).await;
             match run_result {
                 Ok(result) => return Ok(result),
                 Err(async_imap::error::Error::ConnectionLost) => {
@@ -139,15 +145,34 @@ impl ImapConnection {
     async fn fetch_mail(&self, message_id: String) -> Result<async_imap::types::Fetch> {
         let mut session_borrow = self.session().await?;
         let session_borrow = session_borrow.get();
-        let mut message_stream = session_borrow.fetch(&message_id, "RFC822").await?;
-        if let Some(message) = message_stream.next().await {
-            Ok(message?)
-        } else {
-            Err(anyhow!("Failed to fetch message: {}", message_id))
+        // peek, so FLAGS are those the mail had before it was fetched
+        let query = "(UID FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[])";
+        let message = {
+            let mut message_stream = session_borrow.fetch(&message_id, query).await?;
+            let message = match message_stream.next().await {
+                Some(message) => message?,
+                None => return Err(anyhow!("Failed to fetch message: {}", message_id)),
+            };
+            // read the rest of the response, before the next command is sent
+            while message_stream.next().await.is_some() {}
+            message
+        };
+        if !self.read_only {
+            let seen_result: Vec<ImapResult<_>> = session_borrow
+                .store(&message_id, "+FLAGS.SILENT (\\Seen)")
+                .await
+                .context("Failed to mark mail with Seen flag")?
+                .collect()
+                .await;
+            seen_result.into_iter().collect::<ImapResult<Vec<_>>>()?;
         }
+        Ok(message)
     }
 
     pub async fn delete_mails(&self, message_ids: &[Seq]) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("The connection is read-only"));
+        }
         let id_list: String = message_ids.iter().fold("".to_owned(), |a, b| {
             if a.is_empty() {
                 b.to_string()
@@ -185,12 +210,12 @@ impl ImapConnection {
         Ok(())
     }
 
-    pub fn iter_mailboxes_recursive(
+    pub async fn iter_mailboxes_recursive(
         &self,
         path_filter: Option<&str>,
     ) -> Result<vec::IntoIter<MailboxName>> {
         // get a (linearized) list of the folder structure
-        let mut mailboxes = task::block_on(self.recursive_mailbox_list())?;
+        let mut mailboxes = self.recursive_mailbox_list().await?;
         if let Some(filter) = path_filter {
             mailboxes.retain(|mailbox| {
                 // Match the given filter against the "/"-delimited absolute path
@@ -202,51 +227,143 @@ impl ImapConnection {
 
     pub async fn iter_unseen(&self, mailbox: &MailboxName) -> Result<UnseenMailIterator<'_>> {
         // select new mailbox and get a list of new/unseen messages
-        let unread_mails: Vec<_> = self
+        let name = mailbox.name().to_owned();
+        let read_only = self.read_only;
+        let (selected, unread_mails) = self
             .run(|sess| {
-                task::block_on(sess.select(mailbox.name()))?;
-                task::block_on(sess.search("UNDELETED UNSEEN"))
+                let name = name.clone();
+                Box::pin(async move {
+                    let selected = open_mailbox(sess, name, read_only).await?;
+                    Ok((selected, sess.search("UNDELETED UNSEEN").await?))
+                })
             })
-            .await?
-            .into_iter()
-            .collect();
+            .await?;
         Ok(UnseenMailIterator {
             con: self,
-            unread_mails: VecDeque::from(unread_mails),
+            mailbox: mailbox.path(),
+            uid_validity: selected.uid_validity,
+            unread_mails: unread_mails.into_iter().collect(),
         })
     }
 
+    /// Select the mailbox, e.g. to IDLE on it. Read-only connections examine it instead.
+    pub async fn open(&self, name: &str) -> Result<()> {
+        let read_only = self.read_only;
+        self.run(|sess| {
+            let name = name.to_owned();
+            Box::pin(async move { open_mailbox(sess, name, read_only).await.map(|_| ()) })
+        })
+        .await
+    }
+
+    /// Count the unseen mails in the mailbox, without marking them as seen
+    pub async fn count_unseen(&self, mailbox: &MailboxName) -> Result<usize> {
+        let name = mailbox.name().to_owned();
+        self.run(|sess| {
+            let name = name.clone();
+            Box::pin(async move {
+                sess.examine(name).await?;
+                Ok(sess.search("UNDELETED UNSEEN").await?.len())
+            })
+        })
+        .await
+    }
+
     pub async fn idle(&mut self) -> Result<ImapIdleHandle> {
         let mut idle_handle = self.take_session().await?.idle();
-        task::block_on(idle_handle.init())
+        idle_handle
+            .init()
+            .await
             .context("Failed to initialize IDLE session with IMAP server")?;
         Ok(idle_handle)
     }
-}
-impl Drop for ImapConnection {
-    fn drop(&mut self) {
-        if let Ok(session) = &mut task::block_on(self.take_session()) {
-            let _ = task::block_on(session.logout());
+
+    /// Log out of the current session, if there is one
+    pub async fn logout(&mut self) {
+        if let Some(mut session) = self.session.lock().await.take() {
+            let _ = session.logout().await;
         }
     }
 }
 
+async fn open_mailbox(
+    sess: &mut ImapSession,
+    name: String,
+    read_only: bool,
+) -> ImapResult<async_imap::types::Mailbox> {
+    match read_only {
+        true => sess.examine(name).await,
+        false => sess.select(name).await,
+    }
+}
+
+fn format_flag(flag: Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_owned(),
+        Flag::Answered => "\\Answered".to_owned(),
+        Flag::Flagged => "\\Flagged".to_owned(),
+        Flag::Deleted => "\\Deleted".to_owned(),
+        Flag::Draft => "\\Draft".to_owned(),
+        Flag::Recent => "\\Recent".to_owned(),
+        Flag::MayCreate => "\\*".to_owned(),
+        Flag::Custom(flag) => flag.into_owned(),
+    }
+}
+
+/// Raw mail fetched from the server, with the metadata of the mail (see `hub::metadata`)
+pub struct FetchedMail {
+    pub data: Vec<u8>,
+    pub metadata: BTreeMap<String, String>,
+}
+
 pub struct UnseenMailIterator<'a> {
     con: &'a ImapConnection,
+    mailbox: String,
+    uid_validity: Option<u32>,
     unread_mails: VecDeque<Seq>,
 }
-impl Iterator for UnseenMailIterator<'_> {
-    type Item = Result<(Seq, Vec<u8>)>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.unread_mails.pop_front().map(|message_id| {
-            match task::block_on(self.con.fetch_mail(message_id.to_string())) {
-                Ok(fetch_result) => fetch_result
-                    .body()
-                    .map(|body| (message_id, body.to_vec()))
-                    .ok_or_else(|| anyhow!("Failed to fetch message: {}", message_id)),
-                Err(err) => Err(err),
-            }
+impl UnseenMailIterator<'_> {
+    fn fetched_mail(&self, fetch_result: &async_imap::types::Fetch) -> Option<FetchedMail> {
+        let data = fetch_result.body()?.to_vec();
+        let mut meta = BTreeMap::new();
+        meta.insert(metadata::MAILBOX.to_owned(), self.mailbox.clone());
+        if let Some(uid) = fetch_result.uid {
+            meta.insert(metadata::UID.to_owned(), uid.to_string());
+        }
+        if let Some(uid_validity) = self.uid_validity {
+            meta.insert(metadata::UIDVALIDITY.to_owned(), uid_validity.to_string());
+        }
+        let flags: Vec<_> = fetch_result.flags().map(format_flag).collect();
+        meta.insert(metadata::FLAGS.to_owned(), flags.join(" "));
+        if let Some(internal_date) = fetch_result.internal_date() {
+            meta.insert(
+                metadata::INTERNAL_DATE.to_owned(),
+                internal_date.to_rfc3339(),
+            );
+        }
+        meta.insert(
+            metadata::SIZE.to_owned(),
+            fetch_result.size.unwrap_or(data.len() as u32).to_string(),
+        );
+        if let Ok(now) = OffsetDateTime::now_utc().format(&Rfc3339) {
+            meta.insert(metadata::FETCHED.to_owned(), now);
+        }
+        Some(FetchedMail {
+            data,
+            metadata: meta,
+        })
+    }
+}
+impl UnseenMailIterator<'_> {
+    /// Fetch the next unseen mail, None once all mails were fetched
+    pub async fn next_mail(&mut self) -> Option<Result<(Seq, FetchedMail)>> {
+        let message_id = self.unread_mails.pop_front()?;
+        Some(match self.con.fetch_mail(message_id.to_string()).await {
+            Ok(fetch_result) => self
+                .fetched_mail(&fetch_result)
+                .map(|fetched| (message_id, fetched))
+                .ok_or_else(|| anyhow!("Failed to fetch message: {}", message_id)),
+            Err(err) => Err(err),
         })
     }
 }
@@ -5,17 +5,18 @@ use super::{
 use crate::{
     config::ImapIdleSourceConfig,
     hub::{HubSourceChannel, Mail, MailAgent},
+    logging::{mail_log, SourceFields},
 };
 use async_std::task;
 use futures::{future::FutureExt, pin_mut, select};
-use log::{debug, error, info, trace, warn};
-use std::{thread, time::Duration};
+use log::{debug, error, info, trace};
+use std::time::Duration;
 
 pub struct ImapIdleSource {
     name: String,
     log_target: String,
     config: ImapIdleSourceConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl ImapIdleSource {
     pub fn new(name: String, config: &ImapIdleSourceConfig) -> Self {
@@ -28,12 +29,8 @@ impl ImapIdleSource {
     }
 }
 impl MailAgent for ImapIdleSource {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailSource for ImapIdleSource {
@@ -45,43 +42,106 @@ impl MailSource for ImapIdleSource {
         let log_target = self.log_target.clone();
         let config = self.config.clone();
 
-        self.worker = Some(thread::spawn(move || {
+        self.worker = Some(task::spawn(async move {
             let mut con =
-                ImapConnection::new(config.server.clone(), config.port, config.auth.clone());
+                ImapConnection::new(config.server.clone(), config.port, config.auth.clone())
+                    .read_only(channel.dry_run());
 
             let stop_future = channel.next().fuse();
             pin_mut!(stop_future);
 
             loop {
-                match con.iter_mailboxes_recursive(None) {
+                match con.iter_mailboxes_recursive(None).await {
                     Ok(mailboxes) => {
-                        mailboxes.for_each(|mailbox| {
-                            let mut unread_mails = Vec::new();
-                            task::block_on(con.iter_unseen(&mailbox)).unwrap().for_each(
-                                |unseen_message| {
-                                    if let Ok((message_id, unseen_message)) = unseen_message {
-                                        unread_mails.push(message_id);
-                                        debug!(
-                                            target: &log_target,
-                                            "Unread mail in {}",
+                        channel.health.report_healthy();
+                        for mailbox in mailboxes {
+                            let mut fetched_mails = Vec::new();
+                            let mut unseen = match con.iter_unseen(&mailbox).await {
+                                Ok(unseen) => unseen,
+                                Err(e) => {
+                                    error!(
+                                        target: &log_target,
+                                        "Failed to list unread mails in {}: {:#}",
+                                        mailbox.path(),
+                                        e
+                                    );
+                                    channel.health.report_degraded(format!(
+                                        "Failed to list unread mails in {}: {:#}",
+                                        mailbox.path(),
+                                        e
+                                    ));
+                                    continue;
+                                }
+                            };
+                            while let Some(unseen_message) = unseen.next_mail().await {
+                                match unseen_message {
+                                    Ok((message_id, unseen_message)) => {
+                                        let mail =
+                                            Mail::from_rfc822(name.clone(), unseen_message.data)
+                                                .with_metadata(unseen_message.metadata);
+                                        mail_log!(
+                                            Info,
+                                            &log_target,
+                                            mail.log_fields().outcome("fetched"),
+                                            "Fetched mail {} from {}",
+                                            mail.id,
                                             mailbox.path()
                                         );
-                                        channel.notify_new_mail(Mail::from_rfc822(
-                                            name.clone(),
-                                            unseen_message,
-                                        ));
+                                        // only mails the hub took the responsibility for
+                                        // are deleted
+                                        if channel.notify_new_mail(mail.clone()).await {
+                                            fetched_mails.push((message_id, mail));
+                                        } else {
+                                            mail_log!(
+                                                Warn,
+                                                &log_target,
+                                                mail.log_fields(),
+                                                "Mail {} was not recorded by the hub, keeping it in {}",
+                                                mail.id,
+                                                mailbox.path()
+                                            );
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let path = mailbox.path();
+                                        mail_log!(
+                                            Error,
+                                            &log_target,
+                                            SourceFields::new(&name, &path).outcome("fetch_failed"),
+                                            "Failed to fetch unread mail from {}: {:#}",
+                                            path,
+                                            e
+                                        );
                                     }
-                                },
-                            );
-                            if !config.keep && !unread_mails.is_empty() {
-                                if let Err(e) = task::block_on(con.delete_mails(&unread_mails)) {
-                                    warn!(
-                                        target: &log_target,
-                                        "Failed to deleted messages from mailbox\n{}", e
-                                    );
                                 }
                             }
-                        });
+                            if !config.keep && !channel.dry_run() && !fetched_mails.is_empty() {
+                                let message_ids: Vec<_> =
+                                    fetched_mails.iter().map(|(id, _)| *id).collect();
+                                let deleted = con.delete_mails(&message_ids).await;
+                                for (_, mail) in &fetched_mails {
+                                    match &deleted {
+                                        Ok(()) => mail_log!(
+                                            Info,
+                                            &log_target,
+                                            mail.log_fields().outcome("deleted"),
+                                            "Deleted mail {} from {}",
+                                            mail.id,
+                                            mailbox.path()
+                                        ),
+                                        Err(e) => mail_log!(
+                                            Warn,
+                                            &log_target,
+                                            mail.log_fields(),
+                                            "Failed to delete mail {} from {}: {:#}",
+                                            mail.id,
+                                            mailbox.path(),
+                                            e
+                                        ),
+                                    }
+                                }
+                            }
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -89,32 +149,39 @@ impl MailSource for ImapIdleSource {
                             "Failed to get recursive list of mailboxes to iterate\n{}",
                             e.backtrace()
                         );
+                        channel
+                            .health
+                            .report_degraded(format!("Failed to list mailboxes: {:#}", e));
                     }
                 }
 
+                if channel.once() {
+                    con.logout().await;
+                    info!(target: &log_target, "Stopping");
+                    return;
+                }
+
                 loop {
                     // inner loop used only if something fails while entering IDLE state and we need to retry
                     debug!(
                         target: &log_target,
                         "Entering IMAP IDLE to wait for server notification"
                     );
-                    match task::block_on(
-                        con.run(|sess| task::block_on(sess.select(config.path.clone()))),
-                    ) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!(
-                                target: &log_target,
-                                "Failed to enter IMAP IDLE state:\n{}",
-                                e.backtrace()
-                            );
-                            // connection-lost errors should be handled by the connection, so this could
-                            // be an authentication error, or a temporary unavailable server. Wait a bit and retry
-                            thread::sleep(Duration::from_secs(5));
-                            continue;
-                        }
+                    if let Err(e) = con.open(&config.path).await {
+                        error!(
+                            target: &log_target,
+                            "Failed to enter IMAP IDLE state:\n{}",
+                            e.backtrace()
+                        );
+                        channel
+                            .health
+                            .report_degraded(format!("Failed to select mailbox: {:#}", e));
+                        // connection-lost errors should be handled by the connection, so this could
+                        // be an authentication error, or a temporary unavailable server. Wait a bit and retry
+                        task::sleep(Duration::from_secs(5)).await;
+                        continue;
                     }
-                    let mut idle_handle = match task::block_on(con.idle()) {
+                    let mut idle_handle = match con.idle().await {
                         Ok(idle_handle) => idle_handle,
                         Err(e) => {
                             error!(
@@ -122,10 +189,14 @@ impl MailSource for ImapIdleSource {
                                 "Failed to enter IMAP IDLE state:\n{}",
                                 e.backtrace()
                             );
-                            thread::sleep(Duration::from_secs(5));
+                            channel
+                                .health
+                                .report_degraded(format!("Failed to enter IDLE: {:#}", e));
+                            task::sleep(Duration::from_secs(5)).await;
                             continue;
                         }
                     };
+                    channel.health.report_healthy();
                     // dropping the StopSource interrupts idle, the variable thus needs a name.
                     let (idle_future, _stopsrc) =
                         idle_handle.wait_with_timeout(Duration::from_secs(config.renewinterval));
@@ -133,13 +204,11 @@ impl MailSource for ImapIdleSource {
                     // await either a wake-up from the IMAP server, or a request to shutdown
                     let idle_future = idle_future.fuse();
                     pin_mut!(idle_future);
-                    let should_exit = task::block_on(async {
-                        select! {
-                            _ = idle_future => false,
-                            _ = stop_future => true,
-                            complete => unreachable!()
-                        }
-                    });
+                    let should_exit = select! {
+                        _ = idle_future => false,
+                        _ = stop_future => true,
+                        complete => unreachable!()
+                    };
                     if should_exit {
                         info!(target: &log_target, "Stopping");
                         return;
@@ -5,16 +5,17 @@ use super::{
 use crate::{
     config::ImapPollSourceConfig,
     hub::{HubSourceChannel, Mail, MailAgent},
+    logging::{mail_log, SourceFields},
 };
 use async_std::task;
-use log::{debug, error, info, trace, warn};
-use std::{sync::mpsc, thread, time::Duration};
+use log::{debug, error, info, trace};
+use std::{sync::mpsc, time::Duration};
 
 pub struct ImapPollSource {
     name: String,
     log_target: String,
     config: ImapPollSourceConfig,
-    worker: Option<thread::JoinHandle<()>>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl ImapPollSource {
     pub fn new(name: String, config: &ImapPollSourceConfig) -> Self {
@@ -27,12 +28,8 @@ impl ImapPollSource {
     }
 }
 impl MailAgent for ImapPollSource {
-    fn join(&mut self) {
-        self.worker
-            .take()
-            .unwrap()
-            .join()
-            .expect("Thread exited with errors");
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailSource for ImapPollSource {
@@ -44,39 +41,103 @@ impl MailSource for ImapPollSource {
         let log_target = self.log_target.clone();
         let config = self.config.clone();
 
-        self.worker = Some(thread::spawn(move || {
-            let con = ImapConnection::new(config.server.clone(), config.port, config.auth.clone());
+        self.worker = Some(task::spawn(async move {
+            let mut con =
+                ImapConnection::new(config.server.clone(), config.port, config.auth.clone())
+                    .read_only(channel.dry_run());
             loop {
                 debug!(target: &log_target, "Polling for unread mails");
-                match con.iter_mailboxes_recursive(None) {
+                match con.iter_mailboxes_recursive(None).await {
                     Ok(mailboxes) => {
-                        mailboxes.for_each(|mailbox| {
-                            let mut unread_mails = Vec::new();
-                            task::block_on(con.iter_unseen(&mailbox)).unwrap().for_each(
-                                |unseen_message| {
-                                    if let Ok((message_id, unseen_message)) = unseen_message {
-                                        unread_mails.push(message_id);
-                                        debug!(
-                                            target: &log_target,
-                                            "Unread mail in {}",
+                        channel.health.report_healthy();
+                        for mailbox in mailboxes {
+                            let mut fetched_mails = Vec::new();
+                            let mut unseen = match con.iter_unseen(&mailbox).await {
+                                Ok(unseen) => unseen,
+                                Err(e) => {
+                                    error!(
+                                        target: &log_target,
+                                        "Failed to list unread mails in {}: {:#}",
+                                        mailbox.path(),
+                                        e
+                                    );
+                                    channel.health.report_degraded(format!(
+                                        "Failed to list unread mails in {}: {:#}",
+                                        mailbox.path(),
+                                        e
+                                    ));
+                                    continue;
+                                }
+                            };
+                            while let Some(unseen_message) = unseen.next_mail().await {
+                                match unseen_message {
+                                    Ok((message_id, unseen_message)) => {
+                                        let mail =
+                                            Mail::from_rfc822(name.clone(), unseen_message.data)
+                                                .with_metadata(unseen_message.metadata);
+                                        mail_log!(
+                                            Info,
+                                            &log_target,
+                                            mail.log_fields().outcome("fetched"),
+                                            "Fetched mail {} from {}",
+                                            mail.id,
                                             mailbox.path()
                                         );
-                                        channel.notify_new_mail(Mail::from_rfc822(
-                                            name.clone(),
-                                            unseen_message,
-                                        ));
+                                        // only mails the hub took the responsibility for
+                                        // are deleted
+                                        if channel.notify_new_mail(mail.clone()).await {
+                                            fetched_mails.push((message_id, mail));
+                                        } else {
+                                            mail_log!(
+                                                Warn,
+                                                &log_target,
+                                                mail.log_fields(),
+                                                "Mail {} was not recorded by the hub, keeping it in {}",
+                                                mail.id,
+                                                mailbox.path()
+                                            );
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let path = mailbox.path();
+                                        mail_log!(
+                                            Error,
+                                            &log_target,
+                                            SourceFields::new(&name, &path).outcome("fetch_failed"),
+                                            "Failed to fetch unread mail from {}: {:#}",
+                                            path,
+                                            e
+                                        );
                                     }
-                                },
-                            );
-                            if !config.keep {
-                                if let Err(e) = task::block_on(con.delete_mails(&unread_mails)) {
-                                    warn!(
-                                        target: &log_target,
-                                        "Failed to deleted messages from mailbox\n{}", e
-                                    );
                                 }
                             }
-                        });
+                            if !config.keep && !channel.dry_run() && !fetched_mails.is_empty() {
+                                let message_ids: Vec<_> =
+                                    fetched_mails.iter().map(|(id, _)| *id).collect();
+                                let deleted = con.delete_mails(&message_ids).await;
+                                for (_, mail) in &fetched_mails {
+                                    match &deleted {
+                                        Ok(()) => mail_log!(
+                                            Info,
+                                            &log_target,
+                                            mail.log_fields().outcome("deleted"),
+                                            "Deleted mail {} from {}",
+                                            mail.id,
+                                            mailbox.path()
+                                        ),
+                                        Err(e) => mail_log!(
+                                            Warn,
+                                            &log_target,
+                                            mail.log_fields(),
+                                            "Failed to delete mail {} from {}: {:#}",
+                                            mail.id,
+                                            mailbox.path(),
+                                            e
+                                        ),
+                                    }
+                                }
+                            }
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -84,16 +145,27 @@ impl MailSource for ImapPollSource {
                             "Failed to get recursive list of mailboxes to iterate\n{}",
                             e.backtrace()
                         );
+                        channel
+                            .health
+                            .report_degraded(format!("Failed to list mailboxes: {:#}", e));
                     }
                 }
 
+                if channel.once() {
+                    break;
+                }
+
                 // sleep until next poll is due - interrupt if requested to stop
-                match channel.next_timeout(Duration::from_secs(config.interval)) {
+                match channel
+                    .next_timeout(Duration::from_secs(config.interval))
+                    .await
+                {
                     Err(mpsc::RecvTimeoutError::Timeout) => {}
                     Err(mpsc::RecvTimeoutError::Disconnected) => break, // shutdown
-                    _ => panic!(), // There currently are no SourceMessages
+                    Ok(msg) => match msg {}, // There currently are no SourceMessages
                 }
             }
+            con.logout().await;
             info!(target: &log_target, "Stopping");
         }));
     }
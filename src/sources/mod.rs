@@ -1,4 +1,9 @@
-use crate::hub::{HubSourceChannel, MailAgent};
+use crate::{
+    config::SourceConfig,
+    hub::{HubSourceChannel, MailAgent},
+};
+use anyhow::Result;
+use common::{ImapConnection, MailPath};
 
 mod common;
 pub mod imap_idle;
@@ -8,3 +13,23 @@ pub mod testsrc;
 pub trait MailSource: MailAgent {
     fn start(&mut self, channel: HubSourceChannel);
 }
+
+/// Connect to the source's server and count the unseen mails of every mailbox, without fetching
+/// them. Fails, if the mailboxes can not be listed. Returns an empty list for test sources.
+pub async fn probe(config: &SourceConfig) -> Result<Vec<(String, Result<usize>)>> {
+    let (server, port, auth) = match config {
+        SourceConfig::Test(_) => return Ok(Vec::new()),
+        SourceConfig::ImapPoll(config) => (&config.server, config.port, &config.auth),
+        SourceConfig::ImapIdle(config) => (&config.server, config.port, &config.auth),
+    };
+    let mut con = ImapConnection::new(server.clone(), port, auth.clone());
+    let mut unseen = Vec::new();
+    let listed = con.iter_mailboxes_recursive(None).await;
+    if let Ok(mailboxes) = &listed {
+        for mailbox in mailboxes.as_slice() {
+            unseen.push((mailbox.path(), con.count_unseen(mailbox).await));
+        }
+    }
+    con.logout().await;
+    listed.map(|_| unseen)
+}
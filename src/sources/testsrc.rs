@@ -1,4 +1,5 @@
-use std::{sync::mpsc, thread, time::Duration};
+use async_std::task;
+use std::{sync::mpsc, time::Duration};
 
 use crate::{
     config::TestSourceConfig,
@@ -14,7 +15,7 @@ use super::MailSource;
 pub struct TestSource {
     name: String,
     config: TestSourceConfig,
-    worker: Option<(mpsc::Sender<()>, thread::JoinHandle<()>)>,
+    worker: Option<task::JoinHandle<()>>,
 }
 impl TestSource {
     pub fn new(name: String, config: &TestSourceConfig) -> Self {
@@ -25,7 +26,7 @@ impl TestSource {
         }
     }
 
-    fn send_testmail(name: String, channel: &crate::hub::HubSourceChannel) {
+    async fn send_testmail(name: String, channel: &crate::hub::HubSourceChannel) {
         let body_html = SinglePart::builder()
             .header(header::ContentType::parse("text/html; charset=utf8").unwrap())
             .body("<b>text/html</b>".to_owned());
@@ -44,36 +45,44 @@ impl TestSource {
             .multipart(body)
             .unwrap();
 
-        channel.notify_new_mail(Mail::from_rfc822(name, testmail.formatted()));
+        channel.health.report_healthy();
+        channel
+            .notify_new_mail(Mail::from_rfc822(name, testmail.formatted()))
+            .await;
     }
 }
 impl MailAgent for TestSource {
-    fn join(&mut self) {
-        if let Some((_, handle)) = self.worker.take() {
-            let _ = handle.join();
-        }
+    fn join(&mut self) -> Option<task::JoinHandle<()>> {
+        self.worker.take()
     }
 }
 impl MailSource for TestSource {
     fn start(&mut self, channel: crate::hub::HubSourceChannel) {
         let name = self.name.clone();
         let config = self.config.clone();
-        let (stop_tx, stop_rx) = mpsc::channel();
 
-        self.worker = Some((
-            stop_tx,
-            thread::spawn(move || {
-                thread::sleep(Duration::from_secs(config.delay));
-                loop {
-                    TestSource::send_testmail(name.clone(), &channel);
-                    match stop_rx.recv_timeout(Duration::from_secs(config.interval)) {
-                        // timeout hit, send new produce and schedule new test-mail
-                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
-                        // we were asked to exit
-                        _ => break,
-                    }
+        self.worker = Some(task::spawn(async move {
+            if let Err(mpsc::RecvTimeoutError::Disconnected) = channel
+                .next_timeout(Duration::from_secs(config.delay))
+                .await
+            {
+                return;
+            }
+            loop {
+                TestSource::send_testmail(name.clone(), &channel).await;
+                if channel.once() {
+                    break;
+                }
+                match channel
+                    .next_timeout(Duration::from_secs(config.interval))
+                    .await
+                {
+                    // timeout hit, send new produce and schedule new test-mail
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    // we were asked to exit
+                    _ => break,
                 }
-            }),
-        ));
+            }
+        }));
     }
 }
@@ -0,0 +1,46 @@
+//! Helpers for crash-safe storage of files
+use anyhow::{Context, Result};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const TMP_EXTENSION: &str = "tmp";
+
+/// Append an extension to the path. Unlike `Path::with_extension`, this keeps dots in the file name.
+pub fn append_extension(base: &Path, extension: &str) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Write the file under a temporary name, sync it to disk, and rename it into place.
+/// Readers thus either see the complete file, or none at all.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_atomic_from(path, data)
+}
+
+/// Like `write_atomic`, but streams the content from the reader
+pub fn write_atomic_from(path: &Path, mut data: impl io::Read) -> Result<()> {
+    let tmp_path = append_extension(path, TMP_EXTENSION);
+    let mut file = fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    io::copy(&mut data, &mut file)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to rename {}", tmp_path.display()))?;
+    sync_dir(path)
+}
+
+/// Sync the directory containing the given path, to persist renames and deletions.
+pub fn sync_dir(path: &Path) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))
+}
@@ -0,0 +1,441 @@
+use crate::{config::SupervisionConfig, hub::QueueMonitor, storage::write_atomic};
+use async_std::{channel as async_mpsc, future::timeout as await_timeout, task};
+use log::{error, info, warn};
+use serde_derive::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+const DEFAULT_MAX_RESTARTS: usize = 10;
+const DEFAULT_RESTART_WINDOW: u64 = 3600;
+/// The status file is written at most once per interval, with the latest states and queue depths
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identifies one of the agents run by the hub
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AgentId {
+    Source(String),
+    Destination(String),
+    RetryAgent,
+}
+impl fmt::Display for AgentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentId::Source(name) => write!(f, "source {}", name),
+            AgentId::Destination(name) => write!(f, "destination {}", name),
+            AgentId::RetryAgent => write!(f, "retryagent"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentState {
+    /// (Re)started, but did not report on its health yet
+    Starting,
+    Running,
+    /// Running, but its last operation failed (e.g. the server is not reachable)
+    Degraded,
+    /// Crashed, and either waiting for its restart or given up on
+    Failed,
+}
+impl fmt::Display for AgentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentState::Starting => write!(f, "starting"),
+            AgentState::Running => write!(f, "running"),
+            AgentState::Degraded => write!(f, "degraded"),
+            AgentState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AgentHealth {
+    state: AgentState,
+    /// Why the agent is degraded or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Time of the last change of the state
+    since: String,
+    restarts: u32,
+    #[serde(skip)]
+    restart: Restart,
+    /// Times of the crashes within the restart window
+    #[serde(skip)]
+    crashes: VecDeque<Instant>,
+}
+
+/// Restarts of an agent. The delay doubles with every crash, unless the agent ran long enough
+/// to be considered healthy again.
+struct Restart {
+    started: Instant,
+    delay: Duration,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+impl Restart {
+    const MIN_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(300);
+
+    fn with_delays(min_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            delay: min_delay,
+            min_delay,
+            max_delay,
+        }
+    }
+
+    /// Delay before the next restart
+    fn next_delay(&mut self) -> Duration {
+        if self.started.elapsed() >= self.max_delay {
+            self.delay = self.min_delay;
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max_delay);
+        delay
+    }
+}
+
+enum StatusUpdate {
+    /// The states of all agents
+    Agents(serde_json::Map<String, serde_json::Value>),
+    Remove,
+}
+
+/// Writes the status file on its own task, so the hub does not wait for the disk
+struct StatusWriter {
+    sender: async_mpsc::Sender<StatusUpdate>,
+    task: task::JoinHandle<()>,
+}
+impl StatusWriter {
+    fn new(path: PathBuf, queues: Arc<QueueMonitor>) -> Self {
+        let (sender, recv) = async_mpsc::unbounded();
+        Self {
+            sender,
+            task: task::spawn(write_status_file(path, recv, queues)),
+        }
+    }
+}
+
+async fn write_status_file(
+    path: PathBuf,
+    recv: async_mpsc::Receiver<StatusUpdate>,
+    queues: Arc<QueueMonitor>,
+) {
+    let mut agents = serde_json::Map::new();
+    let mut depths = BTreeMap::new();
+    loop {
+        // woken up regularly, to write the changed queue depths
+        let mut updates = match await_timeout(STATUS_INTERVAL, recv.recv()).await {
+            Ok(Ok(update)) => vec![update],
+            Ok(Err(_)) => return,
+            Err(_) => Vec::new(),
+        };
+        // states that changed since the last write are written at once
+        updates.extend(std::iter::from_fn(|| recv.try_recv().ok()));
+        let mut changed = false;
+        for update in updates {
+            match update {
+                StatusUpdate::Agents(latest) => {
+                    agents = latest;
+                    changed = true;
+                }
+                StatusUpdate::Remove => {
+                    if let Err(e) = fs::remove_file(&path) {
+                        warn!(target: "Supervisor", "Failed to remove status file: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+        let latest_depths = queues.depths();
+        if !changed && latest_depths == depths {
+            continue;
+        }
+        depths = latest_depths;
+
+        let mut status = agents.clone();
+        for (dstname, depth) in &depths {
+            let agent = AgentId::Destination(dstname.clone()).to_string();
+            if let Some(serde_json::Value::Object(health)) = status.get_mut(&agent) {
+                health.insert("queued".to_owned(), (*depth).into());
+            }
+        }
+        let path = path.clone();
+        let result = task::spawn_blocking(move || {
+            let status = serde_json::to_vec_pretty(&status)?;
+            write_atomic(&path, &status)
+        })
+        .await;
+        if let Err(e) = result {
+            warn!(target: "Supervisor", "Failed to write status file: {:#}", e);
+        }
+        task::sleep(STATUS_INTERVAL).await;
+    }
+}
+
+/// Tracks the health of the hub's agents, and decides when crashed agents are restarted.
+///
+/// The delay before a restart doubles with every crash, unless the agent ran long enough to
+/// be considered healthy again. Agents that crash too often are given up on.
+pub struct Supervisor {
+    min_delay: Duration,
+    max_delay: Duration,
+    max_restarts: usize,
+    restart_window: Duration,
+    status_writer: Option<StatusWriter>,
+    agents: BTreeMap<AgentId, AgentHealth>,
+}
+impl Supervisor {
+    pub fn new(config: Option<&SupervisionConfig>, queues: Arc<QueueMonitor>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            min_delay: config
+                .min_delay
+                .map_or(Restart::MIN_DELAY, Duration::from_secs),
+            max_delay: config
+                .max_delay
+                .map_or(Restart::MAX_DELAY, Duration::from_secs),
+            max_restarts: config.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+            restart_window: Duration::from_secs(
+                config.restart_window.unwrap_or(DEFAULT_RESTART_WINDOW),
+            ),
+            status_writer: config
+                .status_file
+                .map(|path| StatusWriter::new(PathBuf::from(path), queues)),
+            agents: BTreeMap::new(),
+        }
+    }
+
+    pub fn state(&self, agent: &AgentId) -> Option<AgentState> {
+        self.agents.get(agent).map(|health| health.state)
+    }
+
+    /// The agent's task was (re)started
+    pub fn started(&mut self, agent: &AgentId) {
+        let (min_delay, max_delay) = (self.min_delay, self.max_delay);
+        let health = self
+            .agents
+            .entry(agent.clone())
+            .or_insert_with(|| AgentHealth {
+                state: AgentState::Starting,
+                reason: None,
+                since: now(),
+                restarts: 0,
+                restart: Restart::with_delays(min_delay, max_delay),
+                crashes: VecDeque::new(),
+            });
+        health.restart.started = Instant::now();
+        self.set_state(agent, AgentState::Starting, None);
+    }
+
+    /// The agent reported whether its last operation succeeded
+    pub fn report(&mut self, agent: &AgentId, result: Result<(), String>) {
+        if self.state(agent) == Some(AgentState::Failed) {
+            // a late report of a crashed agent
+            return;
+        }
+        let changed = match result {
+            Ok(()) => self.set_state(agent, AgentState::Running, None),
+            Err(reason) => self.set_state(agent, AgentState::Degraded, Some(reason)),
+        };
+        if changed {
+            match self.agents.get(agent) {
+                Some(AgentHealth {
+                    state: AgentState::Degraded,
+                    reason: Some(reason),
+                    ..
+                }) => warn!(target: "Supervisor", "The {} is degraded: {}", agent, reason),
+                _ => info!(target: "Supervisor", "The {} is running", agent),
+            }
+        }
+    }
+
+    /// The agent's task ended unexpectedly.
+    /// Returns the delay before it is restarted, None if it crashed too often.
+    pub fn crashed(&mut self, agent: &AgentId, reason: String) -> Option<Duration> {
+        error!(target: "Supervisor", "The {} failed: {}", agent, reason);
+        let (max_restarts, restart_window) = (self.max_restarts, self.restart_window);
+        let health = self.agents.get_mut(agent)?;
+        let now = Instant::now();
+        health.crashes.push_back(now);
+        while health
+            .crashes
+            .front()
+            .is_some_and(|crash| now.duration_since(*crash) > restart_window)
+        {
+            health.crashes.pop_front();
+        }
+
+        if health.crashes.len() > max_restarts {
+            error!(
+                target: "Supervisor",
+                "The {} failed {} times within {}s, it is not restarted anymore",
+                agent,
+                health.crashes.len(),
+                restart_window.as_secs()
+            );
+            self.set_state(
+                agent,
+                AgentState::Failed,
+                Some(format!("{} (not restarted anymore)", reason)),
+            );
+            return None;
+        }
+        let delay = health.restart.next_delay();
+        health.restarts += 1;
+        warn!(
+            target: "Supervisor",
+            "Restarting the {} in {}s",
+            agent,
+            delay.as_secs()
+        );
+        self.set_state(agent, AgentState::Failed, Some(reason));
+        Some(delay)
+    }
+
+    /// The agent's task ended unexpectedly, and it is not restarted
+    pub fn failed(&mut self, agent: &AgentId, reason: String) {
+        error!(target: "Supervisor", "The {} failed: {}", agent, reason);
+        self.set_state(agent, AgentState::Failed, Some(reason));
+    }
+
+    /// All agents stopped. The status file is removed, so it does not claim otherwise.
+    pub async fn stopped(&mut self) {
+        if let Some(writer) = self.status_writer.take() {
+            let _ = writer.sender.try_send(StatusUpdate::Remove);
+            writer.task.await;
+        }
+    }
+
+    /// Returns whether the state changed
+    fn set_state(&mut self, agent: &AgentId, state: AgentState, reason: Option<String>) -> bool {
+        let Some(health) = self.agents.get_mut(agent) else {
+            return false;
+        };
+        if health.state == state && health.reason == reason {
+            return false;
+        }
+        if health.state != state {
+            health.since = now();
+        }
+        health.state = state;
+        health.reason = reason;
+        self.write_status();
+        true
+    }
+
+    fn write_status(&self) {
+        let Some(writer) = &self.status_writer else {
+            return;
+        };
+        let agents: BTreeMap<String, &AgentHealth> = self
+            .agents
+            .iter()
+            .map(|(agent, health)| (agent.to_string(), health))
+            .collect();
+        match serde_json::to_value(agents) {
+            Ok(serde_json::Value::Object(agents)) => {
+                let _ = writer.sender.try_send(StatusUpdate::Agents(agents));
+            }
+            Ok(_) => {}
+            Err(e) => warn!(target: "Supervisor", "Failed to write status file: {}", e),
+        }
+    }
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_backoff() {
+        let mut supervisor = Supervisor::new(
+            Some(&SupervisionConfig {
+                max_delay: Some(8),
+                max_restarts: Some(5),
+                ..Default::default()
+            }),
+            Default::default(),
+        );
+        let agent = AgentId::Source("src".to_owned());
+        supervisor.started(&agent);
+        let delays: Vec<_> = (0..5)
+            .map(|_| supervisor.crashed(&agent, "crash".to_owned()).unwrap())
+            .map(|delay| delay.as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 8]);
+        assert_eq!(supervisor.state(&agent), Some(AgentState::Failed));
+
+        // the restart rate is exceeded
+        assert_eq!(supervisor.crashed(&agent, "crash".to_owned()), None);
+
+        // an agent that ran long enough starts over
+        let agent = AgentId::RetryAgent;
+        supervisor.started(&agent);
+        supervisor.crashed(&agent, "crash".to_owned());
+        supervisor.agents.get_mut(&agent).unwrap().restart.started -= Duration::from_secs(8);
+        assert_eq!(
+            supervisor.crashed(&agent, "crash".to_owned()),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let status_file = dir.path().join("status.json");
+        let queues = QueueMonitor::new(&["dst".to_owned()], Default::default());
+        let mut supervisor = Supervisor::new(
+            Some(&SupervisionConfig {
+                status_file: Some(status_file.to_string_lossy().to_string()),
+                ..Default::default()
+            }),
+            Arc::new(queues),
+        );
+        let agent = AgentId::Destination("dst".to_owned());
+        supervisor.started(&agent);
+        assert_eq!(supervisor.state(&agent), Some(AgentState::Starting));
+        supervisor.report(&agent, Err("connection refused".to_owned()));
+        assert_eq!(supervisor.state(&agent), Some(AgentState::Degraded));
+
+        // the degraded state is written with the next update of the status file
+        let deadline = Instant::now() + 5 * STATUS_INTERVAL;
+        let status = loop {
+            let status: serde_json::Value = fs::read(&status_file)
+                .ok()
+                .and_then(|status| serde_json::from_slice(&status).ok())
+                .unwrap_or_default();
+            if status["destination dst"]["state"] == "degraded" || Instant::now() > deadline {
+                break status;
+            }
+            task::block_on(task::sleep(Duration::from_millis(50)));
+        };
+        assert_eq!(status["destination dst"]["state"], "degraded");
+        assert_eq!(status["destination dst"]["reason"], "connection refused");
+        assert_eq!(status["destination dst"]["queued"], 0);
+
+        supervisor.report(&agent, Ok(()));
+        assert_eq!(supervisor.state(&agent), Some(AgentState::Running));
+        supervisor.crashed(&agent, "panic".to_owned());
+        // reports of the crashed agent do not hide the failure
+        supervisor.report(&agent, Ok(()));
+        assert_eq!(supervisor.state(&agent), Some(AgentState::Failed));
+
+        task::block_on(supervisor.stopped());
+        assert!(!status_file.exists());
+    }
+}